@@ -0,0 +1,734 @@
+//! A client making OSCORE requests to a server, after an EDHOC handshake
+//! with it.
+
+use coap_lite::{
+    CoapOption, ContentFormat, MessageClass, MessageType, Packet, RequestType,
+    ResponseType,
+};
+use desktop_common::persist;
+use oscore::{
+    edhoc::{
+        error::{OwnError, OwnOrPeerError},
+        PartyU,
+    },
+    oscore::SecurityContext,
+};
+use rand::{rngs::OsRng, Rng, RngCore};
+use std::{
+    fmt, io,
+    path::Path,
+    time::{Instant, SystemTime},
+};
+use tracing::{field, info, info_span, warn};
+
+use crate::{
+    keys::{KeyPair, PeerKey},
+    logging::Secret,
+    rekey::RekeyPolicy,
+    reliability,
+    request::Request,
+    session::{self, Saved, SessionFile},
+    transport::Transport,
+    uri::Uri,
+};
+
+/// The ways in which a request can fail.
+#[derive(Debug)]
+pub enum Error {
+    /// We couldn't reach the server.
+    Connect(io::Error),
+    /// We're not able to go through a proxy with TCP.
+    ProxyOverTcp,
+    /// The path of the request is not valid.
+    Uri(String),
+    /// Sending the request or receiving the response failed.
+    Exchange(reliability::Error),
+    /// The server aborted the handshake with an EDHOC error message.
+    Peer(String),
+    /// We aborted the handshake, because the server's message was invalid.
+    Edhoc(&'static str),
+    /// Protecting the request or unprotecting the response failed.
+    Oscore(oscore::oscore::Error),
+    /// The security context couldn't be resumed or saved.
+    Session(session::Error),
+    /// The response is not a valid CoAP message.
+    Malformed,
+    /// The request is too large to encode.
+    TooLarge,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Connect(e) => write!(f, "unable to connect: {}", e),
+            Error::ProxyOverTcp => {
+                write!(f, "proxies are only supported with UDP")
+            }
+            Error::Uri(e) => write!(f, "invalid URI: {}", e),
+            Error::Exchange(e) => write!(f, "{}", e),
+            Error::Peer(e) => write!(f, "server aborted EDHOC: {}", e),
+            Error::Edhoc(reason) => write!(f, "aborted EDHOC: {}", reason),
+            Error::Oscore(e) => write!(f, "OSCORE failed: {:?}", e),
+            Error::Session(e) => write!(f, "{}", e),
+            Error::Malformed => write!(f, "received an invalid CoAP message"),
+            Error::TooLarge => write!(f, "the request is too large"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<reliability::Error> for Error {
+    fn from(e: reliability::Error) -> Error {
+        Error::Exchange(e)
+    }
+}
+
+impl From<session::Error> for Error {
+    fn from(e: session::Error) -> Error {
+        Error::Session(e)
+    }
+}
+
+impl From<oscore::oscore::Error> for Error {
+    fn from(e: oscore::oscore::Error) -> Error {
+        Error::Oscore(e)
+    }
+}
+
+/// A security context together with what tells us when to replace it, and
+/// what it's saved as.
+struct Session {
+    context: SecurityContext,
+    master_secret: Vec<u8>,
+    master_salt: Vec<u8>,
+    /// The sequence numbers used with the context so far.
+    seq: u64,
+    established: Instant,
+    /// Whether the context was resumed from the session file and hasn't
+    /// been accepted by the server yet.
+    resumed: bool,
+}
+
+/// Makes requests to a server, protected with OSCORE.
+///
+/// The first request does the EDHOC handshake, unless `connect` was used
+/// before or the context saved in the session file is resumed. A fresh
+/// security context is established before the current one runs out
+/// according to the re-keying policy, and when the server refuses it, in
+/// which case the request is sent again with the new context.
+pub struct OscoreClient {
+    /// The server, without the `coap+tcp://` or `coap://` scheme.
+    destination: String,
+    tcp: bool,
+    proxy: Option<String>,
+    local_port: u16,
+    own: KeyPair,
+    peer: PeerKey,
+    rng: Box<dyn RngCore>,
+    policy: RekeyPolicy,
+    transport: Option<Transport>,
+    session: Option<Session>,
+    session_file: Option<SessionFile>,
+}
+
+impl OscoreClient {
+    /// Creates a client for the destination, like `localhost:5683`, or
+    /// `coap+tcp://localhost:5683` to connect with TCP.
+    pub fn new(
+        destination: &str,
+        own: KeyPair,
+        peer: PeerKey,
+    ) -> OscoreClient {
+        let (destination, tcp) = match destination.strip_prefix("coap+tcp://")
+        {
+            Some(destination) => (destination, true),
+            None => (destination.trim_start_matches("coap://"), false),
+        };
+
+        OscoreClient {
+            destination: destination.to_string(),
+            tcp,
+            proxy: None,
+            local_port: 0,
+            own,
+            peer,
+            rng: Box::new(OsRng),
+            policy: RekeyPolicy::default(),
+            transport: None,
+            session: None,
+            session_file: None,
+        }
+    }
+
+    /// Sends the requests through the CoAP proxy at the address, which only
+    /// works with UDP.
+    pub fn set_proxy(&mut self, proxy: Option<&str>) {
+        self.proxy = proxy.map(str::to_string);
+    }
+
+    /// Sets the local port to bind to with UDP, which is any by default.
+    pub fn set_local_port(&mut self, port: u16) {
+        self.local_port = port;
+    }
+
+    /// Sets the RNG that key material is generated with, which is the
+    /// operating system's by default.
+    pub fn set_rng(&mut self, rng: Box<dyn RngCore>) {
+        self.rng = rng;
+    }
+
+    /// Sets when to replace the security context with a fresh one.
+    pub fn set_rekey_policy(&mut self, policy: RekeyPolicy) {
+        self.policy = policy;
+    }
+
+    /// Saves the security context to the file, and resumes the one saved
+    /// there instead of doing the first handshake.
+    pub fn set_session_file(&mut self, path: Option<&Path>) {
+        self.session_file = path.map(SessionFile::new);
+    }
+
+    /// Does an EDHOC handshake with the server for a fresh security context.
+    pub fn connect(&mut self) -> Result<(), Error> {
+        let (master_secret, master_salt) = self.edhoc()?;
+        let context = SecurityContext::new(
+            master_secret.clone(),
+            master_salt.clone(),
+            self.own.kid.clone(),
+            self.peer.kid.clone(),
+        )?;
+        self.session = Some(Session {
+            context,
+            master_secret,
+            master_salt,
+            seq: 0,
+            established: Instant::now(),
+            resumed: false,
+        });
+
+        self.save_session()
+    }
+
+    /// Resumes the security context saved in the session file, if there is
+    /// one for our and the peer's kid.
+    fn resume(&mut self) -> Result<(), Error> {
+        let saved = match &self.session_file {
+            Some(file) => file.load()?,
+            None => None,
+        };
+        let saved = match saved {
+            Some(saved)
+                if saved.sender_id == self.own.kid
+                    && saved.recipient_id == self.peer.kid =>
+            {
+                saved
+            }
+            Some(_) => {
+                warn!("Saved security context is for other keys, ignoring it");
+                return Ok(());
+            }
+            None => return Ok(()),
+        };
+
+        let context = match persist::restore_context(
+            saved.master_secret.clone(),
+            saved.master_salt.clone(),
+            saved.sender_id,
+            saved.recipient_id,
+            saved.sender_seq,
+        )? {
+            Some(context) => context,
+            None => {
+                warn!("Saved security context is too far along, re-keying");
+                return Ok(());
+            }
+        };
+        // Our clock only tells us how old the context is, not when it was
+        // established
+        let age = SystemTime::now()
+            .duration_since(saved.created)
+            .unwrap_or_default();
+        self.session = Some(Session {
+            context,
+            master_secret: saved.master_secret,
+            master_salt: saved.master_salt,
+            seq: saved.sender_seq,
+            established: Instant::now()
+                .checked_sub(age)
+                .unwrap_or_else(Instant::now),
+            resumed: true,
+        });
+        info!(seq = saved.sender_seq, "Resumed the saved security context");
+
+        // Move the bound on disk past what we're about to use
+        self.save_session()
+    }
+
+    /// Saves the security context to the session file, if there is one.
+    fn save_session(&mut self) -> Result<(), Error> {
+        if let (Some(file), Some(session)) =
+            (self.session_file.as_mut(), self.session.as_ref())
+        {
+            file.save(&Saved {
+                master_secret: session.master_secret.clone(),
+                master_salt: session.master_salt.clone(),
+                sender_id: self.own.kid.clone(),
+                recipient_id: self.peer.kid.clone(),
+                sender_seq: session.seq,
+                created: SystemTime::now() - session.established.elapsed(),
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Makes a request to the resource at the path, which may have a query
+    /// like `store/key?a=1`.
+    pub fn request(
+        &mut self,
+        method: RequestType,
+        path: &str,
+        payload: Vec<u8>,
+    ) -> Result<Packet, Error> {
+        let mut uri = Uri::parse(&format!(
+            "coap://{}/{}",
+            self.destination,
+            path.trim_start_matches('/')
+        ))
+        .map_err(Error::Uri)?;
+        uri.tcp = self.tcp;
+
+        self.send(&Request {
+            method,
+            uri,
+            payload,
+            content_format: None,
+            accept: None,
+        })
+    }
+
+    /// Makes a request protected with OSCORE and returns the unprotected
+    /// response.
+    ///
+    /// The request goes to our destination, its URI's authority is only used
+    /// for the Proxy-Uri. Responses the server didn't protect, like when it
+    /// rejects the request, are returned as they are.
+    pub fn send(&mut self, request: &Request) -> Result<Packet, Error> {
+        if self.session.is_none() {
+            self.resume()?;
+        }
+        let due = match &self.session {
+            Some(session) => {
+                self.policy.is_due(session.seq, session.established)
+            }
+            None => true,
+        };
+        if due {
+            if self.session.is_some() {
+                info!("Security context is running out, re-keying");
+            }
+            self.connect()?;
+        }
+        let mut coap = request
+            .to_bytes(self.proxy.is_some(), &mut *self.rng)
+            .ok_or(Error::TooLarge)?;
+
+        // Everything logged for this exchange carries what identifies it
+        let span = info_span!(
+            "exchange",
+            destination = %self.destination,
+            token = %token(&coap),
+            kid = %hex::encode(&self.own.kid),
+            piv = self.session.as_ref().map_or(0, |session| session.seq)
+        );
+        let _exchange = span.enter();
+
+        let mut res = self.protect_exchange(&coap)?;
+        let resumed = match &self.session {
+            Some(session) => session.resumed,
+            None => false,
+        };
+        if is_refused(&res, resumed) {
+            // The server no longer accepts our context, or has forgotten
+            // the one we resumed, so we get a fresh one and try again
+            warn!("Server refused the security context, re-keying");
+            if resumed {
+                // Don't resume it again if the handshake fails
+                self.session = None;
+                if let Some(file) = self.session_file.as_mut() {
+                    file.remove()?;
+                }
+            }
+            self.connect()?;
+            // With a fresh Message ID and token, so it's not mistaken for
+            // a retransmission of the refused request
+            coap = request
+                .to_bytes(self.proxy.is_some(), &mut *self.rng)
+                .ok_or(Error::TooLarge)?;
+            span.record("token", &field::display(token(&coap)));
+            span.record("piv", &0);
+            res = self.protect_exchange(&coap)?;
+        }
+
+        let packet = Packet::from_bytes(&res).map_err(|_| Error::Malformed)?;
+        if packet.get_option(CoapOption::Oscore).is_none() {
+            warn!("Server didn't protect the response");
+            return Ok(packet);
+        }
+        let session = self.session.as_mut().expect("Session was established");
+        let unprotected = session.context.unprotect_response(&res)?;
+        session.resumed = false;
+
+        Packet::from_bytes(&unprotected).map_err(|_| Error::Malformed)
+    }
+
+    /// Makes a request without OSCORE and without a handshake.
+    pub fn send_unprotected(
+        &mut self,
+        request: &Request,
+    ) -> Result<Packet, Error> {
+        let coap = request
+            .to_bytes(self.proxy.is_some(), &mut *self.rng)
+            .ok_or(Error::TooLarge)?;
+        let res = reliability::exchange(self.transport()?, &coap)?;
+
+        Packet::from_bytes(&res).map_err(|_| Error::Malformed)
+    }
+
+    /// Protects the request with the current security context, sends it and
+    /// returns the response as it is.
+    fn protect_exchange(&mut self, coap: &[u8]) -> Result<Vec<u8>, Error> {
+        let stale = match (&self.session_file, &self.session) {
+            (Some(file), Some(session)) => file.is_stale(session.seq),
+            _ => false,
+        };
+        if stale {
+            self.save_session()?;
+        }
+        let session = self.session.as_mut().expect("Session was established");
+        let protected = session.context.protect_request(coap)?;
+        session.seq += 1;
+
+        Ok(reliability::exchange(self.transport()?, &protected)?)
+    }
+
+    /// Returns the transport to the server or proxy, connecting first if we
+    /// haven't yet.
+    fn transport(&mut self) -> Result<&mut Transport, Error> {
+        if self.transport.is_none() {
+            let transport = if self.tcp {
+                if self.proxy.is_some() {
+                    return Err(Error::ProxyOverTcp);
+                }
+                Transport::tcp(&self.destination)
+            } else {
+                let target =
+                    self.proxy.as_deref().unwrap_or(&self.destination);
+                Transport::udp(&self.local_port.to_string(), target)
+            };
+            self.transport = Some(transport.map_err(Error::Connect)?);
+        }
+
+        Ok(self.transport.as_mut().unwrap())
+    }
+
+    /// Does an EDHOC exchange with the server, returning the master secret
+    /// and salt.
+    fn edhoc(&mut self) -> Result<(Vec<u8>, Vec<u8>), Error> {
+        let span = info_span!("edhoc", destination = %self.destination);
+        let _edhoc = span.enter();
+
+        // Generate a fresh X25519 private key for this handshake only
+        let mut eph = [0; 32];
+        self.rng.fill_bytes(&mut eph);
+        // Choose a random connection identifier
+        let c_u = vec![self.rng.gen()];
+
+        // Initialize what we need to handle messages
+        let msg1_sender = PartyU::new(
+            c_u,
+            eph,
+            &self.own.private,
+            &self.own.public,
+            self.own.kid.clone(),
+        );
+        // type = 1 is the case in CoAP, where party U can correlate
+        // message_1 and message_2 with the token
+        let (msg1_bytes, msg2_receiver) =
+            match msg1_sender.generate_message_1(1) {
+                // If an error happens here, we just abort. No need to send
+                // a message, since the protocol hasn't started yet.
+                Err(OwnError(_)) => {
+                    return Err(Error::Edhoc("unable to generate message_1"));
+                }
+                Ok(val) => val,
+            };
+
+        let msg2_bytes = self.edhoc_exchange(msg1_bytes)?;
+        info!("Sent message_1 to peer and received message_2");
+        let (_v_kid, msg2_verifier) =
+            // This is a case where we could receive an error message (just
+            // abort then), or cause an error (send it to the peer)
+            match msg2_receiver.extract_peer_kid(msg2_bytes) {
+                Err(OwnOrPeerError::PeerError(s)) => {
+                    return Err(Error::Peer(s));
+                }
+                Err(OwnOrPeerError::OwnError(b)) => {
+                    self.edhoc_error(b);
+                    return Err(Error::Edhoc("unable to handle message_2"));
+                }
+                Ok(val) => val,
+            };
+        let msg3_sender =
+            match msg2_verifier.verify_message_2(&self.peer.public) {
+                Err(OwnError(b)) => {
+                    self.edhoc_error(b);
+                    return Err(Error::Edhoc("unable to verify message_2"));
+                }
+                Ok(val) => val,
+            };
+        let (msg3_bytes, master_secret, master_salt) =
+            match msg3_sender.generate_message_3() {
+                Err(OwnError(b)) => {
+                    self.edhoc_error(b);
+                    return Err(Error::Edhoc("unable to generate message_3"));
+                }
+                Ok(val) => val,
+            };
+        info!(
+            master_secret = %Secret(&master_secret),
+            master_salt = %Secret(&master_salt),
+            "Successfully derived the master secret and salt"
+        );
+
+        self.edhoc_exchange(msg3_bytes)?;
+        info!("Sent message_3 to peer");
+
+        Ok((master_secret, master_salt))
+    }
+
+    /// Sends an EDHOC message and returns the payload of the response.
+    fn edhoc_exchange(&mut self, msg: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let req = self.build_edhoc_request(msg)?;
+        let res = reliability::exchange(self.transport()?, &req)?;
+
+        Ok(Packet::from_bytes(&res)
+            .map_err(|_| Error::Malformed)?
+            .payload)
+    }
+
+    /// Sends an EDHOC error message, without waiting for a response since
+    /// we're giving up on the handshake anyway.
+    fn edhoc_error(&mut self, msg: Vec<u8>) {
+        let sent = self.build_edhoc_request(msg).and_then(|req| {
+            let transport = self.transport()?;
+            transport.send(&req).map_err(|e| Error::Exchange(e.into()))
+        });
+        if let Err(e) = sent {
+            warn!("Unable to send EDHOC error message: {}", e);
+        }
+    }
+
+    /// Returns a CoAP packet for an EDHOC message.
+    fn build_edhoc_request(&mut self, msg: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let mut req = Packet::new();
+
+        // Confirmable, so it's retransmitted until the server acknowledges it
+        req.header.set_type(MessageType::Confirmable);
+        // This message ID should be acknowledged by the server for
+        // reliability
+        req.header.message_id = self.rng.gen();
+        // And the token is used to tie response to request
+        req.set_token(reliability::new_token(&mut *self.rng));
+        // As per the EDHOC spec, we send a 0.02 (Post)
+        req.header.code = MessageClass::Request(RequestType::Post);
+        // This would be the EDHOC Content-Format, but it's
+        // not standardized yet
+        req.set_content_format(ContentFormat::ApplicationOctetStream);
+        // Add Proxy-Uri or Uri-Path
+        if self.proxy.is_some() {
+            req.add_option(
+                CoapOption::ProxyUri,
+                format!("coap://{}/.well-known/edhoc", self.destination)
+                    .as_bytes()
+                    .to_vec(),
+            );
+        } else {
+            req.add_option(CoapOption::UriPath, b".well-known".to_vec());
+            req.add_option(CoapOption::UriPath, b"edhoc".to_vec());
+        }
+        // Finally, pack in our EDHOC message
+        req.payload = msg;
+
+        req.to_bytes().map_err(|_| Error::TooLarge)
+    }
+}
+
+/// Returns the token of a message as hex, for logging.
+fn token(msg: &[u8]) -> String {
+    Packet::from_bytes(msg)
+        .map(|packet| hex::encode(packet.get_token()))
+        .unwrap_or_default()
+}
+
+/// Returns `true` if the response is an unprotected 4.01 (Unauthorized),
+/// which is what the server answers with when it doesn't accept the
+/// security context.
+///
+/// With a `resumed` context, a 4.00 (Bad Request) counts too, since that's
+/// what we get if the server has a different context under our kid.
+fn is_refused(res: &[u8], resumed: bool) -> bool {
+    let res = match Packet::from_bytes(res) {
+        Ok(res) => res,
+        Err(_) => return false,
+    };
+    if res.get_option(CoapOption::Oscore).is_some() {
+        return false;
+    }
+
+    match res.header.code {
+        MessageClass::Response(ResponseType::Unauthorized) => true,
+        MessageClass::Response(ResponseType::BadRequest) => resumed,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys;
+    use oscore::edhoc::PartyV;
+    use std::{fs, net::UdpSocket, thread};
+
+    const CLIENT_PRIV: [u8; 32] = [2; 32];
+    const SERVER_PRIV: [u8; 32] = [1; 32];
+
+    fn public(private: &[u8; 32]) -> [u8; 32] {
+        keys::derive_public(private).unwrap()
+    }
+
+    /// Returns the piggybacked response to the request.
+    fn respond(req: &Packet, code: ResponseType, payload: Vec<u8>) -> Vec<u8> {
+        let mut res = Packet::new();
+        res.header.set_type(MessageType::Acknowledgement);
+        res.header.code = MessageClass::Response(code);
+        res.header.message_id = req.header.message_id;
+        res.set_token(req.get_token().clone());
+        res.payload = payload;
+        res.to_bytes().unwrap()
+    }
+
+    /// Serves a client whose resumed context we don't know: the request is
+    /// rejected as if it failed to unprotect, then we do EDHOC and answer
+    /// the request again.
+    fn server(socket: UdpSocket) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            let mut buf = [0; 1500];
+            let mut receive = || {
+                let (len, client) = socket.recv_from(&mut buf).unwrap();
+                (Packet::from_bytes(&buf[..len]).unwrap(), client)
+            };
+
+            let (req, client) = receive();
+            let res = respond(&req, ResponseType::BadRequest, vec![]);
+            socket.send_to(&res, client).unwrap();
+
+            let (req, client) = receive();
+            let msg2_sender = match PartyV::new(
+                vec![0x2B],
+                [3; 32],
+                &SERVER_PRIV,
+                &public(&SERVER_PRIV),
+                vec![0xA3],
+            )
+            .handle_message_1(req.payload.clone())
+            {
+                Ok(val) => val,
+                Err(_) => panic!("Invalid message_1"),
+            };
+            let (msg2, msg3_receiver) = match msg2_sender.generate_message_2()
+            {
+                Ok(val) => val,
+                Err(_) => panic!("Failed generating message_2"),
+            };
+            let res = respond(&req, ResponseType::Changed, msg2);
+            socket.send_to(&res, client).unwrap();
+
+            let (req, client) = receive();
+            let verifier =
+                match msg3_receiver.extract_peer_kid(req.payload.clone()) {
+                    Ok((_, verifier)) => verifier,
+                    Err(_) => panic!("Invalid message_3"),
+                };
+            assert!(verifier.verify_message_3(&public(&CLIENT_PRIV)).is_ok());
+            let res = respond(&req, ResponseType::Changed, vec![]);
+            socket.send_to(&res, client).unwrap();
+
+            let (req, client) = receive();
+            let res = respond(&req, ResponseType::Content, b"fresh".to_vec());
+            socket.send_to(&res, client).unwrap();
+        })
+    }
+
+    #[test]
+    fn rekeys_after_stale_session() {
+        let path = std::env::temp_dir()
+            .join(format!("client-test-{}.cbor", std::process::id()));
+        SessionFile::new(&path)
+            .save(&Saved {
+                master_secret: vec![0; 16],
+                master_salt: vec![],
+                sender_id: vec![0xA2],
+                recipient_id: vec![0xA3],
+                sender_seq: 0,
+                created: SystemTime::now(),
+            })
+            .unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let destination = socket.local_addr().unwrap().to_string();
+        let server = server(socket);
+
+        let mut client = OscoreClient::new(
+            &destination,
+            KeyPair {
+                kid: vec![0xA2],
+                private: CLIENT_PRIV,
+                public: public(&CLIENT_PRIV),
+            },
+            PeerKey {
+                kid: vec![0xA3],
+                public: public(&SERVER_PRIV),
+            },
+        );
+        client.set_session_file(Some(&path));
+        let res = match client.request(RequestType::Get, "hello", vec![]) {
+            Ok(res) => res,
+            Err(e) => panic!("Request failed: {}", e),
+        };
+        server.join().unwrap();
+
+        assert_eq!(res.payload, b"fresh");
+        // The stale context was replaced by the fresh one
+        let saved = SessionFile::new(&path).load().unwrap().unwrap();
+        assert_ne!(saved.master_secret, vec![0; 16]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn refused() {
+        let mut req = Packet::new();
+        req.set_token(vec![1]);
+        let unauthorized = respond(&req, ResponseType::Unauthorized, vec![]);
+        let bad_request = respond(&req, ResponseType::BadRequest, vec![]);
+        assert!(is_refused(&unauthorized, false));
+        assert!(is_refused(&unauthorized, true));
+        assert!(!is_refused(&bad_request, false));
+        assert!(is_refused(&bad_request, true));
+
+        // A protected response means the server accepted the context
+        let mut protected = Packet::from_bytes(&bad_request).unwrap();
+        protected.add_option(CoapOption::Oscore, vec![]);
+        assert!(!is_refused(&protected.to_bytes().unwrap(), true));
+    }
+}
@@ -0,0 +1,10 @@
+pub use desktop_common::{keys, logging};
+
+pub mod client;
+pub mod rekey;
+pub mod reliability;
+pub mod request;
+pub mod session;
+pub mod tcp;
+pub mod transport;
+pub mod uri;
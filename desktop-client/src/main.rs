@@ -1,30 +1,31 @@
-use clap::{App, Arg};
-use coap_lite::{
-    CoapOption, ContentFormat, MessageClass, MessageType, Packet, RequestType,
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use coap_lite::RequestType;
+use rand::{rngs::StdRng, SeedableRng};
+use std::{
+    fs,
+    io::{self, Read},
+    path::Path,
+    process, str,
+    time::Duration,
 };
-use oscore::{
-    edhoc::{
-        error::{OwnError, OwnOrPeerError},
-        PartyU,
-    },
-    oscore::SecurityContext,
+use tracing::{error, info, warn};
+
+use desktop_client::{
+    client::{Error, OscoreClient},
+    keys::{self, KeyPair, PeerKey},
+    logging::{self, Format},
+    rekey::RekeyPolicy,
+    request::{self, Request},
+    uri::Uri,
 };
-use rand::prelude::*;
-use std::{net::UdpSocket, str};
 
-/* EDHOC configuration */
+/* EDHOC configuration (demo keys used when no key files are given) */
 // Private authentication key
 const AUTH_PRIV: [u8; 32] = [
     0x53, 0x21, 0xFC, 0x01, 0xC2, 0x98, 0x20, 0x06, 0x3A, 0x72, 0x50, 0x8F,
     0xC6, 0x39, 0x25, 0x1D, 0xC8, 0x30, 0xE2, 0xF7, 0x68, 0x3E, 0xB8, 0xE3,
     0x8A, 0xF1, 0x64, 0xA5, 0xB9, 0xAF, 0x9B, 0xE3,
 ];
-// Public authentication key (known by peer)
-const AUTH_PUB: [u8; 32] = [
-    0x42, 0x4C, 0x75, 0x6A, 0xB7, 0x7C, 0xC6, 0xFD, 0xEC, 0xF0, 0xB3, 0xEC,
-    0xFC, 0xFF, 0xB7, 0x53, 0x10, 0xC0, 0x15, 0xBF, 0x5C, 0xBA, 0x2E, 0xC0,
-    0xA2, 0x36, 0xE6, 0x65, 0x0C, 0x8A, 0xB9, 0xC7,
-];
 // Key ID used to identify the public authentication key
 const KID: [u8; 1] = [0xA2];
 // Public authentication key of the peer
@@ -33,6 +34,7 @@ const AUTH_PEER: [u8; 32] = [
     0x5B, 0xC2, 0x0F, 0x46, 0x30, 0xDC, 0x78, 0xA1, 0x14, 0xDE, 0x65, 0x9C,
     0x7E, 0x50, 0x4D, 0x0F, 0x52, 0x9A, 0x6B, 0xD3,
 ];
+// Key ID of peer
 const KID_PEER: [u8; 1] = [0xA3];
 
 fn main() {
@@ -40,14 +42,17 @@ fn main() {
         .version(clap::crate_version!())
         .about("Desktop client using an OSCORE server.")
         .author(clap::crate_authors!())
+        .setting(AppSettings::SubcommandRequiredElseHelp)
         .arg(
             Arg::with_name("port")
                 .short("p")
                 .long("port")
                 .value_name("NUM")
                 .takes_value(true)
-                .help("The local port to bind to")
-                .required(true),
+                .help(
+                    "The local port to bind to when using UDP, any if not \
+                     given",
+                ),
         )
         .arg(
             Arg::with_name("proxy")
@@ -58,287 +63,411 @@ fn main() {
                 .help("CoAP proxy to use"),
         )
         .arg(
-            Arg::with_name("DEST")
-                .help("The destination server")
-                .required(true),
+            Arg::with_name("seed")
+                .long("seed")
+                .value_name("NUM")
+                .takes_value(true)
+                .help("Seeds the RNG for reproducible test runs (insecure)"),
+        )
+        .arg(
+            Arg::with_name("key")
+                .long("key")
+                .value_name("FILE")
+                .takes_value(true)
+                .help("Our private key as COSE_Key or hex"),
+        )
+        .arg(
+            Arg::with_name("kid")
+                .long("kid")
+                .value_name("HEX")
+                .takes_value(true)
+                .help("Our key ID, if not in the key file"),
+        )
+        .arg(
+            Arg::with_name("peer-key")
+                .long("peer-key")
+                .value_name("FILE")
+                .takes_value(true)
+                .help("The peer's public key as COSE_Key or hex"),
+        )
+        .arg(
+            Arg::with_name("peer-kid")
+                .long("peer-kid")
+                .value_name("HEX")
+                .takes_value(true)
+                .help("The peer's key ID, if not in the key file"),
+        )
+        .arg(
+            Arg::with_name("max-seq")
+                .long("max-seq")
+                .value_name("NUM")
+                .takes_value(true)
+                .help("Sequence number at which to re-key"),
+        )
+        .arg(
+            Arg::with_name("lifetime")
+                .long("lifetime")
+                .value_name("SECS")
+                .takes_value(true)
+                .help("How long a security context may be used"),
+        )
+        .arg(
+            Arg::with_name("session")
+                .long("session")
+                .value_name("FILE")
+                .takes_value(true)
+                .help(
+                    "Saves the security context to the file, and resumes it \
+                     from there instead of doing EDHOC",
+                ),
+        )
+        .arg(
+            Arg::with_name("no-oscore")
+                .long("no-oscore")
+                .help("Sends the request without OSCORE, skipping EDHOC"),
+        )
+        .arg(
+            Arg::with_name("log")
+                .long("log")
+                .value_name("FILTER")
+                .takes_value(true)
+                .help("Log filter like info,desktop_client=debug"),
+        )
+        .arg(
+            Arg::with_name("log-format")
+                .long("log-format")
+                .value_name("FORMAT")
+                .takes_value(true)
+                .possible_values(&["text", "json"])
+                .default_value("text")
+                .help("Whether to log text or JSON lines"),
+        )
+        .arg(
+            Arg::with_name("show-secrets")
+                .long("show-secrets")
+                .help("Logs key material instead of redacting it (insecure)"),
+        )
+        .subcommand(
+            SubCommand::with_name("demo")
+                .about(
+                    "Alternates OSCORE requests to /hello and /echo \
+                     forever",
+                )
+                .arg(
+                    Arg::with_name("DEST")
+                        .help(
+                            "The destination server, like localhost:5683, or \
+                             coap+tcp://localhost:5683 to connect with TCP",
+                        )
+                        .required(true),
+                ),
+        )
+        .subcommand(request_command("get", "Sends a GET request", false))
+        .subcommand(request_command("post", "Sends a POST request", true))
+        .subcommand(request_command("put", "Sends a PUT request", true))
+        .subcommand(request_command("delete", "Sends a DELETE request", false))
+        .subcommand(
+            SubCommand::with_name("discover")
+                .about(
+                    "Lists the resources of the server in \
+                     /.well-known/core",
+                )
+                .arg(
+                    Arg::with_name("URI")
+                        .help(
+                            "The server, like coap://localhost, with an \
+                             optional query like ?rt=echo",
+                        )
+                        .required(true),
+                ),
         )
         .get_matches();
-    let port = matches.value_of("port").unwrap();
-    let destination = matches.value_of("DEST").unwrap();
-    let proxy = matches.value_of("proxy");
-
-    let socket = UdpSocket::bind(format!("0.0.0.0:{}", port))
-        .expect("Unable to bind to port");
+    let format = matches
+        .value_of("log-format")
+        .unwrap()
+        .parse::<Format>()
+        .unwrap();
+    logging::init(format, matches.value_of("log")).unwrap_or_else(|e| {
+        eprintln!("Error setting up logging: {}", e);
+        process::exit(1);
+    });
+    logging::show_secrets(matches.is_present("show-secrets"));
+    let port = match matches.value_of("port") {
+        Some(port) => port.parse().unwrap_or_else(|_| {
+            error!("--port: invalid port {}", port);
+            process::exit(1);
+        }),
+        None => 0,
+    };
+    let policy = rekey_policy(&matches).unwrap_or_else(|e| {
+        error!("Error parsing re-keying policy: {}", e);
+        process::exit(1);
+    });
 
-    // Do EDHOC exchange
-    let (master_secret, master_salt) = edhoc(&socket, destination, proxy);
-    // Initialize OSCORE context
-    let mut oscore = SecurityContext::new(
-        master_secret,
-        master_salt,
-        KID.to_vec(),
-        KID_PEER.to_vec(),
-    )
-    .expect("Unable to build security context");
-    // Start making OSCORE requests
-    oscore_requests(&socket, destination, proxy, &mut oscore);
-}
+    // Refuse to start with keys we can't use
+    let (own, peer) = load_keys(&matches).unwrap_or_else(|e| {
+        error!("Error loading keys: {}", e);
+        process::exit(1);
+    });
 
-/// Does an EDHOC exchange with the given destination.
-fn edhoc(
-    socket: &UdpSocket,
-    destination: &str,
-    proxy: Option<&str>,
-) -> (Vec<u8>, Vec<u8>) {
-    // "Generate" an ECDH key pair (this is hardcoded, but MUST be
-    // ephemeral and generated randomly)
-    let eph = [
-        0xD4, 0xD8, 0x1A, 0xBA, 0xFA, 0xD9, 0x08, 0xA0, 0xCC, 0xEF, 0xEF,
-        0x5A, 0xD6, 0xB0, 0x5D, 0x50, 0x27, 0x02, 0xF1, 0xC1, 0x6F, 0x23,
-        0x2C, 0x25, 0x92, 0x93, 0x09, 0xAC, 0x44, 0x1B, 0x95, 0x8E,
-    ];
-    // Choose a connection identifier
-    let c_u = [0xC3].to_vec();
+    // Either we make requests forever, or the one we're asked to
+    let (subcommand, sub_matches) = matches.subcommand();
+    let sub_matches = sub_matches.expect("Subcommand is required");
+    let request = match subcommand {
+        "demo" => None,
+        _ => {
+            Some(parse_request(subcommand, sub_matches).unwrap_or_else(|e| {
+                error!("Error in request: {}", e);
+                process::exit(1);
+            }))
+        }
+    };
+    let destination = match request.as_ref() {
+        Some(request) => request.uri.destination(),
+        None => sub_matches.value_of("DEST").unwrap().to_string(),
+    };
 
-    // Initialize what we need to handle messages
-    let msg1_sender =
-        PartyU::new(c_u, eph, &AUTH_PRIV, &AUTH_PUB, KID.to_vec());
-    // type = 1 is the case in CoAP, where party U can correlate
-    // message_1 and message_2 with the token
-    let (msg1_bytes, msg2_receiver) =
-        // If an error happens here, we just abort. No need to send a message,
-        // since the protocol hasn't started yet.
-        msg1_sender.generate_message_1(1).expect("Error generating message_1");
+    let mut client = OscoreClient::new(&destination, own, peer);
+    client.set_proxy(matches.value_of("proxy"));
+    client.set_local_port(port);
+    client.set_rekey_policy(policy);
+    client.set_session_file(matches.value_of("session").map(Path::new));
+    // Key material comes straight from the OS, unless we're asked to be
+    // reproducible
+    if let Some(seed) = matches.value_of("seed") {
+        let seed = seed.parse().unwrap_or_else(|_| {
+            error!("--seed: invalid number {}", seed);
+            process::exit(1);
+        });
+        client.set_rng(Box::new(StdRng::seed_from_u64(seed)));
+    }
 
-    let msg2_bytes = send_receive(
-        &socket,
-        destination,
-        proxy,
-        &build_edhoc_request(msg1_bytes, destination, proxy),
-        true,
-    );
-    println!("Sent message_1 to peer and received message_2");
-    let (_v_kid, msg2_verifier) =
-        // This is a case where we could receive an error message (just abort
-        // then), or cause an error (send it to the peer)
-        match msg2_receiver.extract_peer_kid(msg2_bytes) {
-            Err(OwnOrPeerError::PeerError(s)) => {
-                panic!("Received error msg: {}", s)
-            }
-            Err(OwnOrPeerError::OwnError(b)) => {
-                send(&socket, destination, proxy,&build_edhoc_request(b, destination, proxy));
-                panic!("Ran into an issue dealing with msg_2")
-            }
-            Ok(val) => val,
-        };
-    let msg3_sender = match msg2_verifier.verify_message_2(&AUTH_PEER) {
-        Err(OwnError(b)) => {
-            send(
-                &socket,
-                destination,
-                proxy,
-                &build_edhoc_request(b, destination, proxy),
-            );
-            panic!("Ran into an issue verifying message_2")
+    let request = match request {
+        Some(request) => request,
+        None => {
+            // Start making OSCORE requests
+            let e = oscore_requests(&mut client).unwrap_err();
+            error!("Error making requests: {}", e);
+            process::exit(1);
         }
-        Ok(val) => val,
     };
-    let (msg3_bytes, master_secret, master_salt) =
-        match msg3_sender.generate_message_3() {
-            Err(OwnError(b)) => {
-                send(
-                    &socket,
-                    destination,
-                    proxy,
-                    &build_edhoc_request(b, destination, proxy),
-                );
-                panic!("Ran into an issue generating message_3")
-            }
-            Ok(val) => val,
-        };
-    println!(
-        "Successfully derived the master secret and salt\n\
-         {:?}\n\
-         {:?}",
-        master_secret, master_salt
-    );
-
-    send_receive(
-        &socket,
-        destination,
-        proxy,
-        &build_edhoc_request(msg3_bytes, destination, proxy),
-        false,
-    );
-    println!("Sent message_3 to peer");
 
-    (master_secret, master_salt)
+    let res = if matches.is_present("no-oscore") {
+        client.send_unprotected(&request)
+    } else {
+        client.send(&request)
+    };
+    match res {
+        Ok(res) => request::print_response(&res),
+        Err(e) => {
+            error!("Error making request: {}", e);
+            process::exit(1);
+        }
+    }
 }
 
-/// Makes repeated OSCORE requests to the target's /hello and /echo resources.
-fn oscore_requests(
-    socket: &UdpSocket,
-    destination: &str,
-    proxy: Option<&str>,
-    oscore: &mut SecurityContext,
-) {
-    for i in 0.. {
-        // Build a CoAP request to one of the two resources
-        let coap = if i % 2 == 0 {
-            build_resource_request(destination, proxy, b"hello".to_vec(), None)
-        } else {
-            build_resource_request(
-                destination,
-                proxy,
-                b"echo".to_vec(),
-                Some(format!("Iteration {}", i).as_bytes().to_vec()),
-            )
-        };
-
-        // Protect it with OSCORE
-        let protected =
-            oscore.protect_request(&coap).expect("Protection failed");
-        // Send it to the server
-        let res = send_receive(socket, destination, proxy, &protected, false);
-        // Unprotect from OSCORE
-        let unprotected = oscore
-            .unprotect_response(&res)
-            .expect("Unprotection failed");
-
-        // Parse the embedded CoAP packet
-        let coap = Packet::from_bytes(&unprotected)
-            .expect("Failed parsing unprotected response");
-        // Log the payload
-        println!(
-            "Got response: {}",
-            str::from_utf8(&coap.payload)
-                .expect("Failed parsing response payload as UTF-8")
+/// Returns the subcommand for a request with the given method.
+fn request_command(
+    name: &'static str,
+    about: &'static str,
+    with_payload: bool,
+) -> App<'static, 'static> {
+    let command = SubCommand::with_name(name)
+        .about(about)
+        .arg(
+            Arg::with_name("URI")
+                .help(
+                    "The resource, like coap://localhost:5683/store/key?a=1, \
+                     or coap+tcp://... to connect with TCP",
+                )
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("accept")
+                .short("A")
+                .long("accept")
+                .value_name("FORMAT")
+                .takes_value(true)
+                .help("The Content-Format to ask for, by number or name"),
         );
+    if !with_payload {
+        return command;
     }
+
+    command
+        .arg(
+            Arg::with_name("payload")
+                .short("e")
+                .long("payload")
+                .value_name("TEXT")
+                .takes_value(true)
+                .help("The payload to send"),
+        )
+        .arg(
+            Arg::with_name("payload-file")
+                .short("f")
+                .long("payload-file")
+                .value_name("FILE")
+                .takes_value(true)
+                .conflicts_with("payload")
+                .help("The file to send as payload, or - for stdin"),
+        )
+        .arg(
+            Arg::with_name("content-format")
+                .short("t")
+                .long("content-format")
+                .value_name("FORMAT")
+                .takes_value(true)
+                .help(
+                    "The Content-Format of the payload, by number or one of \
+                     text, link-format, octet-stream, json, cbor",
+                ),
+        )
 }
 
-/// Returns a CoAP packet for an EDHOC message.
-fn build_edhoc_request(
-    msg: Vec<u8>,
-    destination: &str,
-    proxy: Option<&str>,
-) -> Vec<u8> {
-    let mut rng = rand::thread_rng();
-    let mut req = Packet::new();
+/// Returns the request the subcommand asks for.
+fn parse_request(
+    subcommand: &str,
+    matches: &ArgMatches,
+) -> Result<Request, String> {
+    let mut uri = Uri::parse(matches.value_of("URI").unwrap())?;
+    let method = match subcommand {
+        "get" => RequestType::Get,
+        "post" => RequestType::Post,
+        "put" => RequestType::Put,
+        "delete" => RequestType::Delete,
+        "discover" => {
+            uri.path = vec![b".well-known".to_vec(), b"core".to_vec()];
+            RequestType::Get
+        }
+        _ => unreachable!("Unknown subcommand {}", subcommand),
+    };
 
-    // We're not retrying on failure in this demo, but usually you're sending
-    // confirmable messages to achieve reliability
-    req.header.set_type(MessageType::Confirmable);
-    // This message ID should be acknowledged by the server for reliability
-    req.header.message_id = rng.gen();
-    // And the token is used to tie response to request
-    req.set_token(vec![rng.gen()]);
-    // As per the EDHOC spec, we send a 0.02 (Post)
-    req.header.code = MessageClass::Request(RequestType::Post);
-    // This would be the EDHOC Content-Format, but it's
-    // not standardized yet
-    req.set_content_format(ContentFormat::ApplicationOctetStream);
-    // Add Proxy-Uri or Uri-Path
-    if proxy.is_some() {
-        req.add_option(
-            CoapOption::ProxyUri,
-            format!("coap://{}/.well-known/edhoc", destination)
-                .as_bytes()
-                .to_vec(),
-        );
-    } else {
-        req.add_option(CoapOption::UriPath, b".well-known".to_vec());
-        req.add_option(CoapOption::UriPath, b"edhoc".to_vec());
-    }
-    // Finally, pack in our EDHOC message
-    req.payload = msg;
+    let payload = match (
+        matches.value_of("payload"),
+        matches.value_of("payload-file"),
+    ) {
+        (Some(payload), _) => payload.as_bytes().to_vec(),
+        (None, Some("-")) => {
+            let mut payload = Vec::new();
+            io::stdin()
+                .read_to_end(&mut payload)
+                .map_err(|e| format!("unable to read stdin: {}", e))?;
+            payload
+        }
+        (None, Some(path)) => {
+            fs::read(path).map_err(|e| format!("{}: {}", path, e))?
+        }
+        (None, None) => Vec::new(),
+    };
+    let content_format = matches
+        .value_of("content-format")
+        .map(request::parse_content_format)
+        .transpose()
+        .map_err(|e| format!("--content-format: {}", e))?;
+    let accept = matches
+        .value_of("accept")
+        .map(request::parse_content_format)
+        .transpose()
+        .map_err(|e| format!("--accept: {}", e))?;
 
-    req.to_bytes().expect("Failed getting bytes from packet")
+    Ok(Request {
+        method,
+        uri,
+        payload,
+        content_format,
+        accept,
+    })
 }
 
-/// Returns a CoAP packet for a GET request to the given resource with payload.
-fn build_resource_request(
-    destination: &str,
-    proxy: Option<&str>,
-    uri_path: Vec<u8>,
-    payload: Option<Vec<u8>>,
-) -> Vec<u8> {
-    let mut rng = rand::thread_rng();
-    let mut req = Packet::new();
-
-    // We're not retrying on failure in this demo, but usually you're sending
-    // confirmable messages to achieve reliability
-    req.header.set_type(MessageType::Confirmable);
-    // This message ID should be acknowledged by the server for reliability
-    req.header.message_id = rng.gen();
-    // And the token is used to tie response to request
-    req.set_token(vec![rng.gen()]);
-    req.header.code = MessageClass::Request(RequestType::Get);
-    req.set_content_format(ContentFormat::TextPlain);
-    // Add Proxy-Uri or Uri-Path
-    if proxy.is_some() {
-        let mut proxy_uri =
-            format!("coap://{}/", destination).as_bytes().to_vec();
-        proxy_uri.extend(uri_path);
-        req.add_option(CoapOption::ProxyUri, proxy_uri);
-    } else {
-        req.add_option(CoapOption::UriPath, uri_path);
+/// Returns the re-keying policy from the command line, with the defaults for
+/// what isn't given.
+fn rekey_policy(matches: &ArgMatches) -> Result<RekeyPolicy, String> {
+    let mut policy = RekeyPolicy::default();
+    if let Some(max_seq) = matches.value_of("max-seq") {
+        policy.max_seq = max_seq
+            .parse()
+            .map_err(|_| format!("--max-seq: invalid number {}", max_seq))?;
     }
-    if let Some(payload) = payload {
-        req.payload = payload;
+    if let Some(lifetime) = matches.value_of("lifetime") {
+        let secs = lifetime.parse().map_err(|_| {
+            format!("--lifetime: invalid number of seconds {}", lifetime)
+        })?;
+        policy.lifetime = Some(Duration::from_secs(secs));
     }
 
-    req.to_bytes().expect("Failed getting bytes from packet")
+    Ok(policy)
 }
 
-/// Sends a CoAP packet to the destination and returns the full response or
-/// just the payload.
-fn send_receive(
-    socket: &UdpSocket,
-    destination: &str,
-    proxy: Option<&str>,
-    packet: &[u8],
-    payload_only: bool,
-) -> Vec<u8> {
-    let mut buf = [0; 2048];
+/// Returns our key pair and the peer's key from the files given on the
+/// command line, or the built-in demo keys for those that weren't.
+fn load_keys(matches: &ArgMatches) -> Result<(KeyPair, PeerKey), String> {
+    let kid = matches
+        .value_of("kid")
+        .map(keys::parse_kid)
+        .transpose()
+        .map_err(|e| format!("--kid: {}", e))?;
+    let own = match matches.value_of("key") {
+        Some(path) => keys::load_key_pair(Path::new(path), kid)
+            .map_err(|e| format!("{}: {}", path, e))?,
+        None => {
+            warn!("No key file given, using the demo key");
+            KeyPair {
+                kid: kid.unwrap_or_else(|| KID.to_vec()),
+                private: AUTH_PRIV,
+                public: keys::derive_public(&AUTH_PRIV)
+                    .expect("Demo key is invalid"),
+            }
+        }
+    };
 
-    socket
-        .send_to(
-            packet,
-            if let Some(proxy) = proxy {
-                proxy
-            } else {
-                destination
-            },
-        )
-        .expect("Unable to send packet");
-    let (amt, _src) =
-        socket.recv_from(&mut buf).expect("Failed while receiving");
+    let peer_kid = matches
+        .value_of("peer-kid")
+        .map(keys::parse_kid)
+        .transpose()
+        .map_err(|e| format!("--peer-kid: {}", e))?;
+    let peer = match matches.value_of("peer-key") {
+        Some(path) => keys::load_peer_key(Path::new(path), peer_kid)
+            .map_err(|e| format!("{}: {}", path, e))?,
+        None => {
+            warn!("No peer key file given, using the demo key");
+            PeerKey {
+                kid: peer_kid.unwrap_or_else(|| KID_PEER.to_vec()),
+                public: AUTH_PEER,
+            }
+        }
+    };
 
-    if payload_only {
-        Packet::from_bytes(&buf[..amt])
-            .expect("Unable to parse packet")
-            .payload
-    } else {
-        buf[..amt].to_vec()
+    // OSCORE needs our IDs to differ, and it's a mixup anyway
+    if own.kid == peer.kid {
+        return Err("we and the peer have the same kid".to_string());
     }
+
+    Ok((own, peer))
 }
 
-/// Sends a CoAP packet to the destination.
-fn send(
-    socket: &UdpSocket,
-    destination: &str,
-    proxy: Option<&str>,
-    packet: &[u8],
-) {
-    socket
-        .send_to(
-            packet,
-            if let Some(proxy) = proxy {
-                proxy
-            } else {
-                destination
-            },
-        )
-        .expect("Unable to send packet");
+/// Makes repeated OSCORE requests to the target's /hello and /echo resources.
+///
+/// This only ever returns when a request fails.
+fn oscore_requests(client: &mut OscoreClient) -> Result<(), Error> {
+    for i in 0.. {
+        // Make a request to one of the two resources
+        let res = if i % 2 == 0 {
+            client.request(RequestType::Get, "hello", Vec::new())?
+        } else {
+            client.request(
+                RequestType::Get,
+                "echo",
+                format!("Iteration {}", i).into_bytes(),
+            )?
+        };
+        // Log the payload
+        info!(
+            "Got response: {}",
+            str::from_utf8(&res.payload)
+                .expect("Failed parsing response payload as UTF-8")
+        );
+    }
+
+    Ok(())
 }
@@ -0,0 +1,77 @@
+//! Deciding when to replace the security context with a fresh one.
+
+use desktop_common::persist;
+use std::time::{Duration, Instant};
+
+/// The largest Partial IV OSCORE can encode, which is 5 bytes long.
+pub const MAX_PIV: u64 = (1 << 40) - 1;
+
+/// When to do a new EDHOC exchange for a fresh security context.
+///
+/// The server refuses a context once it reaches the limits, so we re-key
+/// before: when the next sequence number would exceed `max_seq`, or when
+/// 90% of the lifetime passed, in case our clocks disagree. We also re-key
+/// at `persist::MAX_SEQ`, while the context can still be resumed from the
+/// session file.
+#[derive(Clone, Copy, Debug)]
+pub struct RekeyPolicy {
+    /// The sequence number at which the context expires.
+    pub max_seq: u64,
+    /// How long the context may be used, if there is a limit.
+    pub lifetime: Option<Duration>,
+}
+
+impl Default for RekeyPolicy {
+    fn default() -> RekeyPolicy {
+        RekeyPolicy {
+            max_seq: MAX_PIV,
+            lifetime: None,
+        }
+    }
+}
+
+impl RekeyPolicy {
+    /// Returns `true` if a context that used `seq` sequence numbers since it
+    /// was established has to be replaced before the next request.
+    pub fn is_due(&self, seq: u64, established: Instant) -> bool {
+        let too_old = match self.lifetime {
+            Some(lifetime) => established.elapsed() >= lifetime / 10 * 9,
+            None => false,
+        };
+
+        seq >= self.max_seq.min(persist::MAX_SEQ) || too_old
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn due_at_max_seq() {
+        let policy = RekeyPolicy {
+            max_seq: 10,
+            lifetime: None,
+        };
+        assert!(!policy.is_due(9, Instant::now()));
+        assert!(policy.is_due(10, Instant::now()));
+    }
+
+    #[test]
+    fn due_while_still_restorable() {
+        let policy = RekeyPolicy::default();
+        assert!(!policy.is_due(persist::MAX_SEQ - 1, Instant::now()));
+        assert!(policy.is_due(persist::MAX_SEQ, Instant::now()));
+    }
+
+    #[test]
+    fn due_before_lifetime() {
+        let policy = RekeyPolicy {
+            max_seq: MAX_PIV,
+            lifetime: Some(Duration::from_secs(100)),
+        };
+        let now = Instant::now();
+        assert!(!policy.is_due(0, now - Duration::from_secs(89)));
+        assert!(policy.is_due(0, now - Duration::from_secs(91)));
+    }
+}
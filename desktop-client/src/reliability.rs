@@ -0,0 +1,325 @@
+//! Reliable exchanges of Confirmable messages over UDP (RFC 7252, 4.2).
+//!
+//! A Confirmable request is retransmitted with exponential backoff until the
+//! server acknowledges it. The response either comes piggybacked on the ACK,
+//! or separately after an empty ACK, in which case we acknowledge it. TCP
+//! is reliable on its own, so there we only wait for the response.
+//!
+//! ACKs and Resets belong to the request with the same Message ID, responses
+//! to the one with the same token. Anything else is a stale retransmission,
+//! a duplicate or unrelated, and is dropped, or rejected with a Reset if it
+//! wants to be acknowledged.
+
+use rand::{Rng, RngCore};
+use std::{
+    fmt, io,
+    time::{Duration, Instant},
+};
+use tracing::{debug, warn};
+
+use crate::transport::Transport;
+
+/// How long to wait for the ACK of the first transmission, before it's
+/// stretched by the random factor.
+pub const ACK_TIMEOUT: Duration = Duration::from_secs(2);
+/// The most the first timeout is stretched by, to avoid synchronized
+/// retransmissions.
+pub const ACK_RANDOM_FACTOR: f64 = 1.5;
+/// How often a Confirmable message is retransmitted before giving up.
+pub const MAX_RETRANSMIT: u32 = 4;
+/// How long a response may take after the request, or after the empty ACK,
+/// which is the time from the first transmission to the last timeout.
+pub const MAX_TRANSMIT_WAIT: Duration = Duration::from_secs(93);
+
+/// The message type of a Confirmable message.
+const CON: u8 = 0;
+/// The message type of a Non-confirmable message.
+const NON: u8 = 1;
+/// The message type of an Acknowledgement.
+const ACK: u8 = 2;
+/// The message type of a Reset.
+const RST: u8 = 3;
+
+/// The length of our tokens, which is the most CoAP allows.
+const TOKEN_LENGTH: usize = 8;
+
+/// How a received message relates to our request.
+enum Match {
+    /// The server got the request and will respond separately.
+    EmptyAck,
+    /// The response to the request, piggybacked or separate.
+    Response,
+    /// The server rejected the request.
+    Reset,
+    /// Something that isn't for the request.
+    Unexpected,
+}
+
+/// The ways in which an exchange can fail.
+#[derive(Debug)]
+pub enum Error {
+    /// Sending or receiving failed.
+    Io(io::Error),
+    /// The request wasn't acknowledged, even after retransmitting it.
+    Timeout,
+    /// The request was acknowledged, but the response never came.
+    NoResponse,
+    /// The server rejected the request with a Reset.
+    Reset,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Timeout => write!(
+                f,
+                "no acknowledgement after {} retransmissions",
+                MAX_RETRANSMIT
+            ),
+            Error::NoResponse => write!(f, "no response from the server"),
+            Error::Reset => write!(f, "the server reset the exchange"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+/// Returns a fresh random token for a request.
+pub fn new_token(rng: &mut dyn RngCore) -> Vec<u8> {
+    let mut token = vec![0; TOKEN_LENGTH];
+    rng.fill_bytes(&mut token);
+
+    token
+}
+
+/// Sends a request and returns the response to it.
+pub fn exchange(
+    transport: &mut Transport,
+    req: &[u8],
+) -> Result<Vec<u8>, Error> {
+    if token(req).is_none() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "bad message",
+        )
+        .into());
+    }
+    if transport.is_reliable() || message_type(req) != CON {
+        transport.send(req)?;
+        return wait_response(transport, req);
+    }
+
+    let mut timeout = ACK_TIMEOUT
+        .mul_f64(rand::thread_rng().gen_range(1.0, ACK_RANDOM_FACTOR));
+    for attempt in 0..=MAX_RETRANSMIT {
+        if attempt > 0 {
+            warn!(attempt, "No acknowledgement, retransmitting");
+        }
+        transport.send(req)?;
+
+        let deadline = Instant::now() + timeout;
+        while let Some(res) = receive_until(transport, deadline)? {
+            match classify(req, &res) {
+                Match::EmptyAck => {
+                    debug!("Got an empty ACK, waiting for the response");
+                    return wait_response(transport, req);
+                }
+                // Possibly a separate response whose empty ACK got lost
+                Match::Response => return acknowledge(transport, res),
+                Match::Reset => return Err(Error::Reset),
+                Match::Unexpected => reject(transport, &res)?,
+            }
+        }
+        timeout *= 2;
+    }
+
+    Err(Error::Timeout)
+}
+
+/// Waits for the response to a request that needs no acknowledgement, or
+/// already got it.
+fn wait_response(
+    transport: &mut Transport,
+    req: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let deadline = Instant::now() + MAX_TRANSMIT_WAIT;
+    while let Some(res) = receive_until(transport, deadline)? {
+        match classify(req, &res) {
+            // A duplicate, if our request was retransmitted
+            Match::EmptyAck => {}
+            Match::Response => return acknowledge(transport, res),
+            Match::Reset => return Err(Error::Reset),
+            Match::Unexpected => reject(transport, &res)?,
+        }
+    }
+
+    Err(Error::NoResponse)
+}
+
+/// Returns how the message relates to the request.
+fn classify(req: &[u8], res: &[u8]) -> Match {
+    let same_mid = res[2..4] == req[2..4];
+    let same_token = token(res) == token(req);
+    match message_type(res) {
+        ACK if same_mid && res[1] == 0 => Match::EmptyAck,
+        // A piggybacked response has to match both
+        ACK if same_mid && same_token => Match::Response,
+        CON | NON if same_token => Match::Response,
+        RST if same_mid => Match::Reset,
+        _ => Match::Unexpected,
+    }
+}
+
+/// Rejects a message that isn't for us with a Reset if it's Confirmable, or
+/// drops it otherwise.
+fn reject(transport: &mut Transport, msg: &[u8]) -> io::Result<()> {
+    if transport.is_reliable() || message_type(msg) != CON {
+        debug!("Dropping a message for another exchange");
+        return Ok(());
+    }
+    debug!("Rejecting a message for another exchange");
+    // Version 1, Reset, no token, empty and the same MID
+    transport.send(&[0x40 | RST << 4, 0, msg[2], msg[3]])
+}
+
+/// Sends an empty ACK for the response if it's Confirmable, and returns it.
+fn acknowledge(
+    transport: &mut Transport,
+    res: Vec<u8>,
+) -> Result<Vec<u8>, Error> {
+    if !transport.is_reliable() && message_type(&res) == CON {
+        // Version 1, Acknowledgement, no token, empty and the same MID
+        transport.send(&[0x40 | ACK << 4, 0, res[2], res[3]])?;
+    }
+
+    Ok(res)
+}
+
+/// Receives the next message, or returns `None` if none came before the
+/// deadline.
+fn receive_until(
+    transport: &mut Transport,
+    deadline: Instant,
+) -> io::Result<Option<Vec<u8>>> {
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(None);
+        }
+        transport.set_read_timeout(Some(deadline - now))?;
+        match transport.receive() {
+            Ok(msg) if msg.len() >= 4 => return Ok(Some(msg)),
+            Ok(_) => debug!("Dropping a message too short for CoAP"),
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock
+                    || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                return Ok(None)
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Returns the type of a message in the UDP format.
+fn message_type(msg: &[u8]) -> u8 {
+    msg[0] >> 4 & 0x03
+}
+
+/// Returns the token of a message in the UDP format, or `None` if it's
+/// malformed.
+fn token(msg: &[u8]) -> Option<&[u8]> {
+    let tkl = usize::from(*msg.first()? & 0x0F);
+    if tkl > TOKEN_LENGTH {
+        return None;
+    }
+
+    msg.get(4..4 + tkl)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    /// Returns a message in the UDP format.
+    fn message(kind: u8, code: u8, mid: u16, token: &[u8]) -> Vec<u8> {
+        let mut msg = vec![0x40 | kind << 4 | token.len() as u8, code];
+        msg.extend_from_slice(&mid.to_be_bytes());
+        msg.extend_from_slice(token);
+
+        msg
+    }
+
+    #[test]
+    fn classifies() {
+        // A GET with MID 1 and token abc
+        let req = message(CON, 1, 1, b"abc");
+        // 2.05 Content
+        let content = 69;
+
+        let res = message(ACK, 0, 1, b"");
+        assert!(matches!(classify(&req, &res), Match::EmptyAck));
+        let res = message(ACK, content, 1, b"abc");
+        assert!(matches!(classify(&req, &res), Match::Response));
+        let res = message(CON, content, 7, b"abc");
+        assert!(matches!(classify(&req, &res), Match::Response));
+        let res = message(NON, content, 7, b"abc");
+        assert!(matches!(classify(&req, &res), Match::Response));
+        let res = message(RST, 0, 1, b"");
+        assert!(matches!(classify(&req, &res), Match::Reset));
+    }
+
+    #[test]
+    fn rejects_mismatches() {
+        let req = message(CON, 1, 1, b"abc");
+        let content = 69;
+
+        // A stale ACK or Reset for an earlier request
+        let res = message(ACK, 0, 2, b"");
+        assert!(matches!(classify(&req, &res), Match::Unexpected));
+        let res = message(RST, 0, 2, b"");
+        assert!(matches!(classify(&req, &res), Match::Unexpected));
+        // A piggybacked response needs both to match
+        let res = message(ACK, content, 1, b"abd");
+        assert!(matches!(classify(&req, &res), Match::Unexpected));
+        let res = message(ACK, content, 2, b"abc");
+        assert!(matches!(classify(&req, &res), Match::Unexpected));
+        let res = message(CON, content, 1, b"abd");
+        assert!(matches!(classify(&req, &res), Match::Unexpected));
+        let res = message(NON, content, 1, b"");
+        assert!(matches!(classify(&req, &res), Match::Unexpected));
+    }
+
+    #[test]
+    fn reads_token() {
+        assert_eq!(token(&message(CON, 1, 1, b"")), Some(&b""[..]));
+        assert_eq!(
+            token(&message(CON, 1, 1, b"12345678")),
+            Some(&b"12345678"[..])
+        );
+        assert_eq!(token(&[]), None);
+        // Longer than the message
+        assert_eq!(token(&[0x44, 1, 0, 1, 0xab]), None);
+        // TKL 9 to 15 are reserved
+        let mut msg = message(CON, 1, 1, b"123456789");
+        assert_eq!(token(&msg), None);
+        msg[0] |= 0x0F;
+        assert_eq!(token(&msg), None);
+    }
+
+    #[test]
+    fn seeded_tokens() {
+        let token = new_token(&mut StdRng::seed_from_u64(1));
+        assert_eq!(token.len(), TOKEN_LENGTH);
+        assert_eq!(token, new_token(&mut StdRng::seed_from_u64(1)));
+        assert_ne!(token, new_token(&mut StdRng::seed_from_u64(2)));
+    }
+}
@@ -0,0 +1,174 @@
+//! Building of requests from the command line and printing of the responses.
+
+use coap_lite::{CoapOption, MessageClass, MessageType, Packet, RequestType};
+use rand::{Rng, RngCore};
+use std::str;
+
+use crate::{reliability, uri::Uri};
+
+/// The Content-Formats that can be given by name.
+const CONTENT_FORMATS: [(&str, u16); 5] = [
+    ("text", 0),
+    ("link-format", 40),
+    ("octet-stream", 42),
+    ("json", 50),
+    ("cbor", 60),
+];
+
+/// The names of the options we know, by number.
+const OPTION_NAMES: [(usize, &str); 17] = [
+    (1, "If-Match"),
+    (4, "ETag"),
+    (5, "If-None-Match"),
+    (6, "Observe"),
+    (8, "Location-Path"),
+    (9, "OSCORE"),
+    (11, "Uri-Path"),
+    (12, "Content-Format"),
+    (14, "Max-Age"),
+    (15, "Uri-Query"),
+    (17, "Accept"),
+    (20, "Location-Query"),
+    (23, "Block2"),
+    (27, "Block1"),
+    (28, "Size2"),
+    (35, "Proxy-Uri"),
+    (60, "Size1"),
+];
+
+/// The options whose values are unsigned integers.
+const UINT_OPTIONS: [usize; 8] = [6, 12, 14, 17, 23, 27, 28, 60];
+
+/// What a request from the command line consists of.
+pub struct Request {
+    pub method: RequestType,
+    pub uri: Uri,
+    pub payload: Vec<u8>,
+    pub content_format: Option<u16>,
+    pub accept: Option<u16>,
+}
+
+impl Request {
+    /// Returns the request as a Confirmable message, which goes through
+    /// the proxy if `proxy` is set, or `None` if it's too large to encode.
+    ///
+    /// The Message ID and token are drawn from `rng`.
+    pub fn to_bytes(
+        &self,
+        proxy: bool,
+        rng: &mut dyn RngCore,
+    ) -> Option<Vec<u8>> {
+        let mut req = Packet::new();
+
+        // Confirmable, so it's retransmitted until the server acknowledges it
+        req.header.set_type(MessageType::Confirmable);
+        // This message ID should be acknowledged by the server for
+        // reliability
+        req.header.message_id = rng.gen();
+        // And the token is used to tie response to request
+        req.set_token(reliability::new_token(rng));
+        req.header.code = MessageClass::Request(self.method);
+        // Add Proxy-Uri or Uri-Path and Uri-Query
+        if proxy {
+            req.add_option(
+                CoapOption::ProxyUri,
+                self.uri.proxy_uri().into_bytes(),
+            );
+        } else {
+            for segment in &self.uri.path {
+                req.add_option(CoapOption::UriPath, segment.clone());
+            }
+            for argument in &self.uri.query {
+                req.add_option(CoapOption::UriQuery, argument.clone());
+            }
+        }
+        if let Some(content_format) = self.content_format {
+            req.add_option(
+                CoapOption::ContentFormat,
+                uint_value(content_format),
+            );
+        }
+        if let Some(accept) = self.accept {
+            req.add_option(CoapOption::Accept, uint_value(accept));
+        }
+        req.payload = self.payload.clone();
+
+        req.to_bytes().ok()
+    }
+}
+
+/// Parses a Content-Format given by name or number.
+pub fn parse_content_format(cf: &str) -> Result<u16, String> {
+    CONTENT_FORMATS
+        .iter()
+        .find(|(name, _)| *name == cf)
+        .map(|&(_, number)| Ok(number))
+        .unwrap_or_else(|| {
+            cf.parse()
+                .map_err(|_| format!("unknown Content-Format {}", cf))
+        })
+}
+
+/// Prints the code, options and payload of a response to stdout.
+pub fn print_response(res: &Packet) {
+    let code: u8 = res.header.code.into();
+    match res.header.code {
+        MessageClass::Response(name) => {
+            println!("{}.{:02} {:?}", code >> 5, code & 0x1F, name)
+        }
+        _ => println!("{}.{:02}", code >> 5, code & 0x1F),
+    }
+
+    let mut content_format = None;
+    for (&number, values) in res.options() {
+        let name = OPTION_NAMES
+            .iter()
+            .find(|(n, _)| *n == number)
+            .map(|(_, name)| name.to_string())
+            .unwrap_or_else(|| format!("Option {}", number));
+        for value in values {
+            if UINT_OPTIONS.contains(&number) {
+                let n = value.iter().fold(0u64, |n, &b| n << 8 | u64::from(b));
+                if number == 12 {
+                    content_format = Some(n);
+                }
+                println!("{}: {}", name, n);
+            } else {
+                println!("{}: {}", name, display_bytes(value));
+            }
+        }
+    }
+
+    if res.payload.is_empty() {
+        return;
+    }
+    println!();
+    match content_format {
+        // CBOR is shown in its diagnostic notation, more or less
+        Some(60) => {
+            match serde_cbor::from_slice::<serde_cbor::Value>(&res.payload) {
+                Ok(value) => println!("{:?}", value),
+                Err(_) => println!("{}", hex::encode(&res.payload)),
+            }
+        }
+        _ => println!("{}", display_bytes(&res.payload)),
+    }
+}
+
+/// Returns bytes as text if they're UTF-8, or as hex otherwise.
+fn display_bytes(bytes: &[u8]) -> String {
+    match str::from_utf8(bytes) {
+        Ok(text) if !text.chars().any(|c| c.is_control() && c != '\n') => {
+            text.to_string()
+        }
+        _ => format!("0x{}", hex::encode(bytes)),
+    }
+}
+
+/// Encodes an unsigned integer option value in as few bytes as possible.
+fn uint_value(n: u16) -> Vec<u8> {
+    let bytes = n.to_be_bytes();
+    let skip = bytes.iter().take_while(|&&b| b == 0).count();
+
+    bytes[skip..].to_vec()
+}
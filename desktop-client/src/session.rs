@@ -0,0 +1,148 @@
+//! Saving the security context, so later runs can resume it instead of
+//! doing an EDHOC handshake.
+//!
+//! The session file is a CBOR map, saved like the server's state file (see
+//! `desktop_common::persist`).
+
+use desktop_common::persist::{
+    bytes, get_bytes, get_u64, key, write_atomically, K,
+};
+use serde_cbor::Value;
+use std::{
+    collections::BTreeMap,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// The ways in which loading or saving the session can fail.
+#[derive(Debug)]
+pub enum Error {
+    /// The file couldn't be read or written.
+    Io(io::Error),
+    /// The file is not a valid session file.
+    Format(&'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "unable to access session file: {}", e),
+            Error::Format(reason) => {
+                write!(f, "invalid session file: {}", reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+/// What a security context is recreated from.
+pub struct Saved {
+    pub master_secret: Vec<u8>,
+    pub master_salt: Vec<u8>,
+    pub sender_id: Vec<u8>,
+    pub recipient_id: Vec<u8>,
+    /// The sequence number the next fresh Partial IV will have.
+    pub sender_seq: u64,
+    /// When the context was established.
+    pub created: SystemTime,
+}
+
+/// The file the security context is saved to.
+pub struct SessionFile {
+    path: PathBuf,
+    /// The sequence number bound on disk.
+    reserved: u64,
+}
+
+impl SessionFile {
+    /// Creates a new `SessionFile` at the given path, which doesn't need to
+    /// exist yet.
+    pub fn new(path: &Path) -> SessionFile {
+        SessionFile {
+            path: path.to_path_buf(),
+            reserved: 0,
+        }
+    }
+
+    /// Loads the saved context, or returns `None` if the file doesn't exist.
+    ///
+    /// The context has to be saved again before it's used, to advance the
+    /// bound on disk.
+    pub fn load(&self) -> Result<Option<Saved>, Error> {
+        let bytes = match fs::read(&self.path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let record = match serde_cbor::from_slice(&bytes) {
+            Ok(Value::Map(record)) => record,
+            _ => return Err(Error::Format("not a CBOR map")),
+        };
+        let seconds = get_u64(&record, "created").map_err(Error::Format)?;
+
+        Ok(Some(Saved {
+            master_secret: get_bytes(&record, "master_secret")
+                .map_err(Error::Format)?,
+            master_salt: get_bytes(&record, "master_salt")
+                .map_err(Error::Format)?,
+            sender_id: get_bytes(&record, "sender_id")
+                .map_err(Error::Format)?,
+            recipient_id: get_bytes(&record, "recipient_id")
+                .map_err(Error::Format)?,
+            sender_seq: get_u64(&record, "sender_seq")
+                .map_err(Error::Format)?,
+            created: UNIX_EPOCH + Duration::from_secs(seconds),
+        }))
+    }
+
+    /// Removes the saved context, if there is one.
+    pub fn remove(&mut self) -> Result<(), Error> {
+        match fs::remove_file(&self.path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        self.reserved = 0;
+
+        Ok(())
+    }
+
+    /// Returns `true` if the context has reached the bound on disk and needs
+    /// to be saved before the sequence number is used.
+    pub fn is_stale(&self, sender_seq: u64) -> bool {
+        sender_seq >= self.reserved
+    }
+
+    /// Saves the context, reserving the next `K` sequence numbers.
+    pub fn save(&mut self, saved: &Saved) -> Result<(), Error> {
+        let bound = saved.sender_seq + K;
+        let created = saved
+            .created
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+
+        let mut record = BTreeMap::new();
+        record.insert(key("master_secret"), bytes(&saved.master_secret));
+        record.insert(key("master_salt"), bytes(&saved.master_salt));
+        record.insert(key("sender_id"), bytes(&saved.sender_id));
+        record.insert(key("recipient_id"), bytes(&saved.recipient_id));
+        record.insert(key("sender_seq"), Value::Integer(bound.into()));
+        record.insert(key("created"), Value::Integer(created.into()));
+        let bytes = serde_cbor::to_vec(&Value::Map(record))
+            .map_err(|_| Error::Format("unable to encode session"))?;
+
+        write_atomically(&self.path, &bytes)?;
+        self.reserved = bound;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,110 @@
+//! The transports we can reach a server with, UDP or TCP (RFC 8323).
+
+use std::{
+    io::{self, Write},
+    net::{TcpStream, UdpSocket},
+    time::Duration,
+};
+
+use crate::tcp;
+
+/// A way of exchanging CoAP messages in the UDP format with a server.
+pub enum Transport {
+    /// A socket connected to the target, which is the server or a proxy.
+    Udp(UdpSocket),
+    /// A connection to the server.
+    Tcp(TcpStream),
+}
+
+impl Transport {
+    /// Binds to the local port to exchange datagrams with the target.
+    pub fn udp(port: &str, target: &str) -> io::Result<Transport> {
+        let socket = UdpSocket::bind(format!("0.0.0.0:{}", port))?;
+        // Connecting makes the socket drop datagrams from anyone else, so
+        // we only ever see the target's
+        socket.connect(target)?;
+
+        Ok(Transport::Udp(socket))
+    }
+
+    /// Connects to the server and sends our CSM.
+    pub fn tcp(server: &str) -> io::Result<Transport> {
+        let mut stream = TcpStream::connect(server)?;
+        stream.write_all(&tcp::csm())?;
+
+        Ok(Transport::Tcp(stream))
+    }
+
+    /// Returns `true` if the transport delivers messages reliably, so they
+    /// don't need to be acknowledged or retransmitted.
+    pub fn is_reliable(&self) -> bool {
+        match self {
+            Transport::Udp(_) => false,
+            Transport::Tcp(_) => true,
+        }
+    }
+
+    /// Sets how long `receive` waits before failing, or makes it wait
+    /// forever with `None`.
+    pub fn set_read_timeout(
+        &self,
+        timeout: Option<Duration>,
+    ) -> io::Result<()> {
+        match self {
+            Transport::Udp(socket) => socket.set_read_timeout(timeout),
+            Transport::Tcp(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    /// Sends a message.
+    pub fn send(&mut self, msg: &[u8]) -> io::Result<()> {
+        match self {
+            Transport::Udp(socket) => {
+                socket.send(msg)?;
+            }
+            Transport::Tcp(stream) => {
+                let frame = tcp::from_datagram(msg).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "bad message")
+                })?;
+                stream.write_all(&frame)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Receives the next message, answering any signaling messages that
+    /// arrive before it.
+    pub fn receive(&mut self) -> io::Result<Vec<u8>> {
+        let stream = match self {
+            Transport::Udp(socket) => {
+                let mut buf = [0; 2048];
+                let amt = socket.recv(&mut buf)?;
+                return Ok(buf[..amt].to_vec());
+            }
+            Transport::Tcp(stream) => stream,
+        };
+
+        loop {
+            let frame = tcp::read_frame(stream)?.ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "server closed the connection",
+                )
+            })?;
+            match tcp::code(&frame) {
+                0 | tcp::CSM | tcp::PONG => {}
+                tcp::PING => stream
+                    .write_all(&tcp::signal(tcp::PONG, tcp::token(&frame)))?,
+                tcp::RELEASE | tcp::ABORT => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        "server ended the connection",
+                    ));
+                }
+                code if code >> 5 == 7 => {}
+                _ => return Ok(tcp::to_datagram(&frame)),
+            }
+        }
+    }
+}
@@ -0,0 +1,181 @@
+//! Parsing of the `coap://` and `coap+tcp://` URIs given on the command line.
+
+/// The port CoAP uses if the URI doesn't have one.
+const DEFAULT_PORT: u16 = 5683;
+
+/// A CoAP URI split into what goes into the request.
+pub struct Uri {
+    /// Whether the scheme is `coap+tcp`.
+    pub tcp: bool,
+    /// The host and port.
+    pub authority: String,
+    /// The percent-decoded path segments.
+    pub path: Vec<Vec<u8>>,
+    /// The percent-decoded query arguments.
+    pub query: Vec<Vec<u8>>,
+}
+
+impl Uri {
+    /// Parses a URI like `coap://localhost:5683/store/key?a=1`.
+    pub fn parse(uri: &str) -> Result<Uri, String> {
+        let (tcp, rest) = if let Some(rest) = uri.strip_prefix("coap+tcp://") {
+            (true, rest)
+        } else if let Some(rest) = uri.strip_prefix("coap://") {
+            (false, rest)
+        } else {
+            return Err(format!("{}: not a coap:// or coap+tcp:// URI", uri));
+        };
+        // Fragments are never sent
+        let rest = rest.split('#').next().unwrap_or_default();
+        let (rest, query) = match rest.find('?') {
+            Some(i) => (&rest[..i], Some(&rest[i + 1..])),
+            None => (rest, None),
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i + 1..]),
+            None => (rest, ""),
+        };
+        if authority.is_empty() {
+            return Err(format!("{}: no host", uri));
+        }
+
+        let path = path
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .map(|s| percent_decode(s).ok_or_else(|| invalid(uri)))
+            .collect::<Result<_, _>>()?;
+        let query = query
+            .into_iter()
+            .flat_map(|q| q.split('&'))
+            .filter(|q| !q.is_empty())
+            .map(|q| percent_decode(q).ok_or_else(|| invalid(uri)))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Uri {
+            tcp,
+            authority: with_port(authority),
+            path,
+            query,
+        })
+    }
+
+    /// Returns the destination to connect to, with the `coap+tcp://`
+    /// scheme for TCP.
+    pub fn destination(&self) -> String {
+        if self.tcp {
+            format!("coap+tcp://{}", self.authority)
+        } else {
+            self.authority.clone()
+        }
+    }
+
+    /// Returns the URI to give a proxy in the Proxy-Uri option.
+    pub fn proxy_uri(&self) -> String {
+        let mut uri = format!("coap://{}/", self.authority);
+        let path: Vec<String> = self
+            .path
+            .iter()
+            .map(|s| String::from_utf8_lossy(s).into_owned())
+            .collect();
+        uri.push_str(&path.join("/"));
+        if !self.query.is_empty() {
+            let query: Vec<String> = self
+                .query
+                .iter()
+                .map(|q| String::from_utf8_lossy(q).into_owned())
+                .collect();
+            uri.push('?');
+            uri.push_str(&query.join("&"));
+        }
+
+        uri
+    }
+}
+
+/// Returns the error for a URI with broken percent-encoding.
+fn invalid(uri: &str) -> String {
+    format!("{}: invalid percent-encoding", uri)
+}
+
+/// Adds the default port to the host if it has none.
+fn with_port(authority: &str) -> String {
+    // An IPv6 address is in brackets, so its colons don't count
+    let host_end = authority.rfind(']').unwrap_or(0);
+    if authority[host_end..].contains(':') {
+        authority.to_string()
+    } else {
+        format!("{}:{}", authority, DEFAULT_PORT)
+    }
+}
+
+/// Decodes the `%XX` escapes, returning `None` if one is malformed.
+fn percent_decode(s: &str) -> Option<Vec<u8>> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = s.get(i + 1..i + 3)?;
+            // from_str_radix would take a sign too
+            if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return None;
+            }
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_udp() {
+        let uri = Uri::parse("coap://localhost/store/key?a=1&b#top").unwrap();
+        assert!(!uri.tcp);
+        assert_eq!(uri.authority, "localhost:5683");
+        assert_eq!(uri.path, [b"store".to_vec(), b"key".to_vec()]);
+        assert_eq!(uri.query, [b"a=1".to_vec(), b"b".to_vec()]);
+        assert_eq!(uri.destination(), "localhost:5683");
+        assert_eq!(uri.proxy_uri(), "coap://localhost:5683/store/key?a=1&b");
+    }
+
+    #[test]
+    fn parses_tcp() {
+        let uri = Uri::parse("coap+tcp://127.0.0.1:5684").unwrap();
+        assert!(uri.tcp);
+        assert!(uri.path.is_empty());
+        assert!(uri.query.is_empty());
+        assert_eq!(uri.destination(), "coap+tcp://127.0.0.1:5684");
+    }
+
+    #[test]
+    fn ipv6_port() {
+        let uri = Uri::parse("coap://[::1]/hello").unwrap();
+        assert_eq!(uri.authority, "[::1]:5683");
+        let uri = Uri::parse("coap://[::1]:5684/hello").unwrap();
+        assert_eq!(uri.authority, "[::1]:5684");
+    }
+
+    #[test]
+    fn percent_decodes() {
+        let uri = Uri::parse("coap://h/a%20b//c?x%3D%26").unwrap();
+        assert_eq!(uri.path, [b"a b".to_vec(), b"c".to_vec()]);
+        assert_eq!(uri.query, [b"x=&".to_vec()]);
+    }
+
+    #[test]
+    fn rejects_invalid() {
+        assert!(Uri::parse("http://localhost/").is_err());
+        assert!(Uri::parse("coap:///hello").is_err());
+        assert!(Uri::parse("coap://h/%4").is_err());
+        assert!(Uri::parse("coap://h/%zz").is_err());
+        assert!(Uri::parse("coap://h/%+1").is_err());
+    }
+}
@@ -0,0 +1,387 @@
+//! Loading of EDHOC authentication keys from files.
+//!
+//! A key file is either a COSE_Key (CBOR encoded, as in RFC 8152) or the raw
+//! key as hex. Since a raw key has no key ID, that one then has to be given
+//! separately.
+
+use ed25519_dalek::{PublicKey, SecretKey};
+use serde_cbor::Value;
+use std::{collections::BTreeMap, fmt, fs, io, path::Path};
+
+/* COSE_Key labels and values */
+const KTY: i128 = 1;
+const KID: i128 = 2;
+const CRV: i128 = -1;
+const X: i128 = -2;
+const D: i128 = -4;
+const KTY_OKP: i128 = 1;
+const CRV_ED25519: i128 = 6;
+
+/// Our own authentication key pair with its key ID.
+pub struct KeyPair {
+    pub kid: Vec<u8>,
+    pub private: [u8; 32],
+    pub public: [u8; 32],
+}
+
+/// The public authentication key of a peer with its key ID.
+pub struct PeerKey {
+    pub kid: Vec<u8>,
+    pub public: [u8; 32],
+}
+
+/// The ways in which loading a key can fail.
+#[derive(Debug)]
+pub enum Error {
+    /// The file couldn't be read.
+    Io(io::Error),
+    /// The file is neither valid hex nor a CBOR map.
+    Format,
+    /// The key is not an Ed25519 key of the right length.
+    Key(&'static str),
+    /// The public key doesn't belong to the private key.
+    Mismatch,
+    /// The key ID is missing or contradicts the one given.
+    Kid(&'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "unable to read key file: {}", e),
+            Error::Format => {
+                write!(f, "key file is neither a COSE_Key nor hex")
+            }
+            Error::Key(reason) => write!(f, "invalid key: {}", reason),
+            Error::Mismatch => {
+                write!(f, "public key does not match the private key")
+            }
+            Error::Kid(reason) => write!(f, "invalid key ID: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+/// Loads our key pair from a file, deriving the public key.
+///
+/// The `kid` is required for raw keys, and if the file is a COSE_Key with a
+/// key ID of its own, both have to match.
+pub fn load_key_pair(
+    path: &Path,
+    kid: Option<Vec<u8>>,
+) -> Result<KeyPair, Error> {
+    let (private, public, kid) = match read_key_file(path)? {
+        KeyFile::Raw(bytes) => (bytes, None, kid),
+        KeyFile::Cose(map) => {
+            let private = get_bytes(&map, D)?
+                .ok_or(Error::Key("COSE_Key has no private key"))?;
+            let public = get_bytes(&map, X)?;
+            let kid = merge_kid(get_bytes(&map, KID)?, kid)?;
+            (private, public, kid)
+        }
+    };
+    let private = to_key(&private)?;
+    let derived = derive_public(&private)?;
+    // If the file has a public key too, it better be the right one
+    if let Some(public) = public {
+        if to_key(&public)? != derived {
+            return Err(Error::Mismatch);
+        }
+    }
+
+    Ok(KeyPair {
+        kid: kid.ok_or(Error::Kid("none given and none in key file"))?,
+        private,
+        public: derived,
+    })
+}
+
+/// Loads the public key of a peer from a file.
+///
+/// The `kid` is required for raw keys, and if the file is a COSE_Key with a
+/// key ID of its own, both have to match.
+pub fn load_peer_key(
+    path: &Path,
+    kid: Option<Vec<u8>>,
+) -> Result<PeerKey, Error> {
+    let (public, kid) = match read_key_file(path)? {
+        KeyFile::Raw(bytes) => (bytes, kid),
+        KeyFile::Cose(map) => {
+            // Being handed the peer's private key is a sign of a mixup
+            if get_bytes(&map, D)?.is_some() {
+                return Err(Error::Key("peer key contains a private key"));
+            }
+            let public = get_bytes(&map, X)?
+                .ok_or(Error::Key("COSE_Key has no public key"))?;
+            let kid = merge_kid(get_bytes(&map, KID)?, kid)?;
+            (public, kid)
+        }
+    };
+
+    Ok(PeerKey {
+        kid: kid.ok_or(Error::Kid("none given and none in key file"))?,
+        public: to_key(&public)?,
+    })
+}
+
+/// Parses a key ID given as hex.
+pub fn parse_kid(kid: &str) -> Result<Vec<u8>, Error> {
+    hex::decode(kid).map_err(|_| Error::Kid("not valid hex"))
+}
+
+/// Returns the Ed25519 public key belonging to the private key.
+pub fn derive_public(private: &[u8; 32]) -> Result<[u8; 32], Error> {
+    let secret = SecretKey::from_bytes(private)
+        .map_err(|_| Error::Key("not an Ed25519 private key"))?;
+
+    Ok(PublicKey::from(&secret).to_bytes())
+}
+
+/// The contents of a key file.
+enum KeyFile {
+    /// The bytes of a raw key.
+    Raw(Vec<u8>),
+    /// The map of a COSE_Key.
+    Cose(BTreeMap<Value, Value>),
+}
+
+/// Reads a key file, determining whether it's raw hex or a COSE_Key.
+fn read_key_file(path: &Path) -> Result<KeyFile, Error> {
+    let bytes = fs::read(path)?;
+
+    // Anything that is nothing but hex digits and whitespace is a raw key
+    if let Ok(text) = std::str::from_utf8(&bytes) {
+        let text: String =
+            text.chars().filter(|c| !c.is_whitespace()).collect();
+        if !text.is_empty() && text.chars().all(|c| c.is_ascii_hexdigit()) {
+            let key = hex::decode(text).map_err(|_| Error::Format)?;
+            return Ok(KeyFile::Raw(key));
+        }
+    }
+
+    match serde_cbor::from_slice(&bytes) {
+        Ok(Value::Map(map)) => {
+            // We only deal in Ed25519, so check that's what we have
+            check_int(&map, KTY, KTY_OKP, "key type is not OKP")?;
+            check_int(&map, CRV, CRV_ED25519, "curve is not Ed25519")?;
+            Ok(KeyFile::Cose(map))
+        }
+        _ => Err(Error::Format),
+    }
+}
+
+/// Returns the byte string under the label, if there is one.
+fn get_bytes(
+    map: &BTreeMap<Value, Value>,
+    label: i128,
+) -> Result<Option<Vec<u8>>, Error> {
+    match map.get(&Value::Integer(label)) {
+        Some(Value::Bytes(bytes)) => Ok(Some(bytes.clone())),
+        Some(_) => Err(Error::Key("COSE_Key parameter is not a byte string")),
+        None => Ok(None),
+    }
+}
+
+/// Checks that the integer under the label, if present, has the value.
+fn check_int(
+    map: &BTreeMap<Value, Value>,
+    label: i128,
+    expected: i128,
+    reason: &'static str,
+) -> Result<(), Error> {
+    match map.get(&Value::Integer(label)) {
+        Some(Value::Integer(value)) if *value == expected => Ok(()),
+        Some(_) => Err(Error::Key(reason)),
+        None => Ok(()),
+    }
+}
+
+/// Combines the key ID from the file with the one given, if any.
+fn merge_kid(
+    from_file: Option<Vec<u8>>,
+    given: Option<Vec<u8>>,
+) -> Result<Option<Vec<u8>>, Error> {
+    match (from_file, given) {
+        (Some(a), Some(b)) if a != b => {
+            Err(Error::Kid("differs from the one in the key file"))
+        }
+        (a, b) => Ok(a.or(b)),
+    }
+}
+
+/// Converts bytes to a key, checking the length.
+fn to_key(bytes: &[u8]) -> Result<[u8; 32], Error> {
+    if bytes.len() != 32 {
+        return Err(Error::Key("key is not 32 bytes long"));
+    }
+    let mut key = [0; 32];
+    key.copy_from_slice(bytes);
+
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{path::PathBuf, process};
+
+    // The first test vector of RFC 8032
+    const PRIVATE: &str =
+        "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60";
+    const PUBLIC: &str =
+        "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a";
+
+    /// Writes the contents to a file of its own in the temp directory.
+    fn key_file(name: &str, contents: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "keys-test-{}-{}",
+            process::id(),
+            name
+        ));
+        fs::write(&path, contents).unwrap();
+
+        path
+    }
+
+    /// Returns a COSE_Key with the given parameters.
+    fn cose_key(params: Vec<(i128, Value)>) -> Vec<u8> {
+        let mut map: BTreeMap<Value, Value> = params
+            .into_iter()
+            .map(|(label, value)| (Value::Integer(label), value))
+            .collect();
+        map.insert(Value::Integer(KTY), Value::Integer(KTY_OKP));
+        map.insert(Value::Integer(CRV), Value::Integer(CRV_ED25519));
+
+        serde_cbor::to_vec(&Value::Map(map)).unwrap()
+    }
+
+    fn bytes(hex: &str) -> Value {
+        Value::Bytes(hex::decode(hex).unwrap())
+    }
+
+    #[test]
+    fn raw_key_pair() {
+        let path = key_file("raw", format!("{}\n", PRIVATE).as_bytes());
+        let pair = load_key_pair(&path, Some(vec![0xA2])).unwrap();
+        assert_eq!(pair.kid, [0xA2]);
+        assert_eq!(pair.public.to_vec(), hex::decode(PUBLIC).unwrap());
+    }
+
+    #[test]
+    fn raw_key_needs_kid() {
+        let path = key_file("raw-no-kid", PRIVATE.as_bytes());
+        assert!(matches!(load_key_pair(&path, None), Err(Error::Kid(_))));
+        assert!(matches!(load_peer_key(&path, None), Err(Error::Kid(_))));
+    }
+
+    #[test]
+    fn raw_key_of_wrong_length() {
+        let path = key_file("raw-short", &PRIVATE.as_bytes()[..62]);
+        assert!(matches!(
+            load_key_pair(&path, Some(vec![0xA2])),
+            Err(Error::Key(_))
+        ));
+    }
+
+    #[test]
+    fn cose_key_pair() {
+        let path = key_file(
+            "cose",
+            &cose_key(vec![
+                (D, bytes(PRIVATE)),
+                (X, bytes(PUBLIC)),
+                (KID, bytes("a3")),
+            ]),
+        );
+        let pair = load_key_pair(&path, None).unwrap();
+        assert_eq!(pair.kid, [0xA3]);
+        assert_eq!(pair.private.to_vec(), hex::decode(PRIVATE).unwrap());
+        // The same kid may be given again, but not a different one
+        assert!(load_key_pair(&path, Some(vec![0xA3])).is_ok());
+        assert!(matches!(
+            load_key_pair(&path, Some(vec![0xA2])),
+            Err(Error::Kid(_))
+        ));
+    }
+
+    #[test]
+    fn cose_key_with_wrong_public_key() {
+        let mut public = hex::decode(PUBLIC).unwrap();
+        public[0] ^= 1;
+        let path = key_file(
+            "cose-mismatch",
+            &cose_key(vec![
+                (D, bytes(PRIVATE)),
+                (X, Value::Bytes(public)),
+                (KID, bytes("a3")),
+            ]),
+        );
+        assert!(matches!(load_key_pair(&path, None), Err(Error::Mismatch)));
+    }
+
+    #[test]
+    fn cose_key_of_other_curve() {
+        let mut map = BTreeMap::new();
+        map.insert(Value::Integer(KTY), Value::Integer(KTY_OKP));
+        // X25519 instead of Ed25519
+        map.insert(Value::Integer(CRV), Value::Integer(4));
+        map.insert(Value::Integer(X), bytes(PUBLIC));
+        let path = key_file(
+            "cose-curve",
+            &serde_cbor::to_vec(&Value::Map(map)).unwrap(),
+        );
+        assert!(matches!(
+            load_peer_key(&path, Some(vec![0xA3])),
+            Err(Error::Key(_))
+        ));
+    }
+
+    #[test]
+    fn cose_peer_key() {
+        let path = key_file(
+            "cose-peer",
+            &cose_key(vec![(X, bytes(PUBLIC)), (KID, bytes("a3"))]),
+        );
+        let peer = load_peer_key(&path, None).unwrap();
+        assert_eq!(peer.kid, [0xA3]);
+        assert_eq!(peer.public.to_vec(), hex::decode(PUBLIC).unwrap());
+    }
+
+    #[test]
+    fn peer_key_with_private_key() {
+        let path = key_file(
+            "cose-peer-private",
+            &cose_key(vec![
+                (D, bytes(PRIVATE)),
+                (X, bytes(PUBLIC)),
+                (KID, bytes("a3")),
+            ]),
+        );
+        assert!(matches!(load_peer_key(&path, None), Err(Error::Key(_))));
+    }
+
+    #[test]
+    fn neither_hex_nor_cbor() {
+        let path = key_file("garbage", b"not a key");
+        assert!(matches!(
+            load_peer_key(&path, Some(vec![0xA3])),
+            Err(Error::Format)
+        ));
+        let path = key_file("missing", b"");
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(load_peer_key(&path, None), Err(Error::Io(_))));
+    }
+
+    #[test]
+    fn kid_from_hex() {
+        assert_eq!(parse_kid("a2").unwrap(), [0xA2]);
+        assert!(matches!(parse_kid("xyz"), Err(Error::Kid(_))));
+    }
+}
@@ -0,0 +1,5 @@
+//! What the desktop server, client and proxy have in common.
+
+pub mod keys;
+pub mod logging;
+pub mod persist;
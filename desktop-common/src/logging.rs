@@ -0,0 +1,73 @@
+//! Setup of the log output and redaction of key material in it.
+//!
+//! Everything is logged through `tracing`, so the level of each module can
+//! be chosen with filter directives like `info,desktop_server::edhoc=debug`.
+
+use std::{
+    fmt,
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use tracing_subscriber::EnvFilter;
+
+/// Whether key material is logged instead of being redacted.
+static SHOW_SECRETS: AtomicBool = AtomicBool::new(false);
+
+/// The format of the log output.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// Human-readable lines.
+    Text,
+    /// One JSON object per line, for log pipelines.
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("unknown log format {}", s)),
+        }
+    }
+}
+
+/// Installs the global subscriber writing to stdout.
+///
+/// The filter directives are taken from `RUST_LOG` if they're not given,
+/// and default to `info`.
+pub fn init(format: Format, directives: Option<&str>) -> Result<(), String> {
+    let filter = match directives {
+        Some(directives) => EnvFilter::try_new(directives)
+            .map_err(|e| format!("invalid log filter: {}", e))?,
+        None => EnvFilter::try_from_default_env()
+            .unwrap_or_else(|_| EnvFilter::new("info")),
+    };
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    let result = match format {
+        Format::Text => builder.try_init(),
+        Format::Json => builder.json().try_init(),
+    };
+
+    result.map_err(|e| format!("unable to set up logging: {}", e))
+}
+
+/// Makes `Secret` show the key material it wraps. Only meant for debugging.
+pub fn show_secrets(show: bool) {
+    SHOW_SECRETS.store(show, Ordering::Relaxed);
+}
+
+/// Key material to log, which is redacted unless `show_secrets` was used.
+pub struct Secret<'a>(pub &'a [u8]);
+
+impl fmt::Display for Secret<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if SHOW_SECRETS.load(Ordering::Relaxed) {
+            write!(f, "{}", hex::encode(self.0))
+        } else {
+            write!(f, "<redacted>")
+        }
+    }
+}
@@ -0,0 +1,162 @@
+//! Saving security contexts to files, for the server's state file and the
+//! client's session file.
+//!
+//! Both follow RFC 8613 Appendix B.1.1: the sender sequence number isn't
+//! written on every use, but as a bound `K` ahead of the one in use. After
+//! a crash, we continue from that bound, so a sequence number is never used
+//! twice.
+
+use oscore::oscore::{Error, SecurityContext};
+use serde_cbor::Value;
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    fs,
+    io::{self, Write},
+    path::Path,
+};
+
+/// How far ahead of the sequence number in use the stored one is.
+pub const K: u64 = 64;
+
+/// The highest sequence number a security context is restored at.
+///
+/// There's no way to set the sequence number of a context, so it's advanced
+/// by protecting a request for every number used up. Past this, that takes
+/// too long, and the peers had better do EDHOC again.
+pub const MAX_RESTORE_SEQ: u64 = 1 << 16;
+
+/// The sequence number at which a persisted security context has to be
+/// replaced, so the bound `K` ahead of it can still be restored.
+pub const MAX_SEQ: u64 = MAX_RESTORE_SEQ - K;
+
+/// The request protected to use up sequence numbers.
+const DUMMY_REQUEST: [u8; 4] = [0x40, 0x01, 0x00, 0x00];
+
+/// Recreates a security context continuing from the sequence number, or
+/// returns `None` if it's beyond `MAX_RESTORE_SEQ`.
+pub fn restore_context(
+    master_secret: Vec<u8>,
+    master_salt: Vec<u8>,
+    sender_id: Vec<u8>,
+    recipient_id: Vec<u8>,
+    sender_seq: u64,
+) -> Result<Option<SecurityContext>, Error> {
+    if sender_seq > MAX_RESTORE_SEQ {
+        return Ok(None);
+    }
+    let mut context = SecurityContext::new(
+        master_secret,
+        master_salt,
+        sender_id,
+        recipient_id,
+    )?;
+    for _ in 0..sender_seq {
+        context.protect_request(&DUMMY_REQUEST)?;
+    }
+
+    Ok(Some(context))
+}
+
+/// Replaces the file with the bytes.
+///
+/// They're written to a temporary file first and flushed to disk before
+/// it's renamed, so a crash can't leave us with half a file.
+pub fn write_atomically(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = fs::File::create(&tmp)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+/// Returns the map key with the given name.
+pub fn key(name: &str) -> Value {
+    Value::Text(name.to_string())
+}
+
+/// Returns a byte string value.
+pub fn bytes(bytes: &[u8]) -> Value {
+    Value::Bytes(bytes.to_vec())
+}
+
+/// Returns the byte string under the key, or the name of the key as the
+/// error.
+pub fn get_bytes(
+    record: &BTreeMap<Value, Value>,
+    name: &'static str,
+) -> Result<Vec<u8>, &'static str> {
+    match record.get(&key(name)) {
+        Some(Value::Bytes(bytes)) => Ok(bytes.clone()),
+        _ => Err(name),
+    }
+}
+
+/// Returns the unsigned integer under the key, or the name of the key as
+/// the error.
+pub fn get_u64(
+    record: &BTreeMap<Value, Value>,
+    name: &'static str,
+) -> Result<u64, &'static str> {
+    match record.get(&key(name)) {
+        Some(Value::Integer(n)) => to_u64(*n),
+        _ => Err(name),
+    }
+}
+
+/// Converts a CBOR integer to `u64`, if it fits.
+pub fn to_u64(n: i128) -> Result<u64, &'static str> {
+    u64::try_from(n).map_err(|_| "integer out of range")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record() -> BTreeMap<Value, Value> {
+        let mut record = BTreeMap::new();
+        record.insert(key("secret"), bytes(&[1, 2, 3]));
+        record.insert(key("seq"), Value::Integer(42));
+        record.insert(key("negative"), Value::Integer(-1));
+        record
+    }
+
+    #[test]
+    fn getters() {
+        let record = record();
+        assert_eq!(get_bytes(&record, "secret"), Ok(vec![1, 2, 3]));
+        assert_eq!(get_u64(&record, "seq"), Ok(42));
+    }
+
+    #[test]
+    fn getters_of_wrong_type() {
+        let record = record();
+        assert_eq!(get_bytes(&record, "seq"), Err("seq"));
+        assert_eq!(get_u64(&record, "secret"), Err("secret"));
+        assert_eq!(get_u64(&record, "missing"), Err("missing"));
+        assert_eq!(get_u64(&record, "negative"), Err("integer out of range"));
+    }
+
+    #[test]
+    fn not_restored_past_bound() {
+        let restored = restore_context(
+            vec![0; 16],
+            vec![],
+            vec![0],
+            vec![1],
+            MAX_RESTORE_SEQ + 1,
+        );
+        assert!(matches!(restored, Ok(None)));
+    }
+
+    #[test]
+    fn write_replaces() {
+        let path = std::env::temp_dir()
+            .join(format!("persist-test-{}.cbor", std::process::id()));
+        write_atomically(&path, b"first").unwrap();
+        write_atomically(&path, b"second").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+        assert!(!path.with_extension("tmp").exists());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,563 @@
+//! Block-wise transfers (RFC 7959).
+//!
+//! Request bodies arriving in Block1 blocks are reassembled before they're
+//! handed on, and response bodies larger than a block are split up with
+//! Block2. A transfer is identified by the peer and the target of its
+//! requests, their method and options (Section 2.4). The token may change
+//! from one request to the next.
+
+use coap_lite::{CoapOption, MessageClass, MessageType, Packet, ResponseType};
+use std::net::SocketAddr;
+
+use crate::coap::generate_error;
+
+/// The largest block size we use, 2^(6+4) = 1024 bytes.
+pub const MAX_SZX: u8 = 6;
+
+/// The largest request body we're willing to reassemble.
+pub const MAX_BODY: usize = 64 * 1024;
+
+/// The maximum number of transfers in progress. When it's reached, the
+/// oldest one is dropped.
+const MAX_TRANSFERS: usize = 16;
+
+/// The options that are about the transfer rather than its target:
+/// Observe, Block2, Block1, Size2 and Size1.
+const TRANSFER_OPTIONS: [usize; 5] = [6, 23, 27, 28, 60];
+
+/// The value of a Block1 or Block2 option.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Block {
+    /// The number of the block.
+    pub num: u32,
+    /// Whether more blocks follow.
+    pub more: bool,
+    /// The size exponent, the block size is 2^(szx+4).
+    pub szx: u8,
+}
+
+impl Block {
+    /// Decodes the option value, which is an unsigned integer of up to
+    /// three bytes.
+    pub fn from_value(value: &[u8]) -> Option<Block> {
+        if value.len() > 3 {
+            return None;
+        }
+        let n = value.iter().fold(0, |n, &b| n << 8 | u32::from(b));
+        let szx = (n & 0x07) as u8;
+        // 7 is reserved for BERT, which is only for TCP
+        if szx == 7 {
+            return None;
+        }
+
+        Some(Block {
+            num: n >> 4,
+            more: n & 0x08 != 0,
+            szx,
+        })
+    }
+
+    /// Returns the block option of the given kind from the packet.
+    pub fn from_packet(packet: &Packet, option: CoapOption) -> Option<Block> {
+        packet
+            .get_option(option)
+            .and_then(|values| values.front())
+            .and_then(|value| Block::from_value(value))
+    }
+
+    /// Encodes the option value in as few bytes as possible.
+    pub fn to_value(self) -> Vec<u8> {
+        let n = self.num << 4 | (self.more as u32) << 3 | u32::from(self.szx);
+        let bytes = n.to_be_bytes();
+        let skip = bytes.iter().take_while(|&&b| b == 0).count();
+
+        bytes[skip..].to_vec()
+    }
+
+    /// Returns the block size.
+    pub fn size(self) -> usize {
+        1 << (self.szx + 4)
+    }
+
+    /// Returns the offset of the block in the body.
+    pub fn offset(self) -> usize {
+        self.num as usize * self.size()
+    }
+}
+
+/// What the requests of a transfer are about: their method and the options
+/// other than `TRANSFER_OPTIONS`.
+#[derive(PartialEq)]
+struct Target {
+    code: u8,
+    options: Vec<(usize, Vec<Vec<u8>>)>,
+}
+
+impl Target {
+    /// Returns the target of a request.
+    fn of(req: &Packet) -> Target {
+        let options = req
+            .options()
+            .filter(|(number, _)| !TRANSFER_OPTIONS.contains(number))
+            .map(|(&number, values)| {
+                (number, values.iter().cloned().collect())
+            })
+            .collect();
+
+        Target {
+            code: req.header.code.into(),
+            options,
+        }
+    }
+}
+
+/// The state of a transfer.
+struct Transfer {
+    peer: SocketAddr,
+    target: Target,
+    /// The request body received so far, or the complete response body.
+    body: Vec<u8>,
+    /// The response to reuse the header and options of for every block.
+    response: Option<Packet>,
+}
+
+/// Reassembles Block1 request bodies and splits up Block2 response bodies.
+#[derive(Default)]
+pub struct BlockHandler {
+    /// The uploads in progress, oldest first.
+    uploads: Vec<Transfer>,
+    /// The downloads in progress, oldest first.
+    downloads: Vec<Transfer>,
+}
+
+impl BlockHandler {
+    /// Creates a new `BlockHandler` without any transfers.
+    pub fn new() -> BlockHandler {
+        Default::default()
+    }
+
+    /// Processes the block options of a request.
+    ///
+    /// Returns the request with the complete body when it's ready to be
+    /// handled, or otherwise the response to send right away.
+    pub fn handle_request(
+        &mut self,
+        peer: SocketAddr,
+        req: Packet,
+    ) -> Result<Packet, Packet> {
+        // Later blocks of a response we already have
+        if let Some(block2) = Block::from_packet(&req, CoapOption::Block2) {
+            if block2.num > 0 {
+                if let Some(res) = self.next_block(peer, &req, block2) {
+                    return Err(res);
+                }
+            }
+        }
+
+        match Block::from_packet(&req, CoapOption::Block1) {
+            Some(block1) => self.receive_block(peer, req, block1),
+            None => Ok(req),
+        }
+    }
+
+    /// Prepares the response to a request handled by `handle_request`,
+    /// confirming its Block1 option and sending the first block of the body
+    /// if it's too large.
+    pub fn handle_response(
+        &mut self,
+        peer: SocketAddr,
+        req: &Packet,
+        mut res: Packet,
+    ) -> Packet {
+        if let Some(block1) = Block::from_packet(req, CoapOption::Block1) {
+            res.add_option(CoapOption::Block1, block1.to_value());
+        }
+
+        // The peer may ask for smaller blocks than ours, or for a later block
+        // if we no longer had the body
+        let block2 = match Block::from_packet(req, CoapOption::Block2) {
+            Some(block2) => Block {
+                szx: block2.szx.min(MAX_SZX),
+                ..block2
+            },
+            None => Block {
+                num: 0,
+                more: false,
+                szx: MAX_SZX,
+            },
+        };
+        if block2.num == 0 && res.payload.len() <= block2.size() {
+            return res;
+        }
+
+        // Keep the body for the following requests
+        let target = Target::of(req);
+        self.downloads
+            .retain(|t| t.peer != peer || t.target != target);
+        if self.downloads.len() >= MAX_TRANSFERS {
+            self.downloads.remove(0);
+        }
+        let body = std::mem::take(&mut res.payload);
+        self.downloads.push(Transfer {
+            peer,
+            target,
+            body,
+            response: Some(res),
+        });
+
+        self.next_block(peer, req, block2)
+            .expect("Download we just stored is gone")
+    }
+
+    /// Stores a Block1 block and returns the complete request once all of
+    /// them are there.
+    fn receive_block(
+        &mut self,
+        peer: SocketAddr,
+        req: Packet,
+        block1: Block,
+    ) -> Result<Packet, Packet> {
+        let target = Target::of(&req);
+        let index = self
+            .uploads
+            .iter()
+            .position(|t| t.peer == peer && t.target == target);
+
+        // The first block starts a new transfer, replacing any old one
+        let mut transfer = match (block1.num, index) {
+            (0, index) => {
+                if let Some(index) = index {
+                    self.uploads.remove(index);
+                }
+                Transfer {
+                    peer,
+                    target,
+                    body: Vec::new(),
+                    response: None,
+                }
+            }
+            (_, Some(index)) => self.uploads.remove(index),
+            (_, None) => {
+                return Err(generate_error(
+                    &req,
+                    ResponseType::RequestEntityIncomplete,
+                    "Missing earlier blocks",
+                ));
+            }
+        };
+        if block1.offset() != transfer.body.len() {
+            return Err(generate_error(
+                &req,
+                ResponseType::RequestEntityIncomplete,
+                "Unexpected block",
+            ));
+        }
+        if transfer.body.len() + req.payload.len() > MAX_BODY {
+            let mut res = generate_error(
+                &req,
+                ResponseType::RequestEntityTooLarge,
+                "Request body too large",
+            );
+            res.add_option(CoapOption::Size1, size_value(MAX_BODY));
+            return Err(res);
+        }
+        transfer.body.extend(&req.payload);
+
+        if !block1.more {
+            // Hand on the request with the complete body. It keeps its
+            // Block1 option, so the response can confirm it.
+            let mut req = req;
+            req.payload = transfer.body;
+            return Ok(req);
+        }
+
+        // Ask for the next block, in our size if the peer's is too large
+        let res = continue_response(
+            &req,
+            Block {
+                szx: block1.szx.min(MAX_SZX),
+                ..block1
+            },
+        );
+        if self.uploads.len() >= MAX_TRANSFERS {
+            self.uploads.remove(0);
+        }
+        self.uploads.push(transfer);
+
+        Err(res)
+    }
+
+    /// Returns the requested block of a stored response body, or `None` if
+    /// there is no such download.
+    fn next_block(
+        &mut self,
+        peer: SocketAddr,
+        req: &Packet,
+        block2: Block,
+    ) -> Option<Packet> {
+        let target = Target::of(req);
+        let index = self
+            .downloads
+            .iter()
+            .position(|t| t.peer == peer && t.target == target)?;
+        let transfer = &self.downloads[index];
+        let block = Block {
+            szx: block2.szx.min(MAX_SZX),
+            ..block2
+        };
+        let offset = block.offset();
+        if offset >= transfer.body.len() {
+            return Some(generate_error(
+                req,
+                ResponseType::BadOption,
+                "Block out of range",
+            ));
+        }
+        let end = (offset + block.size()).min(transfer.body.len());
+        let more = end < transfer.body.len();
+
+        let mut res = transfer.response.clone()?;
+        res.header.message_id = req.header.message_id;
+        res.set_token(req.get_token().clone());
+        res.payload = transfer.body[offset..end].to_vec();
+        res.clear_option(CoapOption::Block2);
+        res.add_option(CoapOption::Block2, Block { more, ..block }.to_value());
+        if block.num == 0 {
+            // Tell the peer what it's in for
+            res.add_option(CoapOption::Size2, size_value(transfer.body.len()));
+        }
+        if !more {
+            // That was the last one
+            self.downloads.remove(index);
+        }
+
+        Some(res)
+    }
+}
+
+/// Returns a 2.31 (Continue) response asking for the next Block1 block.
+fn continue_response(req: &Packet, block1: Block) -> Packet {
+    let mut res = Packet::new();
+    res.header.set_type(MessageType::Acknowledgement);
+    res.header.code = MessageClass::Response(ResponseType::Continue);
+    res.header.message_id = req.header.message_id;
+    res.set_token(req.get_token().clone());
+    res.add_option(CoapOption::Block1, block1.to_value());
+
+    res
+}
+
+/// Encodes a size for the Size1 and Size2 options.
+fn size_value(size: usize) -> Vec<u8> {
+    let bytes = (size as u32).to_be_bytes();
+    let skip = bytes.iter().take_while(|&&b| b == 0).count();
+
+    bytes[skip..].to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use coap_lite::RequestType;
+
+    fn peer() -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], 5683))
+    }
+
+    /// Returns a POST to the path with the Block1 option and payload, and
+    /// a token of its own.
+    fn upload(path: u8, num: u32, more: bool, payload: &[u8]) -> Packet {
+        let mut req = Packet::new();
+        req.header.code = MessageClass::Request(RequestType::Post);
+        req.set_token(vec![path, num as u8]);
+        req.add_option(CoapOption::UriPath, vec![b'a' + path]);
+        req.add_option(
+            CoapOption::Block1,
+            Block { num, more, szx: 0 }.to_value(),
+        );
+        req.payload = payload.to_vec();
+        req
+    }
+
+    fn code(res: &Packet) -> MessageClass {
+        res.header.code
+    }
+
+    #[test]
+    fn option_value_round_trip() {
+        let block = Block {
+            num: 1000,
+            more: true,
+            szx: 6,
+        };
+        assert_eq!(Block::from_value(&block.to_value()), Some(block));
+        assert_eq!(block.size(), 1024);
+        assert_eq!(block.offset(), 1000 * 1024);
+        // Zero is encoded as the empty value
+        assert_eq!(
+            Block {
+                num: 0,
+                more: false,
+                szx: 0
+            }
+            .to_value(),
+            Vec::<u8>::new()
+        );
+    }
+
+    #[test]
+    fn invalid_option_values() {
+        // BERT
+        assert_eq!(Block::from_value(&[0x07]), None);
+        assert_eq!(Block::from_value(&[1, 2, 3, 4]), None);
+    }
+
+    #[test]
+    fn reassembles() {
+        let mut handler = BlockHandler::new();
+        let res = handler
+            .handle_request(peer(), upload(1, 0, true, &[1; 16]))
+            .unwrap_err();
+        assert_eq!(code(&res), MessageClass::Response(ResponseType::Continue));
+        handler
+            .handle_request(peer(), upload(1, 1, true, &[2; 16]))
+            .unwrap_err();
+        let req = handler
+            .handle_request(peer(), upload(1, 2, false, &[3; 5]))
+            .unwrap();
+        assert_eq!(req.payload.len(), 37);
+        assert_eq!(&req.payload[16..32], &[2; 16]);
+        assert_eq!(&req.payload[32..], &[3; 5]);
+    }
+
+    #[test]
+    fn interleaved_uploads() {
+        let mut handler = BlockHandler::new();
+        let other = SocketAddr::from(([127, 0, 0, 1], 5684));
+        handler
+            .handle_request(peer(), upload(1, 0, true, &[1; 16]))
+            .unwrap_err();
+        handler
+            .handle_request(peer(), upload(2, 0, true, &[2; 16]))
+            .unwrap_err();
+        handler
+            .handle_request(other, upload(1, 0, true, &[3; 16]))
+            .unwrap_err();
+
+        let req = handler
+            .handle_request(peer(), upload(1, 1, false, &[1; 4]))
+            .unwrap();
+        assert_eq!(req.payload, [1; 20].to_vec());
+        let req = handler
+            .handle_request(other, upload(1, 1, false, &[3; 4]))
+            .unwrap();
+        assert_eq!(req.payload, [3; 20].to_vec());
+        let req = handler
+            .handle_request(peer(), upload(2, 1, false, &[2; 4]))
+            .unwrap();
+        assert_eq!(req.payload, [2; 20].to_vec());
+    }
+
+    #[test]
+    fn missing_earlier_blocks() {
+        let mut handler = BlockHandler::new();
+        let res = handler
+            .handle_request(peer(), upload(1, 1, true, &[1; 16]))
+            .unwrap_err();
+        assert_eq!(
+            code(&res),
+            MessageClass::Response(ResponseType::RequestEntityIncomplete)
+        );
+    }
+
+    #[test]
+    fn unexpected_block() {
+        let mut handler = BlockHandler::new();
+        handler
+            .handle_request(peer(), upload(1, 0, true, &[1; 16]))
+            .unwrap_err();
+        let res = handler
+            .handle_request(peer(), upload(1, 2, true, &[1; 16]))
+            .unwrap_err();
+        assert_eq!(
+            code(&res),
+            MessageClass::Response(ResponseType::RequestEntityIncomplete)
+        );
+    }
+
+    #[test]
+    fn body_too_large() {
+        let mut handler = BlockHandler::new();
+        let blocks = MAX_BODY / 16;
+        for num in 0..blocks {
+            handler
+                .handle_request(peer(), upload(1, num as u32, true, &[0; 16]))
+                .unwrap_err();
+        }
+        let res = handler
+            .handle_request(peer(), upload(1, blocks as u32, true, &[0; 16]))
+            .unwrap_err();
+        assert_eq!(
+            code(&res),
+            MessageClass::Response(ResponseType::RequestEntityTooLarge)
+        );
+        let size1 = res.get_option(CoapOption::Size1).unwrap();
+        assert_eq!(size1.front(), Some(&size_value(MAX_BODY)));
+    }
+
+    #[test]
+    fn drops_oldest_upload() {
+        let mut handler = BlockHandler::new();
+        for path in 0..=MAX_TRANSFERS as u8 {
+            handler
+                .handle_request(peer(), upload(path, 0, true, &[0; 16]))
+                .unwrap_err();
+        }
+        assert_eq!(handler.uploads.len(), MAX_TRANSFERS);
+        let res = handler
+            .handle_request(peer(), upload(0, 1, true, &[0; 16]))
+            .unwrap_err();
+        assert_eq!(
+            code(&res),
+            MessageClass::Response(ResponseType::RequestEntityIncomplete)
+        );
+        handler
+            .handle_request(peer(), upload(1, 1, true, &[0; 16]))
+            .unwrap_err();
+    }
+
+    #[test]
+    fn splits_response() {
+        let mut handler = BlockHandler::new();
+        let mut req = Packet::new();
+        req.set_token(vec![1]);
+        let mut res = Packet::new();
+        res.header.code = MessageClass::Response(ResponseType::Content);
+        res.payload = vec![7; 1500];
+
+        let first = handler.handle_response(peer(), &req, res);
+        assert_eq!(first.payload.len(), 1024);
+        let block2 = Block::from_packet(&first, CoapOption::Block2).unwrap();
+        assert!(block2.more);
+        let size2 = first.get_option(CoapOption::Size2).unwrap();
+        assert_eq!(size2.front(), Some(&size_value(1500)));
+
+        // The token is the request's, not the transfer's
+        req.set_token(vec![2]);
+        req.add_option(
+            CoapOption::Block2,
+            Block {
+                num: 1,
+                more: false,
+                szx: MAX_SZX,
+            }
+            .to_value(),
+        );
+        let second = handler.handle_request(peer(), req).unwrap_err();
+        assert_eq!(second.get_token(), &vec![2]);
+        assert_eq!(second.payload.len(), 1500 - 1024);
+        let block2 = Block::from_packet(&second, CoapOption::Block2).unwrap();
+        assert!(!block2.more);
+        assert!(handler.downloads.is_empty());
+    }
+}
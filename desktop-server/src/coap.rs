@@ -3,31 +3,223 @@
 use coap_lite::{
     CoapOption, ContentFormat, MessageClass, MessageType, Packet, ResponseType,
 };
+use std::{
+    collections::{BTreeMap, VecDeque},
+    net::SocketAddr,
+    sync::{Arc, Mutex, MutexGuard},
+};
+use tracing::{debug, info};
+
+use crate::{
+    block::BlockHandler,
+    resources::{Echo, Hello, Store, Uptime},
+    router::{Access, Attributes, Notification, Resource, Router},
+    stats::Stats,
+};
 
-use crate::edhoc::EdhocHandler;
+/// The number of notifications we remember, so a Reset to them can cancel
+/// the observation.
+const MAX_SENT: usize = 64;
 
 /// Handles CoAP messages.
-pub struct CoapHandler;
+///
+/// Its parts are locked separately, so requests can be handled at the same
+/// time. A request only waits for another one if they use the same part,
+/// like the resources.
+pub struct CoapHandler {
+    router: Mutex<Router>,
+    /// The transfers of requests protected with OSCORE.
+    blocks: Mutex<BlockHandler>,
+    /// The transfers of unprotected requests, kept apart so they can't get
+    /// at protected response bodies.
+    plain_blocks: Mutex<BlockHandler>,
+    sent: Mutex<Sent>,
+}
+
+/// The notifications that went out.
+struct Sent {
+    /// The Message ID of the next notification.
+    next_mid: u16,
+    /// The peer, Message ID and token of recent notifications, oldest first.
+    recent: VecDeque<(SocketAddr, u16, Vec<u8>)>,
+}
+
+/// A function handling an EDHOC message from the peer and returning the
+/// reply.
+pub type Edhoc<'a> = dyn Fn(SocketAddr, Vec<u8>) -> Option<Vec<u8>> + 'a;
 
 impl Default for CoapHandler {
     fn default() -> CoapHandler {
-        CoapHandler
+        let mut router = Router::new();
+        router.add("hello", Hello);
+        router.add("echo", Echo);
+        router.add("uptime", Uptime::new());
+        router.add("store/*", Store::new());
+
+        CoapHandler {
+            router: Mutex::new(router),
+            blocks: Default::default(),
+            plain_blocks: Default::default(),
+            sent: Mutex::new(Sent {
+                next_mid: 0,
+                recent: VecDeque::with_capacity(MAX_SENT),
+            }),
+        }
     }
 }
 
 impl CoapHandler {
-    /// Creates a new `CoapHandler`.
+    /// Creates a new `CoapHandler` serving the default resources.
     pub fn new() -> CoapHandler {
-        // Since this is an empty struct, the constructor is not necessary, but
-        // it's still here in case that changes.
         Default::default()
     }
 
-    /// Handles a CoAP message and returns a response.
-    pub fn handle(
+    /// Sets the statistics to count the requests for each resource in.
+    pub fn set_stats(&mut self, stats: Arc<Stats>) {
+        self.router_mut().set_stats(stats);
+    }
+
+    /// Registers an additional resource at the given path, which requires
+    /// OSCORE, see `Router::add`.
+    pub fn register<R>(&mut self, path: &str, resource: R)
+    where
+        R: Resource + Send + 'static,
+    {
+        self.router_mut().add(path, resource);
+    }
+
+    /// Registers an additional resource at the given path with the given
+    /// access policy.
+    pub fn register_with_access<R>(
         &mut self,
-        edhoc: &mut EdhocHandler,
+        path: &str,
+        resource: R,
+        access: Access,
+    ) where
+        R: Resource + Send + 'static,
+    {
+        self.router_mut().add_with_access(path, resource, access);
+    }
+
+    /// Changes the access policies of the resources, see
+    /// `Router::set_access_policies`.
+    pub fn set_access_policies(
+        &mut self,
+        policies: &BTreeMap<String, Access>,
+    ) -> Result<(), String> {
+        self.router_mut().set_access_policies(policies)
+    }
+
+    /// Handles a CoAP message from the given peer and returns a response.
+    ///
+    /// `protected` tells whether the message was protected with OSCORE.
+    /// Block-wise transfers are taken care of here, so the resources only
+    /// ever see complete request bodies and return complete response bodies.
+    /// Messages for /.well-known/edhoc are passed to `edhoc`.
+    pub fn handle(
+        &self,
+        edhoc: &Edhoc,
+        peer: SocketAddr,
         req: Packet,
+        protected: bool,
+    ) -> Option<Packet> {
+        // A Reset to a notification means the peer lost interest
+        if req.header.get_type() == MessageType::Reset {
+            self.reset(peer, req.header.message_id);
+            return None;
+        }
+
+        let blocks = if protected {
+            &self.blocks
+        } else {
+            &self.plain_blocks
+        };
+        let req = match lock(blocks).handle_request(peer, req) {
+            Ok(req) => req,
+            Err(res) => return Some(res),
+        };
+        let res = self.route(edhoc, peer, &req, protected)?;
+
+        Some(lock(blocks).handle_response(peer, &req, res))
+    }
+
+    /// Returns the notifications for the observers of all resources that
+    /// changed, ready to be sent.
+    ///
+    /// They're Non-confirmable, so a peer that went away without
+    /// deregistering keeps getting them until it answers with a Reset.
+    pub fn notifications(&self) -> Vec<Notification> {
+        let mut notifications = self.router().notifications();
+        let mut sent = lock(&self.sent);
+        for notification in &mut notifications {
+            let mid = sent.next_mid;
+            sent.next_mid = sent.next_mid.wrapping_add(1);
+
+            let packet = &mut notification.packet;
+            packet.header.set_type(MessageType::NonConfirmable);
+            packet.header.message_id = mid;
+            packet.set_token(notification.token.clone());
+
+            if sent.recent.len() >= MAX_SENT {
+                sent.recent.pop_front();
+            }
+            sent.recent.push_back((
+                notification.peer,
+                mid,
+                notification.token.clone(),
+            ));
+        }
+
+        notifications
+    }
+
+    /// Ends the observation with the given token, if there is one.
+    pub fn cancel(&self, peer: SocketAddr, token: &[u8]) {
+        self.router().cancel(peer, token);
+    }
+
+    /// Ends all observations of the given peer, like when its connection
+    /// closed.
+    pub fn cancel_all(&self, peer: SocketAddr) {
+        self.router().cancel_all(peer);
+    }
+
+    /// Returns `true` if there is an observation with the given token.
+    pub fn is_observing(&self, peer: SocketAddr, token: &[u8]) -> bool {
+        self.router().is_observing(peer, token)
+    }
+
+    /// Cancels the observation a Reset message refers to.
+    fn reset(&self, peer: SocketAddr, mid: u16) {
+        let token = match lock(&self.sent)
+            .recent
+            .iter()
+            .find(|(p, m, _)| *p == peer && *m == mid)
+        {
+            Some((_, _, token)) => token.clone(),
+            None => return,
+        };
+        info!(%peer, "Observation cancelled with a Reset");
+        self.router().cancel(peer, &token);
+    }
+
+    /// Returns the router, locked.
+    fn router(&self) -> MutexGuard<'_, Router> {
+        lock(&self.router)
+    }
+
+    /// Returns the router, for changing it while nobody else can.
+    fn router_mut(&mut self) -> &mut Router {
+        self.router.get_mut().expect("Router poisoned")
+    }
+
+    /// Passes a complete request to the resource it's for.
+    fn route(
+        &self,
+        edhoc: &Edhoc,
+        peer: SocketAddr,
+        req: &Packet,
+        protected: bool,
     ) -> Option<Packet> {
         if let Some(path) = req.get_option(CoapOption::UriPath) {
             // Copy the linked list of references so we can manipulate it for
@@ -38,19 +230,17 @@ impl CoapHandler {
                 if first == b".well-known" {
                     if let Some(second) = path.pop_front() {
                         if second == b"core" {
-                            println!(
+                            debug!(
                                 "Request for the /.well-known/core resource"
                             );
-                            // Response to /.well-known/core
+                            // Response to /.well-known/core, describing all
+                            // registered resources and EDHOC
                             return Some(generate_link_format(
-                                &req,
-                                // About EDHOC: should have a custom ct, but
-                                // since the EDHOC Content-Format is not part
-                                // of the IANA registry yet, we don't know it
-                                b"</hello>;rt=\"test\";ct=0,\
-                                  </echo>;rt=\"echo\";ct=42,\
-                                  </.well-known/edhoc>;rt=\"edhoc\";ct=42"
-                                    .to_vec(),
+                                req,
+                                self.router().link_format(&[(
+                                    "/.well-known/edhoc",
+                                    edhoc_attributes(),
+                                )]),
                             ));
                         } else if second == b"edhoc" {
                             // Response to /.well-known/edhoc
@@ -58,7 +248,7 @@ impl CoapHandler {
                             // Duplicate the token for later use
                             let token = req.get_token().clone();
                             // Get our response from the EDHOC handler
-                            let payload = edhoc.handle(req.payload);
+                            let payload = edhoc(peer, req.payload.clone());
                             // Do an early return with None if we got that
                             let payload = payload?;
 
@@ -85,41 +275,37 @@ impl CoapHandler {
 
                     // Response to /.well-known
                     return Some(generate_link_format(
-                        &req,
+                        req,
                         br#"</.well-known/core>;rt="core";ct=40"#.to_vec(),
                     ));
-                } else if first == b"hello" {
-                    println!("Request for the /hello resource");
-                    // Response to /hello
-                    return Some(generate_response(
-                        &req,
-                        b"Hello, world!".to_vec(),
-                        ContentFormat::TextPlain,
-                    ));
-                } else if first == b"echo" {
-                    println!("Request for the /echo resource");
-                    // Response to /echo
-                    let payload = req.payload.clone();
-                    return Some(generate_response(
-                        &req,
-                        payload,
-                        ContentFormat::ApplicationOctetStream,
-                    ));
                 }
             }
         }
 
+        // Everything else is up to the registered resources
+        if let Some(res) = self.router().dispatch(req, peer, protected) {
+            return Some(res);
+        }
+
         // If we made it here, the requested resource was not found
-        println!("Requested resource was not found");
-        let mut res = Packet::new();
-        res.header.set_type(MessageType::Acknowledgement);
-        res.header.code = MessageClass::Response(ResponseType::NotFound);
-        res.header.message_id = req.header.message_id;
-        res.set_token(req.get_token().clone());
-        res.set_content_format(ContentFormat::TextPlain);
-        res.payload = b"Not found".to_vec();
+        debug!("Requested resource was not found");
+        Some(generate_error(req, ResponseType::NotFound, "Not found"))
+    }
+}
+
+/// Locks one of the parts of `CoapHandler`.
+fn lock<T>(part: &Mutex<T>) -> MutexGuard<'_, T> {
+    part.lock().expect("CoAP handler poisoned")
+}
 
-        Some(res)
+/// Returns the attributes of the EDHOC resource.
+fn edhoc_attributes() -> Attributes {
+    // Should have a custom ct, but since the EDHOC Content-Format is not part
+    // of the IANA registry yet, we don't know it
+    Attributes {
+        rt: Some("edhoc"),
+        ct: Some(42),
+        ..Default::default()
     }
 }
 
@@ -137,7 +323,7 @@ fn generate_link_format(req: &Packet, payload: Vec<u8>) -> Packet {
 }
 
 /// Returns a "normal" response with a payload and Content-Format.
-fn generate_response(
+pub fn generate_response(
     req: &Packet,
     payload: Vec<u8>,
     cf: ContentFormat,
@@ -152,3 +338,31 @@ fn generate_response(
 
     res
 }
+
+/// Returns a response with just the code, like 2.04 (Changed).
+pub fn generate_status(req: &Packet, code: ResponseType) -> Packet {
+    let mut res = Packet::new();
+    res.header.set_type(MessageType::Acknowledgement);
+    res.header.code = MessageClass::Response(code);
+    res.header.message_id = req.header.message_id;
+    res.set_token(req.get_token().clone());
+
+    res
+}
+
+/// Returns an error response with a diagnostic payload.
+pub fn generate_error(
+    req: &Packet,
+    code: ResponseType,
+    diagnostic: &str,
+) -> Packet {
+    let mut res = Packet::new();
+    res.header.set_type(MessageType::Acknowledgement);
+    res.header.code = MessageClass::Response(code);
+    res.header.message_id = req.header.message_id;
+    res.set_token(req.get_token().clone());
+    res.set_content_format(ContentFormat::TextPlain);
+    res.payload = diagnostic.as_bytes().to_vec();
+
+    res
+}
@@ -0,0 +1,438 @@
+//! The TOML configuration file of the server.
+//!
+//! Every section and setting is optional, what's missing keeps its default.
+//! A complete example:
+//!
+//! ```toml
+//! [server]
+//! bind = ["coap://0.0.0.0:5683", "coap+tcp://0.0.0.0:5683"]
+//! async = true
+//! state = "contexts.cbor"
+//! stats_file = "/var/lib/node_exporter/oscore.prom"
+//!
+//! [identity]
+//! key = "server.key"
+//! kid = "a3"
+//!
+//! [[peer]]
+//! key = "client.pub"
+//! kid = "a2"
+//! # Default to our and the peer's kid
+//! sender_id = "a3"
+//! recipient_id = "a2"
+//!
+//! [resources]
+//! hello = "public"
+//! uptime = "disabled"
+//!
+//! [limits]
+//! handshake_timeout = 30
+//! max_pending = 32
+//! max_seq = 1000000
+//! lifetime = 86400
+//! grace = 60
+//!
+//! [log]
+//! filter = "info,desktop_server::edhoc=debug"
+//! format = "json"
+//! show_secrets = false
+//! ```
+//!
+//! Relative paths are relative to the directory of the configuration file.
+//! On SIGHUP, the peers, resources and limits are reloaded, the rest only
+//! takes effect on restart.
+
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    fmt, fs, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::{
+    context::{RekeyPolicy, MAX_PIV},
+    edhoc::MAX_PENDING,
+    logging::Format,
+    router::Access,
+};
+
+/// The settings of the server.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub identity: IdentityConfig,
+    /// The peers we authenticate, the demo peer is used if there are none.
+    #[serde(rename = "peer")]
+    pub peers: Vec<PeerConfig>,
+    /// The access policies of the resources, by path.
+    pub resources: BTreeMap<String, Policy>,
+    pub limits: LimitsConfig,
+    pub log: LogConfig,
+}
+
+/// Where and how requests are served.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// The addresses to bind to, prefixed with `coap+tcp://` for TCP.
+    pub bind: Vec<String>,
+    /// Whether to use the asynchronous event loop.
+    #[serde(rename = "async")]
+    pub asynchronous: bool,
+    /// The file to persist security contexts in.
+    pub state: Option<PathBuf>,
+    /// The file to write statistics to for Prometheus.
+    pub stats_file: Option<PathBuf>,
+    /// The seed for reproducible test runs.
+    pub seed: Option<u64>,
+}
+
+/// Our own authentication key.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IdentityConfig {
+    /// The key file with our private key, the demo key is used without it.
+    pub key: Option<PathBuf>,
+    /// Our key ID as hex, if it's not in the key file.
+    pub kid: Option<String>,
+}
+
+/// A peer we authenticate, with the OSCORE IDs to use with it.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PeerConfig {
+    /// The key file with the peer's public key.
+    pub key: PathBuf,
+    /// The peer's key ID as hex, if it's not in the key file.
+    pub kid: Option<String>,
+    /// Our sender ID as hex, our kid by default.
+    pub sender_id: Option<String>,
+    /// Our recipient ID as hex, the peer's kid by default.
+    pub recipient_id: Option<String>,
+}
+
+/// Who may access a resource, as it's written in the file.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Policy {
+    Oscore,
+    Public,
+    Disabled,
+}
+
+impl From<Policy> for Access {
+    fn from(policy: Policy) -> Access {
+        match policy {
+            Policy::Oscore => Access::Oscore,
+            Policy::Public => Access::Public,
+            Policy::Disabled => Access::Disabled,
+        }
+    }
+}
+
+/// Limits on handshakes and security contexts, with durations in seconds.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// How long to wait for message_3 of a handshake.
+    pub handshake_timeout: Option<u64>,
+    /// How many handshakes may wait for message_3 at the same time.
+    pub max_pending: Option<usize>,
+    /// The sequence number at which a security context expires.
+    pub max_seq: Option<u64>,
+    /// How long a security context may be used.
+    pub lifetime: Option<u64>,
+    /// How long a replaced security context is still accepted.
+    pub grace: Option<u64>,
+}
+
+/// What gets logged and how.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// Filter directives like `info,desktop_server::edhoc=debug`.
+    pub filter: Option<String>,
+    /// Either `text` or `json`.
+    pub format: Option<String>,
+    /// Whether to log key material instead of redacting it.
+    pub show_secrets: bool,
+}
+
+/// The ways in which loading the configuration can fail.
+#[derive(Debug)]
+pub enum Error {
+    /// The file couldn't be read.
+    Io(io::Error),
+    /// The file is not valid TOML or has unknown or mistyped settings.
+    Parse(toml::de::Error),
+    /// A setting has a value we can't use.
+    Invalid {
+        setting: &'static str,
+        reason: String,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "unable to read config file: {}", e),
+            Error::Parse(e) => write!(f, "invalid config file: {}", e),
+            Error::Invalid { setting, reason } => {
+                write!(f, "{}: {}", setting, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<toml::de::Error> for Error {
+    fn from(e: toml::de::Error) -> Error {
+        Error::Parse(e)
+    }
+}
+
+/// Shorthand for an `Error::Invalid`.
+fn invalid(setting: &'static str, reason: String) -> Error {
+    Error::Invalid { setting, reason }
+}
+
+impl Config {
+    /// Reads the configuration file.
+    ///
+    /// It's not validated yet, since settings from elsewhere (like the
+    /// command line) may still change it.
+    pub fn load(path: &Path) -> Result<Config, Error> {
+        let text = fs::read_to_string(path)?;
+        let mut config: Config = toml::from_str(&text)?;
+        if let Some(dir) = path.parent() {
+            config.resolve_paths(dir);
+        }
+
+        Ok(config)
+    }
+
+    /// Checks every setting, so mistakes are found before anything is
+    /// applied.
+    pub fn validate(&self) -> Result<(), Error> {
+        self.bind_addrs()?;
+        self.rekey_policy()?;
+        self.log_format()?;
+        check_hex("identity.kid", self.identity.kid.as_deref())?;
+        for peer in &self.peers {
+            check_hex("peer.kid", peer.kid.as_deref())?;
+            check_hex("peer.sender_id", peer.sender_id.as_deref())?;
+            check_hex("peer.recipient_id", peer.recipient_id.as_deref())?;
+        }
+        let max_pending = self.limits.max_pending.unwrap_or(1);
+        if !(1..=MAX_PENDING).contains(&max_pending) {
+            return Err(invalid(
+                "limits.max_pending",
+                format!("has to be between 1 and {}", MAX_PENDING),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Returns the UDP and TCP addresses to bind to.
+    pub fn bind_addrs(
+        &self,
+    ) -> Result<(Vec<SocketAddr>, Vec<SocketAddr>), Error> {
+        let mut addrs = Vec::new();
+        let mut tcp_addrs = Vec::new();
+        for bind in &self.server.bind {
+            let (addr, tcp) = match bind.strip_prefix("coap+tcp://") {
+                Some(addr) => (addr, true),
+                None => (bind.trim_start_matches("coap://"), false),
+            };
+            let addr = addr.parse::<SocketAddr>().map_err(|_| {
+                invalid("server.bind", format!("invalid address {}", bind))
+            })?;
+            if tcp {
+                tcp_addrs.push(addr);
+            } else {
+                addrs.push(addr);
+            }
+        }
+        // Only the asynchronous mode can serve several sockets or TCP
+        if !self.server.asynchronous {
+            if !tcp_addrs.is_empty() {
+                return Err(invalid(
+                    "server.bind",
+                    "need async to accept TCP connections".to_string(),
+                ));
+            }
+            if addrs.len() > 1 {
+                return Err(invalid(
+                    "server.bind",
+                    "need async to bind to more than one address".to_string(),
+                ));
+            }
+        }
+
+        Ok((addrs, tcp_addrs))
+    }
+
+    /// Returns the re-keying policy, with the defaults for what isn't set.
+    pub fn rekey_policy(&self) -> Result<RekeyPolicy, Error> {
+        let mut policy = RekeyPolicy::default();
+        if let Some(max_seq) = self.limits.max_seq {
+            if max_seq > MAX_PIV {
+                return Err(invalid(
+                    "limits.max_seq",
+                    format!("can't be more than {}", MAX_PIV),
+                ));
+            }
+            policy.max_seq = max_seq;
+        }
+        policy.lifetime = self.limits.lifetime.map(Duration::from_secs);
+        if let Some(grace) = self.limits.grace {
+            policy.grace = Duration::from_secs(grace);
+        }
+
+        Ok(policy)
+    }
+
+    /// Returns the handshake timeout, if it's set.
+    pub fn handshake_timeout(&self) -> Option<Duration> {
+        self.limits.handshake_timeout.map(Duration::from_secs)
+    }
+
+    /// Returns the format of the log output, text by default.
+    pub fn log_format(&self) -> Result<Format, Error> {
+        match &self.log.format {
+            Some(format) => {
+                format.parse().map_err(|e| invalid("log.format", e))
+            }
+            None => Ok(Format::Text),
+        }
+    }
+
+    /// Returns the access policies of the resources.
+    pub fn access_policies(&self) -> BTreeMap<String, Access> {
+        self.resources
+            .iter()
+            .map(|(path, &policy)| (path.clone(), policy.into()))
+            .collect()
+    }
+
+    /// Makes the relative paths relative to the directory instead of the
+    /// working directory.
+    fn resolve_paths(&mut self, dir: &Path) {
+        let resolve = |path: &mut PathBuf| *path = dir.join(&*path);
+        if let Some(state) = self.server.state.as_mut() {
+            resolve(state);
+        }
+        if let Some(stats_file) = self.server.stats_file.as_mut() {
+            resolve(stats_file);
+        }
+        if let Some(key) = self.identity.key.as_mut() {
+            resolve(key);
+        }
+        for peer in &mut self.peers {
+            resolve(&mut peer.key);
+        }
+    }
+}
+
+/// Checks that the setting is valid hex, if it's there.
+fn check_hex(setting: &'static str, value: Option<&str>) -> Result<(), Error> {
+    match value {
+        Some(value) if hex::decode(value).is_err() => {
+            Err(invalid(setting, format!("{} is not valid hex", value)))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Config {
+        toml::from_str(text).unwrap()
+    }
+
+    fn invalid_setting(config: &Config) -> &'static str {
+        match config.validate() {
+            Err(Error::Invalid { setting, .. }) => setting,
+            other => panic!("expected an invalid setting, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn empty_is_valid() {
+        let config = parse("");
+        assert!(config.validate().is_ok());
+        assert_eq!(config.log_format().unwrap(), Format::Text);
+        assert_eq!(config.handshake_timeout(), None);
+    }
+
+    #[test]
+    fn rejects_unknown_settings() {
+        assert!(toml::from_str::<Config>("[server]\nport = 5683").is_err());
+    }
+
+    #[test]
+    fn bind_needs_async() {
+        let mut config = parse(
+            r#"
+            [server]
+            bind = ["coap://127.0.0.1:5683", "coap+tcp://127.0.0.1:5683"]
+            "#,
+        );
+        assert_eq!(invalid_setting(&config), "server.bind");
+        config.server.asynchronous = true;
+        let (addrs, tcp_addrs) = config.bind_addrs().unwrap();
+        assert_eq!(addrs, ["127.0.0.1:5683".parse().unwrap()]);
+        assert_eq!(tcp_addrs, ["127.0.0.1:5683".parse().unwrap()]);
+    }
+
+    #[test]
+    fn invalid_values() {
+        let config = parse("[server]\nbind = [\"localhost\"]");
+        assert_eq!(invalid_setting(&config), "server.bind");
+        let config = parse("[identity]\nkid = \"xy\"");
+        assert_eq!(invalid_setting(&config), "identity.kid");
+        let config = parse("[[peer]]\nkey = \"a.pub\"\nsender_id = \"a\"");
+        assert_eq!(invalid_setting(&config), "peer.sender_id");
+        let config = parse("[limits]\nmax_pending = 0");
+        assert_eq!(invalid_setting(&config), "limits.max_pending");
+        let config = parse("[limits]\nmax_pending = 256");
+        assert_eq!(invalid_setting(&config), "limits.max_pending");
+        let config = parse(&format!("[limits]\nmax_seq = {}", MAX_PIV + 1));
+        assert_eq!(invalid_setting(&config), "limits.max_seq");
+        let config = parse("[log]\nformat = \"xml\"");
+        assert_eq!(invalid_setting(&config), "log.format");
+    }
+
+    #[test]
+    fn load_leaves_validation() {
+        let dir = std::env::temp_dir()
+            .join(format!("config-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("server.toml");
+        fs::write(
+            &path,
+            "[server]\nbind = [\"coap+tcp://127.0.0.1:5683\"]\n\
+             state = \"contexts.cbor\"",
+        )
+        .unwrap();
+        let config = Config::load(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        // Without async, but that may still come from the command line
+        assert!(config.validate().is_err());
+        assert_eq!(config.server.state, Some(dir.join("contexts.cbor")));
+    }
+}
@@ -0,0 +1,544 @@
+//! Storage of the OSCORE security contexts established with our peers.
+
+use desktop_common::persist;
+use oscore::oscore::{Error, SecurityContext};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// The number of requests older than the newest one we still accept, as
+/// long as they haven't been received yet.
+const REPLAY_WINDOW: u64 = 32;
+
+/// The largest Partial IV OSCORE can encode, which is 5 bytes long.
+pub const MAX_PIV: u64 = (1 << 40) - 1;
+
+/// When security contexts have to be replaced with fresh ones from a new
+/// EDHOC exchange.
+///
+/// Since only the client can start EDHOC, we refuse requests with a context
+/// once it's expired. A client with the same policy re-keys just before.
+#[derive(Clone, Copy, Debug)]
+pub struct RekeyPolicy {
+    /// The sequence number at which a context expires, ours or the peer's.
+    /// Ours never goes past `persist::MAX_SEQ`, so the context can still be
+    /// restored.
+    pub max_seq: u64,
+    /// How long a context may be used, if there is a limit.
+    pub lifetime: Option<Duration>,
+    /// How long a replaced context is still accepted, so requests that were
+    /// already protected with it can be answered.
+    pub grace: Duration,
+}
+
+impl Default for RekeyPolicy {
+    fn default() -> RekeyPolicy {
+        RekeyPolicy {
+            max_seq: MAX_PIV,
+            lifetime: None,
+            grace: Duration::from_secs(60),
+        }
+    }
+}
+
+/// The OSCORE security context shared with a single peer.
+///
+/// Since `SecurityContext` doesn't expose its sequence number or replay
+/// window, they're mirrored here so they can be persisted.
+pub struct PeerContext {
+    master_secret: Vec<u8>,
+    master_salt: Vec<u8>,
+    sender_id: Vec<u8>,
+    recipient_id: Vec<u8>,
+    context: SecurityContext,
+    /// The sequence number the next fresh Partial IV will have.
+    sender_seq: u64,
+    replay_window: ReplayWindow,
+    /// When the context was established.
+    created: SystemTime,
+    /// Whether the context was restored too far along to protect anything
+    /// with a fresh Partial IV, so the peer has to do EDHOC again.
+    exhausted: bool,
+}
+
+impl PeerContext {
+    /// Creates a new `PeerContext` from the parameters negotiated with EDHOC.
+    pub fn new(
+        master_secret: Vec<u8>,
+        master_salt: Vec<u8>,
+        sender_id: Vec<u8>,
+        recipient_id: Vec<u8>,
+    ) -> Result<PeerContext, Error> {
+        let context = SecurityContext::new(
+            master_secret.clone(),
+            master_salt.clone(),
+            sender_id.clone(),
+            recipient_id.clone(),
+        )?;
+
+        Ok(PeerContext {
+            master_secret,
+            master_salt,
+            sender_id,
+            recipient_id,
+            context,
+            sender_seq: 0,
+            replay_window: ReplayWindow::default(),
+            created: SystemTime::now(),
+            exhausted: false,
+        })
+    }
+
+    /// Recreates a `PeerContext` that was persisted, continuing with the
+    /// given sender sequence number and replay window.
+    ///
+    /// If the sequence number is too far along to restore, the context is
+    /// expired from the start. It's kept rather than dropped, so the peer
+    /// learns that it has to re-key.
+    pub fn restore(
+        master_secret: Vec<u8>,
+        master_salt: Vec<u8>,
+        sender_id: Vec<u8>,
+        recipient_id: Vec<u8>,
+        sender_seq: u64,
+        replay_window: ReplayWindow,
+        created: SystemTime,
+    ) -> Result<PeerContext, Error> {
+        let restored = persist::restore_context(
+            master_secret.clone(),
+            master_salt.clone(),
+            sender_id.clone(),
+            recipient_id.clone(),
+            sender_seq,
+        )?;
+        let exhausted = restored.is_none();
+        // Starting over at zero is fine, since an expired context is never
+        // used for a fresh Partial IV
+        let context = match restored {
+            Some(context) => context,
+            None => SecurityContext::new(
+                master_secret.clone(),
+                master_salt.clone(),
+                sender_id.clone(),
+                recipient_id.clone(),
+            )?,
+        };
+
+        Ok(PeerContext {
+            master_secret,
+            master_salt,
+            sender_id,
+            recipient_id,
+            context,
+            sender_seq,
+            replay_window,
+            created,
+            exhausted,
+        })
+    }
+
+    /// Returns the master secret of this context.
+    pub fn master_secret(&self) -> &[u8] {
+        &self.master_secret
+    }
+
+    /// Returns the master salt of this context.
+    pub fn master_salt(&self) -> &[u8] {
+        &self.master_salt
+    }
+
+    /// Returns our sender ID in this context.
+    pub fn sender_id(&self) -> &[u8] {
+        &self.sender_id
+    }
+
+    /// Returns our recipient ID in this context, which is the ID the peer
+    /// uses as its kid.
+    pub fn recipient_id(&self) -> &[u8] {
+        &self.recipient_id
+    }
+
+    /// Returns the sequence number the next fresh Partial IV will have.
+    pub fn sender_seq(&self) -> u64 {
+        self.sender_seq
+    }
+
+    /// Returns the replay window for the peer's requests.
+    pub fn replay_window(&self) -> &ReplayWindow {
+        &self.replay_window
+    }
+
+    /// Returns when the context was established.
+    pub fn created(&self) -> SystemTime {
+        self.created
+    }
+
+    /// Returns `true` if the policy no longer allows using the context,
+    /// because either side ran out of sequence numbers or it's too old.
+    pub fn is_expired(&self, policy: &RekeyPolicy) -> bool {
+        if self.exhausted {
+            return true;
+        }
+        let peer_seq = self.replay_window.highest().unwrap_or(0);
+        // A clock that went backwards doesn't make the context older
+        let too_old = match (policy.lifetime, self.created.elapsed()) {
+            (Some(lifetime), Ok(age)) => age >= lifetime,
+            _ => false,
+        };
+
+        self.sender_seq >= policy.max_seq.min(persist::MAX_SEQ)
+            || peer_seq >= policy.max_seq
+            || too_old
+    }
+
+    /// Unprotects a request with the given Partial IV, rejecting it if it
+    /// was already received.
+    pub fn unprotect_request(
+        &mut self,
+        req: &[u8],
+        piv: u64,
+    ) -> Result<Vec<u8>, Error> {
+        if !self.replay_window.is_fresh(piv) {
+            return Err(Error::ReplayDetected);
+        }
+        let unprotected = self.context.unprotect_request(req)?;
+        // Only remember it once we know it's authentic
+        self.replay_window.mark(piv);
+
+        Ok(unprotected)
+    }
+
+    /// Protects a response to the request, using a fresh Partial IV unless
+    /// `reuse_piv` is set.
+    pub fn protect_response(
+        &mut self,
+        res: &[u8],
+        req: &[u8],
+        reuse_piv: bool,
+    ) -> Result<Vec<u8>, Error> {
+        assert!(
+            reuse_piv || !self.exhausted,
+            "Fresh Partial IV from an exhausted context"
+        );
+        let protected = self.context.protect_response(res, req, reuse_piv)?;
+        if !reuse_piv {
+            self.sender_seq += 1;
+        }
+
+        Ok(protected)
+    }
+}
+
+/// Keeps track of the Partial IVs of the requests received from a peer.
+#[derive(Default, Clone, Copy, PartialEq)]
+pub struct ReplayWindow {
+    /// The highest Partial IV received so far.
+    highest: Option<u64>,
+    /// Bit `i` is set if `highest - 1 - i` was received.
+    bitmap: u32,
+}
+
+impl ReplayWindow {
+    /// Creates a `ReplayWindow` from its persisted parts.
+    pub fn from_parts(highest: Option<u64>, bitmap: u32) -> ReplayWindow {
+        ReplayWindow { highest, bitmap }
+    }
+
+    /// Returns the highest Partial IV received so far.
+    pub fn highest(&self) -> Option<u64> {
+        self.highest
+    }
+
+    /// Returns the bitmap of the Partial IVs received below the highest.
+    pub fn bitmap(&self) -> u32 {
+        self.bitmap
+    }
+
+    /// Returns `true` if a request with this Partial IV is acceptable.
+    pub fn is_fresh(&self, piv: u64) -> bool {
+        let highest = match self.highest {
+            Some(highest) => highest,
+            None => return true,
+        };
+        if piv > highest {
+            return true;
+        }
+        let age = highest - piv;
+        // Anything older than the window is rejected, since we can't know
+        age != 0 && age <= REPLAY_WINDOW && self.bitmap & 1 << (age - 1) == 0
+    }
+
+    /// Records that a request with this Partial IV was received.
+    pub fn mark(&mut self, piv: u64) {
+        match self.highest {
+            Some(highest) if piv <= highest => {
+                let age = highest - piv;
+                if age != 0 && age <= REPLAY_WINDOW {
+                    self.bitmap |= 1 << (age - 1);
+                }
+            }
+            Some(highest) => {
+                // Slide the window, the previous highest becomes a bit too
+                let shift = piv - highest;
+                self.bitmap = if shift > REPLAY_WINDOW {
+                    0
+                } else {
+                    ((u64::from(self.bitmap) << shift) | 1 << (shift - 1))
+                        as u32
+                };
+                self.highest = Some(piv);
+            }
+            None => self.highest = Some(piv),
+        }
+    }
+}
+
+/// Holds the security contexts of all peers, keyed by recipient ID.
+#[derive(Default)]
+pub struct ContextTable {
+    contexts: HashMap<Vec<u8>, PeerContext>,
+    /// Contexts that were replaced, with the time until which they're still
+    /// accepted.
+    retired: Vec<(PeerContext, Instant)>,
+}
+
+impl ContextTable {
+    /// Creates a new, empty `ContextTable`.
+    pub fn new() -> ContextTable {
+        Default::default()
+    }
+
+    /// Adds a context, returning the one it replaced for the same recipient
+    /// ID if there was any.
+    pub fn insert(&mut self, context: PeerContext) -> Option<PeerContext> {
+        self.contexts.insert(context.recipient_id.clone(), context)
+    }
+
+    /// Returns the context for the given recipient ID.
+    pub fn get(&self, recipient_id: &[u8]) -> Option<&PeerContext> {
+        self.contexts.get(recipient_id)
+    }
+
+    /// Returns the context for the given recipient ID mutably.
+    pub fn get_mut(
+        &mut self,
+        recipient_id: &[u8],
+    ) -> Option<&mut PeerContext> {
+        self.contexts.get_mut(recipient_id)
+    }
+
+    /// Evicts the context for the given recipient ID and returns it.
+    pub fn remove(&mut self, recipient_id: &[u8]) -> Option<PeerContext> {
+        self.contexts.remove(recipient_id)
+    }
+
+    /// Keeps a replaced context around until the deadline, so requests
+    /// protected with it can still be answered. Any older context for the
+    /// same recipient ID is dropped.
+    pub fn retire(&mut self, context: PeerContext, until: Instant) {
+        self.retired
+            .retain(|(c, _)| c.recipient_id != context.recipient_id);
+        self.retired.push((context, until));
+    }
+
+    /// Returns the retired context for the given recipient ID mutably, if
+    /// it's still within its grace period.
+    pub fn get_retired_mut(
+        &mut self,
+        recipient_id: &[u8],
+    ) -> Option<&mut PeerContext> {
+        let now = Instant::now();
+        self.retired
+            .iter_mut()
+            .find(|(c, until)| c.recipient_id == recipient_id && *until > now)
+            .map(|(c, _)| c)
+    }
+
+    /// Keeps only the contexts, retired ones included, whose recipient ID
+    /// the predicate accepts.
+    pub fn retain<F>(&mut self, mut keep: F)
+    where
+        F: FnMut(&[u8]) -> bool,
+    {
+        self.contexts.retain(|id, _| keep(id));
+        self.retired.retain(|(c, _)| keep(&c.recipient_id));
+    }
+
+    /// Drops the retired contexts whose grace period is over.
+    pub fn expire_retired(&mut self) {
+        let now = Instant::now();
+        self.retired.retain(|(_, until)| *until > now);
+    }
+
+    /// Returns an iterator over all stored contexts.
+    pub fn iter(&self) -> impl Iterator<Item = &PeerContext> {
+        self.contexts.values()
+    }
+
+    /// Returns an iterator over the recipient IDs of all stored contexts.
+    pub fn recipient_ids(&self) -> impl Iterator<Item = &[u8]> {
+        self.contexts.keys().map(|id| &id[..])
+    }
+
+    /// Returns the number of stored contexts.
+    pub fn len(&self) -> usize {
+        self.contexts.len()
+    }
+
+    /// Returns `true` if there are no stored contexts.
+    pub fn is_empty(&self) -> bool {
+        self.contexts.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context(recipient_id: u8) -> PeerContext {
+        PeerContext::new(vec![0; 16], vec![], vec![0], vec![recipient_id])
+            .unwrap()
+    }
+
+    /// Returns a context at the sequence numbers, created at the time.
+    fn restored(
+        sender_seq: u64,
+        peer_seq: Option<u64>,
+        created: SystemTime,
+    ) -> PeerContext {
+        PeerContext::restore(
+            vec![0; 16],
+            vec![],
+            vec![0],
+            vec![1],
+            sender_seq,
+            ReplayWindow::from_parts(peer_seq, 0),
+            created,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn expires_at_max_seq() {
+        let policy = RekeyPolicy {
+            max_seq: 10,
+            ..Default::default()
+        };
+        let now = SystemTime::now();
+        assert!(!restored(9, Some(9), now).is_expired(&policy));
+        // Either side running out is enough
+        assert!(restored(10, Some(9), now).is_expired(&policy));
+        assert!(restored(9, Some(10), now).is_expired(&policy));
+    }
+
+    #[test]
+    fn expires_after_lifetime() {
+        let policy = RekeyPolicy {
+            lifetime: Some(Duration::from_secs(60)),
+            ..Default::default()
+        };
+        let now = SystemTime::now();
+        let created = |secs| now - Duration::from_secs(secs);
+        assert!(!restored(0, None, created(50)).is_expired(&policy));
+        assert!(restored(0, None, created(70)).is_expired(&policy));
+        // A clock that went backwards doesn't make it older
+        let future = now + Duration::from_secs(3600);
+        assert!(!restored(0, None, future).is_expired(&policy));
+    }
+
+    #[test]
+    fn retired_until_grace_ends() {
+        let mut table = ContextTable::new();
+        table.retire(context(1), Instant::now() + Duration::from_secs(60));
+        table.retire(context(2), Instant::now());
+        table.expire_retired();
+        assert!(table.get_retired_mut(&[1]).is_some());
+        assert!(table.get_retired_mut(&[2]).is_none());
+    }
+
+    #[test]
+    fn expires_while_restorable() {
+        assert!(!context(1).is_expired(&RekeyPolicy::default()));
+        let restored = restored(persist::MAX_SEQ, None, SystemTime::now());
+        assert!(restored.is_expired(&RekeyPolicy::default()));
+    }
+
+    #[test]
+    fn retains_current_and_retired() {
+        let mut table = ContextTable::new();
+        table.insert(context(1));
+        table.insert(context(2));
+        let until = Instant::now() + Duration::from_secs(60);
+        table.retire(context(1), until);
+        table.retire(context(2), until);
+
+        table.retain(|id| id == [2]);
+        assert!(table.get(&[1]).is_none());
+        assert!(table.get_retired_mut(&[1]).is_none());
+        assert!(table.get(&[2]).is_some());
+        assert!(table.get_retired_mut(&[2]).is_some());
+    }
+
+    #[test]
+    fn empty_window_accepts_anything() {
+        let window = ReplayWindow::default();
+        assert!(window.is_fresh(0));
+        assert!(window.is_fresh(1000));
+    }
+
+    #[test]
+    fn rejects_replays() {
+        let mut window = ReplayWindow::default();
+        window.mark(5);
+        assert!(!window.is_fresh(5));
+        assert!(window.is_fresh(6));
+        window.mark(3);
+        assert!(!window.is_fresh(3));
+        assert!(window.is_fresh(4));
+    }
+
+    #[test]
+    fn slides() {
+        let mut window = ReplayWindow::default();
+        window.mark(10);
+        window.mark(12);
+        assert_eq!(window.highest(), Some(12));
+        assert_eq!(window.bitmap(), 0b10);
+        assert!(!window.is_fresh(10));
+        assert!(window.is_fresh(11));
+        window.mark(11);
+        assert_eq!(window.bitmap(), 0b11);
+    }
+
+    #[test]
+    fn rejects_older_than_window() {
+        let mut window = ReplayWindow::default();
+        window.mark(100);
+        assert!(window.is_fresh(100 - REPLAY_WINDOW));
+        assert!(!window.is_fresh(100 - REPLAY_WINDOW - 1));
+    }
+
+    #[test]
+    fn forgets_after_jump() {
+        let mut window = ReplayWindow::default();
+        window.mark(1);
+        window.mark(2);
+        window.mark(2 + REPLAY_WINDOW + 1);
+        assert_eq!(window.bitmap(), 0);
+        assert!(!window.is_fresh(2));
+        assert!(window.is_fresh(2 + REPLAY_WINDOW));
+    }
+
+    #[test]
+    fn from_parts_round_trip() {
+        let mut window = ReplayWindow::default();
+        window.mark(7);
+        window.mark(9);
+        let restored =
+            ReplayWindow::from_parts(window.highest(), window.bitmap());
+        assert!(restored == window);
+        assert!(!restored.is_fresh(7));
+        assert!(restored.is_fresh(8));
+    }
+}
@@ -0,0 +1,231 @@
+//! Detection of duplicate Confirmable messages.
+//!
+//! A client retransmits a Confirmable message until it gets a response, so
+//! if the response is lost, we see the same message again. Instead of
+//! handling it a second time (which OSCORE would reject as a replay), we
+//! send the response we already built, as RFC 7252 Section 4.5 asks.
+
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+/// How long a client may retransmit a message (RFC 7252 EXCHANGE_LIFETIME).
+pub const EXCHANGE_LIFETIME: Duration = Duration::from_secs(247);
+
+/// The number of exchanges remembered by default.
+const DEFAULT_CAPACITY: usize = 256;
+
+/// A message we responded to.
+struct Entry {
+    peer: SocketAddr,
+    message_id: u16,
+    /// The response, or `None` if the message didn't get one.
+    response: Option<Vec<u8>>,
+    received: Instant,
+}
+
+/// Remembers the responses to recent Confirmable messages.
+pub struct DedupCache {
+    /// The remembered exchanges, oldest first.
+    entries: VecDeque<Entry>,
+    capacity: usize,
+}
+
+impl Default for DedupCache {
+    fn default() -> DedupCache {
+        DedupCache::with_capacity(DEFAULT_CAPACITY)
+    }
+}
+
+impl DedupCache {
+    /// Creates a new `DedupCache` with the default capacity.
+    pub fn new() -> DedupCache {
+        Default::default()
+    }
+
+    /// Creates a new `DedupCache` remembering at most `capacity` exchanges.
+    /// When it's full, the oldest one is forgotten.
+    pub fn with_capacity(capacity: usize) -> DedupCache {
+        DedupCache {
+            entries: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Returns the response to send again if the message is a duplicate.
+    ///
+    /// The outer `Option` is `None` if the message is new, the inner one if
+    /// the original didn't get a response.
+    pub fn lookup(
+        &mut self,
+        peer: SocketAddr,
+        msg: &[u8],
+    ) -> Option<Option<Vec<u8>>> {
+        self.expire();
+        let message_id = confirmable_id(msg)?;
+
+        self.entries
+            .iter()
+            .find(|e| e.peer == peer && e.message_id == message_id)
+            .map(|e| e.response.clone())
+    }
+
+    /// Remembers the response to a message, if it's Confirmable, replacing
+    /// what was remembered for it before.
+    pub fn insert(
+        &mut self,
+        peer: SocketAddr,
+        msg: &[u8],
+        response: Option<&[u8]>,
+    ) {
+        let message_id = match confirmable_id(msg) {
+            Some(message_id) => message_id,
+            None => return,
+        };
+        if self.capacity == 0 {
+            return;
+        }
+        self.entries
+            .retain(|e| e.peer != peer || e.message_id != message_id);
+        if self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(Entry {
+            peer,
+            message_id,
+            response: response.map(|r| r.to_vec()),
+            received: Instant::now(),
+        });
+    }
+
+    /// Returns the number of remembered exchanges.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns `true` if no exchanges are remembered.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Forgets the exchanges the client can no longer retransmit in.
+    fn expire(&mut self) {
+        while let Some(entry) = self.entries.front() {
+            if entry.received.elapsed() < EXCHANGE_LIFETIME {
+                break;
+            }
+            self.entries.pop_front();
+        }
+    }
+}
+
+/// Returns the Message ID of a Confirmable message, or `None` for any other
+/// message.
+fn confirmable_id(msg: &[u8]) -> Option<u16> {
+    let header = msg.get(..4)?;
+    // The type is in bits 4 and 5 of the first byte, 0 is Confirmable
+    if header[0] >> 4 & 0x03 != 0 {
+        return None;
+    }
+
+    Some(u16::from_be_bytes([header[2], header[3]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A Confirmable GET with the Message ID.
+    fn con(message_id: u16) -> Vec<u8> {
+        let [high, low] = message_id.to_be_bytes();
+        vec![0x40, 0x01, high, low]
+    }
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn repeats_response() {
+        let mut cache = DedupCache::new();
+        assert_eq!(cache.lookup(peer(1), &con(7)), None);
+        cache.insert(peer(1), &con(7), Some(b"response"));
+        assert_eq!(
+            cache.lookup(peer(1), &con(7)),
+            Some(Some(b"response".to_vec()))
+        );
+    }
+
+    #[test]
+    fn replaces_response() {
+        let mut cache = DedupCache::new();
+        cache.insert(peer(1), &con(7), None);
+        cache.insert(peer(1), &con(7), Some(b"response"));
+        assert_eq!(cache.len(), 1);
+        assert_eq!(
+            cache.lookup(peer(1), &con(7)),
+            Some(Some(b"response".to_vec()))
+        );
+    }
+
+    #[test]
+    fn remembers_missing_response() {
+        let mut cache = DedupCache::new();
+        cache.insert(peer(1), &con(7), None);
+        assert_eq!(cache.lookup(peer(1), &con(7)), Some(None));
+    }
+
+    #[test]
+    fn distinguishes_peers_and_ids() {
+        let mut cache = DedupCache::new();
+        cache.insert(peer(1), &con(7), Some(b"response"));
+        assert_eq!(cache.lookup(peer(2), &con(7)), None);
+        assert_eq!(cache.lookup(peer(1), &con(8)), None);
+    }
+
+    #[test]
+    fn ignores_non_confirmable() {
+        let mut cache = DedupCache::new();
+        let non = [0x50, 0x01, 0x00, 0x07];
+        cache.insert(peer(1), &non, Some(b"response"));
+        assert!(cache.is_empty());
+        assert_eq!(cache.lookup(peer(1), &non), None);
+        cache.insert(peer(1), &[0x40], Some(b"response"));
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn forgets_oldest_when_full() {
+        let mut cache = DedupCache::with_capacity(2);
+        for message_id in 1..=3 {
+            cache.insert(peer(1), &con(message_id), None);
+        }
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.lookup(peer(1), &con(1)), None);
+        assert_eq!(cache.lookup(peer(1), &con(3)), Some(None));
+    }
+
+    #[test]
+    fn zero_capacity() {
+        let mut cache = DedupCache::with_capacity(0);
+        cache.insert(peer(1), &con(1), None);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn expires() {
+        let mut cache = DedupCache::new();
+        cache.insert(peer(1), &con(1), None);
+        cache.insert(peer(1), &con(2), None);
+        // Pretend the first one arrived an exchange lifetime ago
+        cache.entries[0].received = Instant::now()
+            .checked_sub(EXCHANGE_LIFETIME)
+            .expect("Clock too close to its start");
+
+        assert_eq!(cache.lookup(peer(1), &con(1)), None);
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.lookup(peer(1), &con(2)), Some(None));
+    }
+}
@@ -5,177 +5,800 @@ use oscore::edhoc::{
     error::{OwnError, OwnOrPeerError},
     PartyV,
 };
+use rand::{rngs::OsRng, CryptoRng, Rng, RngCore};
+use std::{
+    convert::TryFrom,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::{debug, info, warn};
+
+use crate::{logging::Secret, stats::Stats, trust::TrustStore};
+
+/// The maximum number of handshakes that can be waiting for message_3 at the
+/// same time by default. When it's reached, the oldest one is dropped.
+pub const DEFAULT_MAX_PENDING: usize = 32;
+
+/// The most handshakes that can be waiting for message_3 at the same time,
+/// since each needs a C_V of its own and that's a single byte.
+pub const MAX_PENDING: usize = 255;
+
+/// How long we wait for message_3 by default, before giving up on the
+/// handshake.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A handshake that has sent message_2 and is waiting for message_3.
+struct Session {
+    c_v: Vec<u8>,
+    peer: SocketAddr,
+    /// The message_1 it was started with, to recognize the peer starting
+    /// over.
+    message_1: Vec<u8>,
+    msg3_receiver: PartyV<api::Msg3Receiver>,
+    /// When message_2 was sent.
+    started: Instant,
+}
+
+/// How a handshake ended, as reported to the callback.
+#[derive(Debug)]
+pub enum Outcome {
+    /// The peer authenticated with the kid and we derived the secrets.
+    Completed { peer: SocketAddr, kid: Vec<u8> },
+    /// message_3 didn't arrive in time, or the handshake was dropped to
+    /// make room for others.
+    TimedOut { peer: SocketAddr },
+    /// An EDHOC error was sent or received.
+    Failed { peer: SocketAddr, error: String },
+}
+
+/// A function called with the outcome of every handshake.
+pub type Callback = Box<dyn FnMut(Outcome) + Send>;
 
-/// The state in which the EDHOC exchange is.
-#[derive(PartialEq)]
-enum State {
-    WaitingForFirst,
-    WaitingForThird,
-    Complete,
+/// The outcome of a completed handshake.
+pub struct Params {
+    /// The kid the peer authenticated with.
+    pub kid: Vec<u8>,
+    /// Our sender ID for the peer, as given by the trust store.
+    pub sender_id: Vec<u8>,
+    /// Our recipient ID for the peer, as given by the trust store.
+    pub recipient_id: Vec<u8>,
+    pub master_secret: Vec<u8>,
+    pub master_salt: Vec<u8>,
 }
 
-/// Handles the EDHOC exchange.
+/// Handles the EDHOC exchanges with any number of peers.
 pub struct EdhocHandler {
     auth_priv: [u8; 32],
     auth_pub: [u8; 32],
     kid: Vec<u8>,
-    auth_peer: [u8; 32],
-    state: State,
-    msg1_receiver: Option<PartyV<api::Msg1Receiver>>,
-    msg3_receiver: Option<PartyV<api::Msg3Receiver>>,
-    master_secret: Option<Vec<u8>>,
-    master_salt: Option<Vec<u8>>,
+    trust_store: TrustStore,
+    /// The pending handshakes, oldest first.
+    sessions: Vec<Session>,
+    rng: Box<dyn RngCore + Send>,
+    completed: Option<Params>,
+    /// How long a handshake may wait for message_3.
+    timeout: Duration,
+    /// How many handshakes may wait for message_3 at the same time.
+    max_pending: usize,
+    callback: Option<Callback>,
+    /// Where the handshakes are counted.
+    stats: Arc<Stats>,
 }
 
 impl EdhocHandler {
-    /// Creates a new `EdhocHandler`.
+    /// Creates a new `EdhocHandler`, which authenticates the peers in the
+    /// trust store and draws its ephemeral keys and connection identifiers
+    /// from the operating system's CSPRNG.
     pub fn new(
         auth_priv: [u8; 32],
         auth_pub: [u8; 32],
         kid: Vec<u8>,
-        auth_peer: [u8; 32],
+        trust_store: TrustStore,
     ) -> EdhocHandler {
+        EdhocHandler::with_rng(auth_priv, auth_pub, kid, trust_store, OsRng)
+    }
+
+    /// Creates a new `EdhocHandler` using the given random number generator.
+    ///
+    /// This is meant for reproducible test runs with a seeded generator,
+    /// otherwise use `new`.
+    pub fn with_rng<R>(
+        auth_priv: [u8; 32],
+        auth_pub: [u8; 32],
+        kid: Vec<u8>,
+        trust_store: TrustStore,
+        rng: R,
+    ) -> EdhocHandler
+    where
+        R: RngCore + CryptoRng + Send + 'static,
+    {
         EdhocHandler {
             auth_priv,
             auth_pub,
             kid,
-            auth_peer,
-            state: State::WaitingForFirst,
-            msg1_receiver: None,
-            msg3_receiver: None,
-            master_secret: None,
-            master_salt: None,
+            trust_store,
+            sessions: Vec::new(),
+            rng: Box::new(rng),
+            completed: None,
+            timeout: DEFAULT_TIMEOUT,
+            max_pending: DEFAULT_MAX_PENDING,
+            callback: None,
+            stats: Default::default(),
         }
     }
 
-    /// Handles an EDHOC message and returns the reply to send.
-    pub fn handle(&mut self, msg: Vec<u8>) -> Option<Vec<u8>> {
-        match self.state {
-            State::WaitingForFirst => {
-                println!(
-                    "Received an EDHOC message while waiting for message_1"
-                );
-                // Setup
-                self.initialize();
-
-                // Take out the receiver (which we know exists at this point)
-                let msg1_receiver = self.msg1_receiver.take().unwrap();
-                // Try to deal with message_1
-                let msg2_sender = match msg1_receiver.handle_message_1(msg) {
-                    Err(OwnError(b)) => {
-                        println!("Ran into an issue dealing with the message");
-                        // Since there's a problem, send an error message
-                        return Some(b);
-                    }
-                    Ok(val) => val,
-                };
-                // If that went well, produce message_2
-                let (msg2_bytes, msg3_receiver) =
-                    match msg2_sender.generate_message_2() {
-                        Err(OwnError(b)) => {
-                            println!("Ran into an issue producing message_2");
-                            return Some(b);
-                        }
-                        Ok(val) => val,
-                    };
-                // Store the state and advance our progress
-                self.msg3_receiver = Some(msg3_receiver);
-                self.state = State::WaitingForThird;
-                println!("Successfully built message_2");
-
-                // Return message_2 to be sent
-                Some(msg2_bytes)
-            }
-            State::WaitingForThird => {
-                println!(
-                    "Received an EDHOC message while waiting for message_3"
-                );
-                // Retrieve our state (which we know exists at this point)
-                let msg3_receiver = self.msg3_receiver.take().unwrap();
-                let (_u_kid, msg3_verifier) = match msg3_receiver
-                    .extract_peer_kid(msg)
-                {
-                    Err(OwnOrPeerError::PeerError(s)) => {
-                        println!("Received an EDHOC error: {}", s);
-                        self.state = State::WaitingForFirst;
-                        return None;
-                    }
-                    Err(OwnOrPeerError::OwnError(b)) => {
-                        println!("Ran into an issue dealing with the message");
-                        self.state = State::WaitingForFirst;
-                        return Some(b);
-                    }
-                    Ok(val) => val,
-                };
-                let (master_secret, master_salt) =
-                    match msg3_verifier.verify_message_3(&self.auth_peer) {
-                        Err(OwnError(b)) => {
-                            println!("Ran into an issue verifying message_3");
-                            self.state = State::WaitingForFirst;
-                            return Some(b);
-                        }
-                        Ok(val) => val,
-                    };
-
-                self.state = State::Complete;
-                println!(
-                    "Successfully derived the master secret and salt\r\n\
-                     {:?}\r\n\
-                     {:?}",
-                    master_secret, master_salt
-                );
-                self.master_secret = Some(master_secret);
-                self.master_salt = Some(master_salt);
+    /// Sets how long a handshake may wait for message_3, which is
+    /// `DEFAULT_TIMEOUT` otherwise.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Sets how many handshakes may wait for message_3 at the same time,
+    /// which is `DEFAULT_MAX_PENDING` otherwise. It's at least one and at
+    /// most `MAX_PENDING`.
+    pub fn set_max_pending(&mut self, max_pending: usize) {
+        self.max_pending = max_pending.clamp(1, MAX_PENDING);
+    }
+
+    /// Sets the function to report the outcome of every handshake to.
+    pub fn set_callback<F>(&mut self, callback: F)
+    where
+        F: FnMut(Outcome) + Send + 'static,
+    {
+        self.callback = Some(Box::new(callback));
+    }
+
+    /// Sets the statistics to count the handshakes in.
+    pub fn set_stats(&mut self, stats: Arc<Stats>) {
+        self.stats = stats;
+    }
+
+    /// Drops the handshakes that waited too long for message_3.
+    ///
+    /// This happens on every message, but should also be called regularly
+    /// so timeouts are reported when nobody talks to us.
+    pub fn expire(&mut self) {
+        let timeout = self.timeout;
+        let (expired, pending): (Vec<Session>, _) =
+            std::mem::take(&mut self.sessions)
+                .into_iter()
+                .partition(|s| s.started.elapsed() >= timeout);
+        self.sessions = pending;
+        for session in expired {
+            info!(peer = %session.peer, "Giving up on the handshake");
+            self.report(Outcome::TimedOut { peer: session.peer });
+        }
+    }
 
-                // Return an empty message, which results in the final ACK to
-                // the client
-                Some(vec![])
+    /// Handles an EDHOC message from the given peer and returns the reply to
+    /// send.
+    pub fn handle(
+        &mut self,
+        peer: SocketAddr,
+        msg: Vec<u8>,
+    ) -> Option<Vec<u8>> {
+        self.expire();
+
+        // message_1 starts with the TYPE integer, while message_3 (and an
+        // error message in its place) starts with our C_V byte string
+        match peek_bstr(&msg) {
+            Some(c_v) => {
+                let c_v = c_v.to_vec();
+                self.handle_message_3(peer, c_v, msg)
             }
-            State::Complete => {
-                println!(
-                    "Received an EDHOC message, but we're already complete"
-                );
+            None if is_error(&msg) => {
+                // Without C_V we can't tell which handshake this ends, since
+                // several peers may be behind the same address (a proxy)
+                warn!("Received an EDHOC error");
                 None
             }
+            None => self.handle_message_1(peer, msg),
         }
     }
 
-    /// Returns the negotiated master secret & salt, resetting the EDHOC state.
-    pub fn take_params(&mut self) -> Option<(Vec<u8>, Vec<u8>)> {
-        if self.state == State::Complete {
-            // Reset the state
-            self.state = State::WaitingForFirst;
-            // Take and return the derived context
-            Some((
-                self.master_secret.take().unwrap(),
-                self.master_salt.take().unwrap(),
-            ))
-        } else {
-            None
-        }
+    /// Returns the outcome of the last completed handshake.
+    pub fn take_params(&mut self) -> Option<Params> {
+        self.completed.take()
+    }
+
+    /// Returns the trust store, for instance to enroll more peers.
+    pub fn trust_store_mut(&mut self) -> &mut TrustStore {
+        &mut self.trust_store
     }
 
-    /// Initializes the handler to its original state.
-    fn initialize(&mut self) {
-        // "Generate" an ECDH key pair (this is hardcoded, but MUST be
-        // ephemeral and generated randomly)
-        let eph = [
-            0x17, 0xCD, 0xC7, 0xBC, 0xA3, 0xF2, 0xA0, 0xBD, 0xA6, 0x0C, 0x6D,
-            0xE5, 0xB9, 0x6F, 0x82, 0xA3, 0x62, 0x39, 0xB4, 0x4B, 0xDE, 0x39,
-            0x7A, 0x38, 0x62, 0xD5, 0x29, 0xBA, 0x8B, 0x3D, 0x7C, 0x62,
-        ];
-        // Choose a connection identifier (also hardcoded, could be
-        // chosen by user or generated randomly)
-        let c_v = [0xC4].to_vec();
-
-        // Initialize what we need to handle messages
+    /// Returns the number of handshakes waiting for message_3.
+    pub fn pending(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// Starts a new handshake with message_1 and returns message_2.
+    fn handle_message_1(
+        &mut self,
+        peer: SocketAddr,
+        msg: Vec<u8>,
+    ) -> Option<Vec<u8>> {
+        debug!("Received message_1");
+        self.stats.handshakes_started.increment();
+        // A client starting over abandons its pending handshake. Several
+        // clients may share the address, so it's only the same one if it
+        // sent the same message_1 again, or one with the same C_U.
+        let c_u = c_u(&msg);
+        let same_client = |session: &Session| {
+            session.peer == peer
+                && (session.message_1 == msg
+                    || c_u.is_some() && self::c_u(&session.message_1) == c_u)
+        };
+        let pending = self.sessions.len();
+        self.sessions.retain(|session| !same_client(session));
+        if self.sessions.len() < pending {
+            debug!("Replacing the pending handshake");
+        }
+        // Make room if we're at capacity, which may have been lowered
+        while self.sessions.len() >= self.max_pending {
+            let oldest = self.sessions.remove(0);
+            warn!(
+                peer = %oldest.peer,
+                "Too many handshakes, dropping the oldest one"
+            );
+            self.report(Outcome::TimedOut { peer: oldest.peer });
+        }
+
+        // Setup
+        let c_v = self.choose_c_v();
+        // Generate a fresh X25519 private key for this handshake only
+        let mut eph = [0; 32];
+        self.rng.fill_bytes(&mut eph);
         let msg1_receiver = PartyV::new(
-            c_v,
+            c_v.clone(),
             eph,
             &self.auth_priv,
             &self.auth_pub,
             self.kid.clone(),
         );
-        self.msg1_receiver = Some(msg1_receiver);
+
+        // Try to deal with message_1
+        let message_1 = msg.clone();
+        let msg2_sender = match msg1_receiver.handle_message_1(msg) {
+            Err(OwnError(b)) => {
+                warn!("Ran into an issue dealing with the message");
+                self.fail(peer, "Unable to handle message_1");
+                // Since there's a problem, send an error message
+                return Some(b);
+            }
+            Ok(val) => val,
+        };
+        // If that went well, produce message_2
+        let (msg2_bytes, msg3_receiver) =
+            match msg2_sender.generate_message_2() {
+                Err(OwnError(b)) => {
+                    warn!("Ran into an issue producing message_2");
+                    self.fail(peer, "Unable to produce message_2");
+                    return Some(b);
+                }
+                Ok(val) => val,
+            };
+        // Store the state of this handshake until message_3 arrives
+        self.sessions.push(Session {
+            c_v,
+            peer,
+            message_1,
+            msg3_receiver,
+            started: Instant::now(),
+        });
+        debug!(
+            "Successfully built message_2 ({} handshakes pending)",
+            self.sessions.len()
+        );
+
+        // Return message_2 to be sent
+        Some(msg2_bytes)
+    }
+
+    /// Completes the handshake identified by C_V with message_3.
+    fn handle_message_3(
+        &mut self,
+        peer: SocketAddr,
+        c_v: Vec<u8>,
+        msg: Vec<u8>,
+    ) -> Option<Vec<u8>> {
+        debug!("Received message_3");
+        // Retrieve the state of this handshake
+        let index = match self
+            .sessions
+            .iter()
+            .position(|s| s.c_v == c_v && s.peer == peer)
+        {
+            Some(index) => index,
+            None => {
+                warn!("There is no handshake with C_V {}", hex::encode(&c_v));
+                return Some(error_message("Unknown connection identifier"));
+            }
+        };
+        let msg3_receiver = self.sessions.remove(index).msg3_receiver;
+
+        let (u_kid, msg3_verifier) = match msg3_receiver.extract_peer_kid(msg)
+        {
+            Err(OwnOrPeerError::PeerError(s)) => {
+                warn!("Received an EDHOC error: {}", s);
+                self.fail(peer, &s);
+                return None;
+            }
+            Err(OwnOrPeerError::OwnError(b)) => {
+                warn!("Ran into an issue dealing with the message");
+                self.fail(peer, "Unable to handle message_3");
+                return Some(b);
+            }
+            Ok(val) => val,
+        };
+        // Find out who the peer claims to be
+        let trusted = match self.trust_store.get(&u_kid) {
+            Some(trusted) => trusted.clone(),
+            None => {
+                warn!(
+                    "Peer authenticated with unknown kid {}",
+                    hex::encode(&u_kid)
+                );
+                self.fail(peer, "Unknown kid");
+                return Some(error_message("Unknown kid"));
+            }
+        };
+        let (master_secret, master_salt) =
+            match msg3_verifier.verify_message_3(&trusted.public) {
+                Err(OwnError(b)) => {
+                    warn!("Ran into an issue verifying message_3");
+                    self.fail(peer, "Unable to verify message_3");
+                    return Some(b);
+                }
+                Ok(val) => val,
+            };
+
+        info!(
+            kid = %hex::encode(&u_kid),
+            master_secret = %Secret(&master_secret),
+            master_salt = %Secret(&master_salt),
+            "Successfully derived the master secret and salt"
+        );
+        self.report(Outcome::Completed {
+            peer,
+            kid: u_kid.clone(),
+        });
+        self.completed = Some(Params {
+            kid: u_kid,
+            sender_id: trusted.sender_id,
+            recipient_id: trusted.recipient_id,
+            master_secret,
+            master_salt,
+        });
+
+        // Return an empty message, which results in the final ACK to
+        // the client
+        Some(vec![])
+    }
+
+    /// Reports a handshake that failed with an EDHOC error.
+    fn fail(&mut self, peer: SocketAddr, error: &str) {
+        self.report(Outcome::Failed {
+            peer,
+            error: error.to_string(),
+        });
+    }
+
+    /// Passes the outcome of a handshake to the callback, if there is one.
+    fn report(&mut self, outcome: Outcome) {
+        match outcome {
+            Outcome::Completed { .. } => {
+                self.stats.handshakes_completed.increment()
+            }
+            _ => self.stats.handshakes_failed.increment(),
+        }
+        if let Some(callback) = self.callback.as_mut() {
+            callback(outcome);
+        }
+    }
+
+    /// Returns a random connection identifier not used by any pending
+    /// handshake.
+    fn choose_c_v(&mut self) -> Vec<u8> {
+        // Fewer than MAX_PENDING handshakes are pending when a new one
+        // starts, so there's always a free value
+        loop {
+            let c_v = vec![self.rng.gen()];
+            if !self.sessions.iter().any(|s| s.c_v == c_v) {
+                return c_v;
+            }
+        }
+    }
+}
+
+/// Returns the CBOR byte string at the start of the message, if there is one.
+fn peek_bstr(msg: &[u8]) -> Option<&[u8]> {
+    split_bstr(msg).map(|(bstr, _)| bstr)
+}
+
+/// Returns C_U of a message_1, which is the CBOR sequence of TYPE, SUITE,
+/// X_U and C_U, or `None` if it's malformed.
+fn c_u(msg: &[u8]) -> Option<&[u8]> {
+    let rest = skip_int(skip_int(msg)?)?;
+    let (_x_u, rest) = split_bstr(rest)?;
+
+    peek_bstr(rest)
+}
+
+/// Splits the CBOR byte string at the start of the message from what
+/// follows it.
+fn split_bstr(msg: &[u8]) -> Option<(&[u8], &[u8])> {
+    // Major type 2 is a byte string
+    if msg.first()? >> 5 != 2 {
+        return None;
+    }
+    let (len, start) = argument(msg)?;
+    let end = start.checked_add(usize::try_from(len).ok()?)?;
+
+    Some((msg.get(start..end)?, &msg[end..]))
+}
+
+/// Returns what follows the CBOR integer at the start of the message.
+fn skip_int(msg: &[u8]) -> Option<&[u8]> {
+    // Major types 0 and 1 are unsigned and negative integers
+    if msg.first()? >> 5 > 1 {
+        return None;
+    }
+    let (_, start) = argument(msg)?;
+
+    Some(&msg[start..])
+}
+
+/// Returns the argument of the CBOR item at the start of the message, which
+/// is a value or length, and where the item's content starts.
+fn argument(msg: &[u8]) -> Option<(u64, usize)> {
+    // The additional information is either the argument itself or tells
+    // us how many of the following bytes encode it
+    let size = match msg.first()? & 0x1F {
+        n @ 0..=23 => return Some((u64::from(n), 1)),
+        24 => 1,
+        25 => 2,
+        26 => 4,
+        27 => 8,
+        _ => return None,
+    };
+    let bytes = msg.get(1..1 + size)?;
+    let argument = bytes.iter().fold(0, |n, &b| n << 8 | u64::from(b));
+
+    Some((argument, 1 + size))
+}
+
+/// Returns whether the message starts with a CBOR text string, which is the
+/// case for an EDHOC error message without connection identifier.
+fn is_error(msg: &[u8]) -> bool {
+    // Major type 3 is a text string
+    matches!(msg.first(), Some(b) if b >> 5 == 3)
+}
+
+/// Returns an EDHOC error message with the given diagnostic text.
+fn error_message(err_msg: &str) -> Vec<u8> {
+    let len = err_msg.len();
+    // Encode the text string header, we never need more than 16 bits length
+    let mut msg = if len < 24 {
+        vec![0x60 | len as u8]
+    } else if len < 256 {
+        vec![0x78, len as u8]
+    } else {
+        let len = (len as u16).to_be_bytes();
+        vec![0x79, len[0], len[1]]
+    };
+    msg.extend(err_msg.as_bytes());
+
+    msg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{keys, trust::Peer};
+    use oscore::edhoc::PartyU;
+    use std::sync::Mutex;
+
+    const SERVER_PRIV: [u8; 32] = [1; 32];
+    const CLIENT_PRIV: [u8; 32] = [2; 32];
+    const CLIENT_KID: [u8; 1] = [0xA2];
+
+    fn public(private: &[u8; 32]) -> [u8; 32] {
+        keys::derive_public(private).unwrap()
+    }
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    /// Returns a handler trusting the client.
+    fn server() -> EdhocHandler {
+        let mut trust_store = TrustStore::new();
+        let client = Peer {
+            public: public(&CLIENT_PRIV),
+            sender_id: vec![0xA3],
+            recipient_id: CLIENT_KID.to_vec(),
+        };
+        assert!(trust_store.insert(CLIENT_KID.to_vec(), client).is_ok());
+
+        EdhocHandler::new(
+            SERVER_PRIV,
+            public(&SERVER_PRIV),
+            vec![0xA3],
+            trust_store,
+        )
+    }
+
+    /// Returns the message_1 of a client with the C_U and ephemeral key.
+    fn message_1(
+        c_u: u8,
+        eph: u8,
+        kid: &[u8],
+    ) -> (Vec<u8>, PartyU<api::Msg2Receiver>) {
+        let client = PartyU::new(
+            vec![c_u],
+            [eph; 32],
+            &CLIENT_PRIV,
+            &public(&CLIENT_PRIV),
+            kid.to_vec(),
+        );
+        match client.generate_message_1(1) {
+            Ok(val) => val,
+            Err(_) => panic!("Failed generating message_1"),
+        }
+    }
+
+    /// Returns the client's message_3 for the server's message_2.
+    fn message_3(
+        client: PartyU<api::Msg2Receiver>,
+        message_2: Vec<u8>,
+    ) -> Vec<u8> {
+        let verifier = match client.extract_peer_kid(message_2) {
+            Ok((_, verifier)) => verifier,
+            Err(_) => panic!("Invalid message_2"),
+        };
+        let sender = match verifier.verify_message_2(&public(&SERVER_PRIV)) {
+            Ok(sender) => sender,
+            Err(_) => panic!("Failed verifying message_2"),
+        };
+        match sender.generate_message_3() {
+            Ok((message_3, _, _)) => message_3,
+            Err(_) => panic!("Failed generating message_3"),
+        }
+    }
+
+    /// Hands out the given numbers, then counts up from the last one.
+    struct Scripted(Vec<u32>);
+
+    impl RngCore for Scripted {
+        fn next_u32(&mut self) -> u32 {
+            match self.0.len() {
+                1 => {
+                    self.0[0] += 1;
+                    self.0[0] - 1
+                }
+                _ => self.0.remove(0),
+            }
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            u64::from(self.next_u32())
+        }
+
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            for byte in dest {
+                *byte = 0x55;
+            }
+        }
+
+        fn try_fill_bytes(
+            &mut self,
+            dest: &mut [u8],
+        ) -> Result<(), rand::Error> {
+            self.fill_bytes(dest);
+            Ok(())
+        }
+    }
+
+    impl CryptoRng for Scripted {}
+
+    /// Returns the C_V a message_2 starts with.
+    fn c_v(message_2: &[u8]) -> Vec<u8> {
+        peek_bstr(message_2).unwrap().to_vec()
+    }
+
+    /// Makes the server record the outcomes of its handshakes.
+    fn record(server: &mut EdhocHandler) -> Arc<Mutex<Vec<Outcome>>> {
+        let outcomes = Arc::new(Mutex::new(Vec::new()));
+        let recorded = outcomes.clone();
+        server.set_callback(move |outcome| {
+            recorded.lock().unwrap().push(outcome);
+        });
+        outcomes
+    }
+
+    #[test]
+    fn concurrent_handshakes() {
+        let mut server = server();
+        let outcomes = record(&mut server);
+        let (msg1_a, client_a) = message_1(1, 10, &CLIENT_KID);
+        let (msg1_b, client_b) = message_1(1, 20, &CLIENT_KID);
+        let msg2_a = server.handle(peer(1), msg1_a).unwrap();
+        let msg2_b = server.handle(peer(2), msg1_b).unwrap();
+        assert_ne!(c_v(&msg2_a), c_v(&msg2_b));
+
+        // message_3 only counts from the peer the handshake is with
+        let msg3_b = message_3(client_b, msg2_b);
+        assert_eq!(
+            server.handle(peer(1), msg3_b.clone()),
+            Some(error_message("Unknown connection identifier"))
+        );
+        assert_eq!(server.handle(peer(2), msg3_b), Some(vec![]));
+        let msg3_a = message_3(client_a, msg2_a);
+        assert_eq!(server.handle(peer(1), msg3_a), Some(vec![]));
+
+        let outcomes = outcomes.lock().unwrap();
+        assert_eq!(outcomes.len(), 2);
+        assert!(matches!(
+            &outcomes[0],
+            Outcome::Completed { peer: p, kid }
+                if *p == peer(2) && kid == &CLIENT_KID
+        ));
+        assert!(matches!(
+            &outcomes[1],
+            Outcome::Completed { peer: p, .. } if *p == peer(1)
+        ));
+    }
+
+    #[test]
+    fn c_v_collision() {
+        let mut trust_store = TrustStore::new();
+        let client = Peer {
+            public: public(&CLIENT_PRIV),
+            sender_id: vec![0xA3],
+            recipient_id: CLIENT_KID.to_vec(),
+        };
+        assert!(trust_store.insert(CLIENT_KID.to_vec(), client).is_ok());
+        let mut server = EdhocHandler::with_rng(
+            SERVER_PRIV,
+            public(&SERVER_PRIV),
+            vec![0xA3],
+            trust_store,
+            Scripted(vec![5, 5, 5, 6]),
+        );
+
+        let (msg1, _) = message_1(1, 10, &CLIENT_KID);
+        let msg2_a = server.handle(peer(1), msg1).unwrap();
+        let (msg1, _) = message_1(1, 10, &CLIENT_KID);
+        let msg2_b = server.handle(peer(2), msg1).unwrap();
+        assert_eq!(c_v(&msg2_a), vec![5]);
+        // It drew 5 twice more before getting a free one
+        assert_eq!(c_v(&msg2_b), vec![6]);
+    }
+
+    #[test]
+    fn reports_timeout() {
+        let mut server = server();
+        let outcomes = record(&mut server);
+        server.set_timeout(Duration::from_secs(0));
+        let (msg1, client) = message_1(1, 10, &CLIENT_KID);
+        let msg2 = server.handle(peer(1), msg1).unwrap();
+        server.expire();
+        assert_eq!(server.pending(), 0);
+        assert!(matches!(
+            outcomes.lock().unwrap()[..],
+            [Outcome::TimedOut { peer: p }] if p == peer(1)
+        ));
+
+        // Too late
+        let msg3 = message_3(client, msg2);
+        assert_eq!(
+            server.handle(peer(1), msg3),
+            Some(error_message("Unknown connection identifier"))
+        );
+        assert!(server.take_params().is_none());
+    }
+
+    #[test]
+    fn reports_dropped_handshake() {
+        let mut server = server();
+        let outcomes = record(&mut server);
+        server.set_max_pending(1);
+        let (msg1, _) = message_1(1, 10, &CLIENT_KID);
+        server.handle(peer(1), msg1);
+        let (msg1, _) = message_1(1, 20, &CLIENT_KID);
+        server.handle(peer(2), msg1);
+        assert_eq!(server.pending(), 1);
+        assert!(matches!(
+            outcomes.lock().unwrap()[..],
+            [Outcome::TimedOut { peer: p }] if p == peer(1)
+        ));
+    }
+
+    #[test]
+    fn reports_unknown_kid() {
+        let mut server = server();
+        let outcomes = record(&mut server);
+        let (msg1, client) = message_1(1, 10, &[0xEE]);
+        let msg2 = server.handle(peer(1), msg1).unwrap();
+        let msg3 = message_3(client, msg2);
+        assert_eq!(
+            server.handle(peer(1), msg3),
+            Some(error_message("Unknown kid"))
+        );
+        assert!(server.take_params().is_none());
+        assert!(matches!(
+            &outcomes.lock().unwrap()[..],
+            [Outcome::Failed { error, .. }] if error == "Unknown kid"
+        ));
+    }
+
+    #[test]
+    fn clients_sharing_an_address() {
+        let mut server = server();
+        let (msg1_a, client_a) = message_1(1, 10, &CLIENT_KID);
+        let (msg1_b, client_b) = message_1(2, 20, &CLIENT_KID);
+        let msg2_a = server.handle(peer(1), msg1_a).unwrap();
+        let msg2_b = server.handle(peer(1), msg1_b).unwrap();
+        assert_eq!(server.pending(), 2);
+
+        // Neither aborts the other's handshake
+        let msg3_b = message_3(client_b, msg2_b);
+        assert_eq!(server.handle(peer(1), msg3_b), Some(vec![]));
+        assert!(server.take_params().is_some());
+        let msg3_a = message_3(client_a, msg2_a);
+        assert_eq!(server.handle(peer(1), msg3_a), Some(vec![]));
+        assert!(server.take_params().is_some());
+        assert_eq!(server.pending(), 0);
+    }
+
+    #[test]
+    fn client_starting_over() {
+        let mut server = server();
+        let (msg1, _) = message_1(1, 10, &CLIENT_KID);
+        server.handle(peer(1), msg1.clone());
+        // A retransmission
+        server.handle(peer(1), msg1);
+        assert_eq!(server.pending(), 1);
+        // A fresh attempt with the same C_U
+        let (msg1, client) = message_1(1, 11, &CLIENT_KID);
+        let msg2 = server.handle(peer(1), msg1).unwrap();
+        assert_eq!(server.pending(), 1);
+        // But the same C_U from elsewhere is another client
+        let (msg1, _) = message_1(1, 12, &CLIENT_KID);
+        server.handle(peer(2), msg1);
+        assert_eq!(server.pending(), 2);
+
+        let msg3 = message_3(client, msg2);
+        assert_eq!(server.handle(peer(1), msg3), Some(vec![]));
+    }
+
+    #[test]
+    fn limits_pending() {
+        let mut server = server();
+        server.set_max_pending(0);
+        assert_eq!(server.max_pending, 1);
+        server.set_max_pending(1000);
+        assert_eq!(server.max_pending, MAX_PENDING);
+
+        // Every C_V is taken but one, which the new handshake gets
+        for port in 0..MAX_PENDING as u16 {
+            let (msg1, _) = message_1(1, 10, &CLIENT_KID);
+            server.handle(peer(port), msg1);
+        }
+        assert_eq!(server.pending(), MAX_PENDING);
+        let mut c_vs: Vec<_> =
+            server.sessions.iter().map(|s| &s.c_v).collect();
+        c_vs.sort();
+        c_vs.dedup();
+        assert_eq!(c_vs.len(), MAX_PENDING);
+    }
+
+    #[test]
+    fn c_u_of_message_1() {
+        let (msg1, _) = message_1(7, 10, &CLIENT_KID);
+        assert_eq!(c_u(&msg1), Some(&[7][..]));
+        assert_eq!(c_u(&msg1[..msg1.len() - 1]), None);
+        assert_eq!(c_u(&[]), None);
     }
 }
@@ -1,3 +1,16 @@
+pub use desktop_common::{keys, logging};
+
+pub mod block;
 pub mod coap;
+pub mod config;
+pub mod context;
+pub mod dedup;
 pub mod edhoc;
 pub mod oscore;
+pub mod resources;
+pub mod router;
+pub mod server;
+pub mod state;
+pub mod stats;
+pub mod tcp;
+pub mod trust;
@@ -1,23 +1,35 @@
-use clap::{App, Arg};
-use std::net::UdpSocket;
+use clap::{App, Arg, ArgMatches};
+use rand::{rngs::StdRng, SeedableRng};
+use std::{
+    net::{SocketAddr, UdpSocket},
+    path::{Path, PathBuf},
+    process,
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+use tracing::{error, info, warn};
 
 use desktop_server::{
-    coap::CoapHandler, edhoc::EdhocHandler, oscore::OscoreHandler,
+    coap::CoapHandler,
+    config::{Config, PeerConfig},
+    edhoc::{EdhocHandler, Outcome, DEFAULT_MAX_PENDING, DEFAULT_TIMEOUT},
+    keys::{self, KeyPair},
+    logging,
+    oscore::OscoreHandler,
+    server::Server,
+    state::StateFile,
+    stats::{Stats, StatsResource},
+    trust::{Peer, TrustStore},
 };
 
-/* EDHOC configuration */
+/* EDHOC configuration (demo keys used when no key files are given) */
 // Private authentication key
 const AUTH_PRIV: [u8; 32] = [
     0x74, 0x56, 0xB3, 0xA3, 0xE5, 0x8D, 0x8D, 0x26, 0xDD, 0x36, 0xBC, 0x75,
     0xD5, 0x5B, 0x88, 0x63, 0xA8, 0x5D, 0x34, 0x72, 0xF4, 0xA0, 0x1F, 0x02,
     0x24, 0x62, 0x1B, 0x1C, 0xB8, 0x16, 0x6D, 0xA9,
 ];
-// Public authentication key (known by peer)
-const AUTH_PUB: [u8; 32] = [
-    0x1B, 0x66, 0x1E, 0xE5, 0xD5, 0xEF, 0x16, 0x72, 0xA2, 0xD8, 0x77, 0xCD,
-    0x5B, 0xC2, 0x0F, 0x46, 0x30, 0xDC, 0x78, 0xA1, 0x14, 0xDE, 0x65, 0x9C,
-    0x7E, 0x50, 0x4D, 0x0F, 0x52, 0x9A, 0x6B, 0xD3,
-];
 // Key ID used to identify the public authentication key
 const KID: [u8; 1] = [0xA3];
 // Public authentication key of the peer
@@ -29,51 +41,695 @@ const AUTH_PEER: [u8; 32] = [
 // Key ID of peer
 const KID_PEER: [u8; 1] = [0xA2];
 
+/// How often the statistics file is rewritten.
+const STATS_INTERVAL: Duration = Duration::from_secs(15);
+
 fn main() {
-    let matches = App::new(clap::crate_name!())
+    let matches = app().get_matches();
+    let config_path = matches.value_of("config").map(PathBuf::from);
+    let config =
+        load_config(config_path.as_deref(), &matches).unwrap_or_else(|e| {
+            eprintln!("Error in configuration: {}", e);
+            process::exit(1);
+        });
+    // These were validated with the rest of the configuration
+    let format = config.log_format().unwrap();
+    let (addrs, tcp_addrs) = config.bind_addrs().unwrap();
+    logging::init(format, config.log.filter.as_deref()).unwrap_or_else(|e| {
+        eprintln!("Error setting up logging: {}", e);
+        process::exit(1);
+    });
+    logging::show_secrets(config.log.show_secrets);
+
+    // Refuse to start with keys we can't use
+    let (own, trust_store) = load_keys(&config).unwrap_or_else(|e| {
+        error!("Error loading keys: {}", e);
+        process::exit(1);
+    });
+
+    // This is doing the EDHOC exchange
+    let mut edhoc = match config.server.seed {
+        Some(seed) => EdhocHandler::with_rng(
+            own.private,
+            own.public,
+            own.kid,
+            trust_store,
+            StdRng::seed_from_u64(seed),
+        ),
+        None => {
+            EdhocHandler::new(own.private, own.public, own.kid, trust_store)
+        }
+    };
+    edhoc.set_callback(|outcome| match outcome {
+        Outcome::Completed { peer, kid } => {
+            info!(%peer, kid = %hex::encode(kid), "Handshake completed")
+        }
+        Outcome::TimedOut { peer } => {
+            warn!(%peer, "Handshake timed out")
+        }
+        Outcome::Failed { peer, error } => {
+            warn!(%peer, "Handshake failed: {}", error)
+        }
+    });
+    // Counts what all the layers are doing, which the /stats resource
+    // reports
+    let stats = Arc::new(Stats::new());
+    // This will be responsible for dealing with CoAP messages
+    let mut coap = CoapHandler::new();
+    coap.register("stats", StatsResource::new(stats.clone()));
+    // And finally this is the layer for OSCORE, which keeps a security
+    // context for every peer that completed EDHOC
+    let mut oscore = match config.server.state.as_ref() {
+        Some(path) => {
+            OscoreHandler::with_state(edhoc, coap, StateFile::new(path))
+                .unwrap_or_else(|e| {
+                    error!("Error loading state: {}", e);
+                    process::exit(1);
+                })
+        }
+        None => OscoreHandler::new(edhoc, coap),
+    };
+    configure(&mut oscore, &config).unwrap_or_else(|e| {
+        error!("Error in configuration: {}", e);
+        process::exit(1);
+    });
+    oscore.set_stats(stats.clone());
+    if let Some(path) = config.server.stats_file.clone() {
+        write_stats(stats, path);
+    }
+
+    let mut server = Server::new(oscore);
+    if let Some(path) = config_path {
+        let started = config.clone();
+        server.set_reload(move |oscore| {
+            if let Err(e) = reload(oscore, &path, &matches, &started) {
+                error!("Not reloading the configuration: {}", e);
+            }
+        });
+    }
+    if config.server.asynchronous {
+        server
+            .run_async(&addrs, &tcp_addrs)
+            .expect("Failed serving");
+    } else {
+        let socket =
+            UdpSocket::bind(addrs[0]).expect("Unable to bind to port");
+        server.run_blocking(&socket).expect("Failed serving");
+    }
+}
+
+/// Returns the command line interface.
+fn app() -> App<'static, 'static> {
+    App::new(clap::crate_name!())
         .version(clap::crate_version!())
         .about("Desktop server for OSCORE clients.")
         .author(clap::crate_authors!())
+        .arg(
+            Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .value_name("FILE")
+                .takes_value(true)
+                .help(
+                    "TOML file with the settings, reloaded on SIGHUP; the \
+                     other options take precedence",
+                ),
+        )
         .arg(
             Arg::with_name("port")
                 .short("p")
                 .long("port")
                 .value_name("NUM")
                 .takes_value(true)
-                .help("The local port to bind to")
-                .required(true),
+                .help("The local port to bind to on all IPv4 interfaces")
+                .required_unless_one(&["bind", "config"]),
+        )
+        .arg(
+            Arg::with_name("bind")
+                .long("bind")
+                .value_name("ADDR")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help(
+                    "An address to bind to, like [::]:5683, or \
+                     coap+tcp://[::]:5683 to accept TCP connections",
+                ),
+        )
+        .arg(
+            Arg::with_name("async").long("async").help(
+                "Handles requests concurrently, on any number of sockets",
+            ),
+        )
+        .arg(
+            Arg::with_name("seed")
+                .long("seed")
+                .value_name("NUM")
+                .takes_value(true)
+                .help("Seeds the RNG for reproducible test runs (insecure)"),
+        )
+        .arg(
+            Arg::with_name("key")
+                .long("key")
+                .value_name("FILE")
+                .takes_value(true)
+                .help("Our private key as COSE_Key or hex"),
+        )
+        .arg(
+            Arg::with_name("kid")
+                .long("kid")
+                .value_name("HEX")
+                .takes_value(true)
+                .help("Our key ID, if not in the key file"),
+        )
+        .arg(
+            Arg::with_name("peer-key")
+                .long("peer-key")
+                .value_name("FILE")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("The public key of a trusted peer as COSE_Key or hex"),
+        )
+        .arg(
+            Arg::with_name("peer-kid")
+                .long("peer-kid")
+                .value_name("HEX")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("The key ID of each peer key, if not in the key file"),
+        )
+        .arg(
+            Arg::with_name("state")
+                .long("state")
+                .value_name("FILE")
+                .takes_value(true)
+                .help("File to persist security contexts in across restarts"),
+        )
+        .arg(
+            Arg::with_name("handshake-timeout")
+                .long("handshake-timeout")
+                .value_name("SECS")
+                .takes_value(true)
+                .help("How long to wait for message_3 of a handshake"),
+        )
+        .arg(
+            Arg::with_name("max-seq")
+                .long("max-seq")
+                .value_name("NUM")
+                .takes_value(true)
+                .help("Sequence number at which a security context expires"),
+        )
+        .arg(
+            Arg::with_name("lifetime")
+                .long("lifetime")
+                .value_name("SECS")
+                .takes_value(true)
+                .help("How long a security context may be used"),
+        )
+        .arg(
+            Arg::with_name("grace")
+                .long("grace")
+                .value_name("SECS")
+                .takes_value(true)
+                .help(
+                    "How long a replaced security context is still accepted",
+                ),
+        )
+        .arg(
+            Arg::with_name("stats-file")
+                .long("stats-file")
+                .value_name("FILE")
+                .takes_value(true)
+                .help("File to keep the statistics in for Prometheus"),
+        )
+        .arg(
+            Arg::with_name("log")
+                .long("log")
+                .value_name("FILTER")
+                .takes_value(true)
+                .help("Log filter like info,desktop_server::edhoc=debug"),
         )
-        .get_matches();
-    let port = matches.value_of("port").unwrap();
+        .arg(
+            Arg::with_name("log-format")
+                .long("log-format")
+                .value_name("FORMAT")
+                .takes_value(true)
+                .possible_values(&["text", "json"])
+                .default_value("text")
+                .help("Whether to log text or JSON lines"),
+        )
+        .arg(
+            Arg::with_name("show-secrets")
+                .long("show-secrets")
+                .help("Logs key material instead of redacting it (insecure)"),
+        )
+}
 
-    let socket = UdpSocket::bind(format!("0.0.0.0:{}", port))
-        .expect("Unable to bind to port");
+/// Returns the configuration from the file, if there is one, with the
+/// settings given on the command line taking precedence.
+fn load_config(
+    path: Option<&Path>,
+    matches: &ArgMatches,
+) -> Result<Config, String> {
+    let mut config = match path {
+        Some(path) => Config::load(path)
+            .map_err(|e| format!("{}: {}", path.display(), e))?,
+        None => Config::default(),
+    };
+    apply_args(&mut config, matches)?;
+    config.validate().map_err(|e| e.to_string())?;
+    if config.server.bind.is_empty() {
+        return Err("no address to bind to".to_string());
+    }
 
-    // This is doing the EDHOC exchange
-    let edhoc =
-        EdhocHandler::new(AUTH_PRIV, AUTH_PUB, KID.to_vec(), AUTH_PEER);
-    // This will be responsible for dealing with CoAP messages
-    let coap = CoapHandler::new();
-    // And finally this is the layer for OSCORE
-    let mut oscore =
-        OscoreHandler::new(edhoc, coap, KID.to_vec(), KID_PEER.to_vec());
-
-    loop {
-        let mut buf = [0; 2048];
-        let (amt, src) =
-            socket.recv_from(&mut buf).expect("Failed while receiving");
-        println!("\nRx({})", amt);
-        println!("IP packet from {}", src.ip());
-
-        // Handle the request
-        let res = oscore.handle(&buf[..amt]);
-        if res.is_none() {
-            continue;
+    Ok(config)
+}
+
+/// Overrides the settings of the configuration with those given on the
+/// command line.
+fn apply_args(
+    config: &mut Config,
+    matches: &ArgMatches,
+) -> Result<(), String> {
+    // Addresses on the command line replace those in the file
+    let mut bind: Vec<String> = matches
+        .values_of("bind")
+        .into_iter()
+        .flatten()
+        .map(String::from)
+        .collect();
+    if let Some(port) = matches.value_of("port") {
+        let port = port
+            .parse::<u16>()
+            .map_err(|_| format!("--port: invalid port {}", port))?;
+        bind.push(SocketAddr::from(([0, 0, 0, 0], port)).to_string());
+    }
+    if !bind.is_empty() {
+        config.server.bind = bind;
+    }
+    if matches.is_present("async") {
+        config.server.asynchronous = true;
+    }
+    if let Some(seed) = matches.value_of("seed") {
+        config.server.seed = Some(
+            seed.parse()
+                .map_err(|_| format!("--seed: invalid number {}", seed))?,
+        );
+    }
+    if let Some(state) = matches.value_of("state") {
+        config.server.state = Some(PathBuf::from(state));
+    }
+    if let Some(stats_file) = matches.value_of("stats-file") {
+        config.server.stats_file = Some(PathBuf::from(stats_file));
+    }
+
+    if let Some(key) = matches.value_of("key") {
+        config.identity.key = Some(PathBuf::from(key));
+    }
+    if let Some(kid) = matches.value_of("kid") {
+        config.identity.kid = Some(kid.to_string());
+    }
+    // Each --peer-kid belongs to the --peer-key in the same position
+    let paths: Vec<&str> = matches
+        .values_of("peer-key")
+        .into_iter()
+        .flatten()
+        .collect();
+    let peer_kids: Vec<&str> = matches
+        .values_of("peer-kid")
+        .into_iter()
+        .flatten()
+        .collect();
+    if !peer_kids.is_empty() && peer_kids.len() != paths.len() {
+        return Err("need one --peer-kid for every --peer-key".to_string());
+    }
+    if !paths.is_empty() {
+        config.peers = paths
+            .iter()
+            .enumerate()
+            .map(|(i, path)| PeerConfig {
+                key: PathBuf::from(path),
+                kid: peer_kids.get(i).map(|kid| kid.to_string()),
+                sender_id: None,
+                recipient_id: None,
+            })
+            .collect();
+    }
+
+    let limits = &mut config.limits;
+    if let Some(timeout) = matches.value_of("handshake-timeout") {
+        limits.handshake_timeout =
+            Some(parse_number(timeout, "--handshake-timeout")?);
+    }
+    if let Some(max_seq) = matches.value_of("max-seq") {
+        limits.max_seq = Some(parse_number(max_seq, "--max-seq")?);
+    }
+    if let Some(lifetime) = matches.value_of("lifetime") {
+        limits.lifetime = Some(parse_number(lifetime, "--lifetime")?);
+    }
+    if let Some(grace) = matches.value_of("grace") {
+        limits.grace = Some(parse_number(grace, "--grace")?);
+    }
+
+    if let Some(filter) = matches.value_of("log") {
+        config.log.filter = Some(filter.to_string());
+    }
+    if matches.occurrences_of("log-format") > 0 {
+        config.log.format = matches.value_of("log-format").map(String::from);
+    }
+    if matches.is_present("show-secrets") {
+        config.log.show_secrets = true;
+    }
+
+    Ok(())
+}
+
+/// Parses a number given with the argument.
+fn parse_number(number: &str, arg: &str) -> Result<u64, String> {
+    number
+        .parse()
+        .map_err(|_| format!("{}: invalid number {}", arg, number))
+}
+
+/// Applies the resource policies and limits of the configuration.
+///
+/// The resource policies are the only thing that can fail, so they go first
+/// and nothing is changed if they do.
+fn configure(
+    oscore: &mut OscoreHandler,
+    config: &Config,
+) -> Result<(), String> {
+    oscore
+        .coap_mut()
+        .set_access_policies(&config.access_policies())
+        .map_err(|path| format!("resources: there is no resource {}", path))?;
+    // These were validated with the rest of the configuration
+    oscore.set_rekey_policy(config.rekey_policy().unwrap());
+    let edhoc = oscore.edhoc_mut();
+    edhoc.set_timeout(config.handshake_timeout().unwrap_or(DEFAULT_TIMEOUT));
+    edhoc.set_max_pending(
+        config.limits.max_pending.unwrap_or(DEFAULT_MAX_PENDING),
+    );
+
+    Ok(())
+}
+
+/// Reloads the peers, resources and limits from the configuration file,
+/// keeping the security contexts that were established with the peers
+/// still in it.
+///
+/// Nothing is changed if the configuration is invalid.
+fn reload(
+    oscore: &mut OscoreHandler,
+    path: &Path,
+    matches: &ArgMatches,
+    started: &Config,
+) -> Result<(), String> {
+    let config = load_config(Some(path), matches)?;
+    let (own, trust_store) = load_keys(&config)?;
+    if config.server != started.server
+        || config.identity != started.identity
+        || config.log != started.log
+    {
+        warn!("Changes to [server], [identity] and [log] need a restart");
+    }
+    configure(oscore, &config)?;
+    let peers = trust_store.len();
+    *oscore.edhoc_mut().trust_store_mut() = trust_store;
+    // The peers that were removed don't get to keep their contexts
+    oscore.retain_trusted();
+    info!(
+        kid = %hex::encode(own.kid),
+        "Reloaded the configuration with {} peers",
+        peers
+    );
+
+    Ok(())
+}
+
+/// Rewrites the statistics file periodically in a background thread.
+fn write_stats(stats: Arc<Stats>, path: PathBuf) {
+    thread::spawn(move || loop {
+        if let Err(e) = stats.write_prometheus(&path) {
+            warn!("Failed writing statistics: {}", e);
+        }
+        thread::sleep(STATS_INTERVAL);
+    });
+}
+
+/// Returns our key pair and the trust store with the peer keys from the
+/// files in the configuration, or the built-in demo keys for those that
+/// aren't there.
+fn load_keys(config: &Config) -> Result<(KeyPair, TrustStore), String> {
+    let kid = config
+        .identity
+        .kid
+        .as_deref()
+        .map(keys::parse_kid)
+        .transpose()
+        .map_err(|e| format!("identity.kid: {}", e))?;
+    let own = match config.identity.key.as_ref() {
+        Some(path) => keys::load_key_pair(path, kid)
+            .map_err(|e| format!("{}: {}", path.display(), e))?,
+        None => {
+            warn!("No key file given, using the demo key");
+            KeyPair {
+                kid: kid.unwrap_or_else(|| KID.to_vec()),
+                private: AUTH_PRIV,
+                public: keys::derive_public(&AUTH_PRIV)
+                    .expect("Demo key is invalid"),
+            }
+        }
+    };
+
+    let mut peers = Vec::new();
+    for peer in &config.peers {
+        let kid = peer
+            .kid
+            .as_deref()
+            .map(keys::parse_kid)
+            .transpose()
+            .map_err(|e| format!("peer.kid: {}", e))?;
+        let peer_key = keys::load_peer_key(&peer.key, kid)
+            .map_err(|e| format!("{}: {}", peer.key.display(), e))?;
+        peers.push((
+            peer_key,
+            peer.sender_id.clone(),
+            peer.recipient_id.clone(),
+        ));
+    }
+    if peers.is_empty() {
+        warn!("No peer key file given, using the demo key");
+        peers.push((
+            keys::PeerKey {
+                kid: KID_PEER.to_vec(),
+                public: AUTH_PEER,
+            },
+            None,
+            None,
+        ));
+    }
+
+    let mut trust_store = TrustStore::new();
+    for (peer_key, sender_id, recipient_id) in peers {
+        // OSCORE needs our IDs to differ, and it's a mixup anyway
+        if peer_key.kid == own.kid {
+            return Err("a peer has the same kid as we do".to_string());
+        }
+        // By default, the IDs are our and the peer's kid
+        let parse_id = |id: Option<String>, default: &[u8]| match id {
+            Some(id) => hex::decode(id).map_err(|_| "invalid OSCORE ID"),
+            None => Ok(default.to_vec()),
+        };
+        let peer = Peer {
+            public: peer_key.public,
+            sender_id: parse_id(sender_id, &own.kid)?,
+            recipient_id: parse_id(recipient_id, &peer_key.kid)?,
+        };
+        if peer.sender_id == peer.recipient_id {
+            return Err(format!(
+                "peer {}: sender and recipient ID are the same",
+                hex::encode(&peer_key.kid)
+            ));
+        }
+        if trust_store.get(&peer_key.kid).is_some() {
+            return Err("several peers have the same kid".to_string());
         }
-        let res = res.unwrap();
+        if trust_store.insert(peer_key.kid.clone(), peer).is_err() {
+            return Err(format!(
+                "peer {}: recipient ID is already used by another peer",
+                hex::encode(&peer_key.kid)
+            ));
+        }
+    }
+
+    Ok((own, trust_store))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn config(file: &str, args: &[&str]) -> Result<Config, String> {
+        let mut config: Config = toml::from_str(file).unwrap();
+        let matches = app()
+            .get_matches_from_safe(
+                ["desktop-server", "--config", "server.toml"]
+                    .iter()
+                    .chain(args),
+            )
+            .unwrap();
+        apply_args(&mut config, &matches)?;
+        config.validate().map_err(|e| e.to_string())?;
+
+        Ok(config)
+    }
+
+    #[test]
+    fn args_take_precedence() {
+        let file = r#"
+            [server]
+            bind = ["coap://127.0.0.1:5683"]
+            [identity]
+            kid = "a3"
+            [limits]
+            max_seq = 100
+            [log]
+            format = "json"
+        "#;
+        let config = config(
+            file,
+            &["--port", "5684", "--kid", "b3", "--max-seq", "200"],
+        )
+        .unwrap();
+        assert_eq!(config.server.bind, ["0.0.0.0:5684"]);
+        assert_eq!(config.identity.kid.as_deref(), Some("b3"));
+        assert_eq!(config.limits.max_seq, Some(200));
+        // Defaults of the command line don't override the file
+        assert_eq!(config.log.format.as_deref(), Some("json"));
+    }
+
+    #[test]
+    fn validated_after_args() {
+        let file = r#"
+            [server]
+            bind = ["coap://127.0.0.1:5683", "coap+tcp://127.0.0.1:5683"]
+        "#;
+        assert!(config(file, &[]).is_err());
+        let config = config(file, &["--async"]).unwrap();
+        assert!(config.server.asynchronous);
+    }
+
+    /// Returns the result of loading the keys with the peer entries, each
+    /// with the demo peer key.
+    fn load_peers(entries: &str) -> Result<TrustStore, String> {
+        let dir = std::env::temp_dir()
+            .join(format!("load-keys-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let key = dir.join("peer.pub");
+        fs::write(&key, hex::encode(AUTH_PEER)).unwrap();
+        let file = entries.replace("KEY", &key.display().to_string());
+        let config: Config = toml::from_str(&file).unwrap();
+        let loaded = load_keys(&config);
+        fs::remove_dir_all(&dir).unwrap();
+
+        loaded.map(|(_, trust_store)| trust_store)
+    }
+
+    #[test]
+    fn loads_peers() {
+        let trust_store = load_peers(
+            r#"
+            [[peer]]
+            key = "KEY"
+            kid = "a2"
+            [[peer]]
+            key = "KEY"
+            kid = "a4"
+            recipient_id = "01"
+        "#,
+        )
+        .unwrap();
+        assert_eq!(trust_store.len(), 2);
+        assert_eq!(trust_store.get(&[0xA2]).unwrap().recipient_id, [0xA2]);
+        assert_eq!(trust_store.get(&[0xA4]).unwrap().recipient_id, [0x01]);
+        assert_eq!(trust_store.get(&[0xA4]).unwrap().sender_id, KID);
+    }
 
-        println!("Responding with CoAP packet");
-        println!("Tx({})", res.len());
-        socket.send_to(&res, src).expect("Failed sending");
+    #[test]
+    fn rejects_bad_peers() {
+        // Same kid twice
+        assert!(load_peers(
+            r#"
+            [[peer]]
+            key = "KEY"
+            kid = "a2"
+            [[peer]]
+            key = "KEY"
+            kid = "a2"
+            recipient_id = "01"
+        "#
+        )
+        .is_err());
+        // Recipient ID of another peer
+        assert!(load_peers(
+            r#"
+            [[peer]]
+            key = "KEY"
+            kid = "a2"
+            [[peer]]
+            key = "KEY"
+            kid = "a4"
+            recipient_id = "a2"
+        "#
+        )
+        .is_err());
+        // Our own kid
+        assert!(load_peers(
+            r#"
+            [[peer]]
+            key = "KEY"
+            kid = "a3"
+        "#
+        )
+        .is_err());
+        // Raw key without a kid
+        assert!(load_peers(
+            r#"
+            [[peer]]
+            key = "KEY"
+        "#
+        )
+        .is_err());
+        // Missing key file
+        assert!(load_peers(
+            r#"
+            [[peer]]
+            key = "KEY.missing"
+            kid = "a2"
+        "#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn invalid_args() {
+        assert!(config("", &["--seed", "x"]).is_err());
+        assert!(config("", &["--port", "70000"]).is_err());
+        assert!(config(
+            "",
+            &[
+                "--peer-key",
+                "a.pub",
+                "--peer-kid",
+                "a2",
+                "--peer-kid",
+                "b2"
+            ]
+        )
+        .is_err());
     }
 }
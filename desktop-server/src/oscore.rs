@@ -1,97 +1,923 @@
 //! Protection and unprotection of OSCORE messages.
 
-use coap_lite::{CoapOption, Packet};
-use oscore::oscore::SecurityContext;
+use coap_lite::{CoapOption, Packet, ResponseType};
+use std::{
+    cell::Cell,
+    collections::HashSet,
+    fmt,
+    net::SocketAddr,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Instant,
+};
+use tracing::{debug, error, field, info, info_span, warn, Span};
 
-use crate::{coap::CoapHandler, edhoc::EdhocHandler};
+use crate::{
+    block::BlockHandler,
+    coap::{generate_error, CoapHandler},
+    context::{ContextTable, PeerContext, RekeyPolicy},
+    edhoc::{EdhocHandler, Params},
+    state::{self, StateFile},
+    stats::Stats,
+};
 
 /// Unprotects and protects OSCORE message and invokes `CoapHandler`.
+///
+/// The EDHOC handler, the security contexts and the CoAP handler are locked
+/// separately, so requests can be handled at the same time. Where several
+/// locks are needed, `edhoc` is taken before `contexts`, and `contexts`
+/// before `state`.
 pub struct OscoreHandler {
-    edhoc: EdhocHandler,
+    edhoc: Mutex<EdhocHandler>,
     coap: CoapHandler,
-    oscore: Option<SecurityContext>,
-    sender_id: Vec<u8>,
+    contexts: Mutex<ContextTable>,
+    /// When the security contexts have to be replaced.
+    policy: RekeyPolicy,
+    state: Mutex<Option<StateFile>>,
+    /// Reassembles requests a proxy split up with outer Block1 options and
+    /// splits up large protected responses with outer Block2 options.
+    outer_blocks: Mutex<BlockHandler>,
+    /// The observations registered with OSCORE.
+    observations: Mutex<Vec<Observation>>,
+    stats: Arc<Stats>,
+}
+
+/// An observation registered with an OSCORE request.
+struct Observation {
+    peer: SocketAddr,
+    token: Vec<u8>,
+    /// The recipient ID of the context the registration was protected with.
     recipient_id: Vec<u8>,
+    /// The protected registration, which the notifications are bound to.
+    request: Vec<u8>,
 }
 
 impl OscoreHandler {
     /// Creates a new `OscoreHandler`.
-    pub fn new(
-        edhoc: EdhocHandler,
-        coap: CoapHandler,
-        sender_id: Vec<u8>,
-        recipient_id: Vec<u8>,
-    ) -> OscoreHandler {
+    ///
+    /// The sender and recipient IDs of each security context are the ones
+    /// the trust store of `EdhocHandler` has for the authenticated peer.
+    pub fn new(edhoc: EdhocHandler, coap: CoapHandler) -> OscoreHandler {
         OscoreHandler {
-            edhoc,
+            edhoc: Mutex::new(edhoc),
             coap,
-            oscore: None,
-            sender_id,
-            recipient_id,
+            contexts: Default::default(),
+            policy: RekeyPolicy::default(),
+            state: Default::default(),
+            outer_blocks: Default::default(),
+            observations: Default::default(),
+            stats: Default::default(),
         }
     }
 
+    /// Creates a new `OscoreHandler` that saves its security contexts to the
+    /// state file, starting with the ones already in it.
+    pub fn with_state(
+        edhoc: EdhocHandler,
+        coap: CoapHandler,
+        mut state: StateFile,
+    ) -> Result<OscoreHandler, state::Error> {
+        let contexts = state.load()?;
+        info!("Restored {} security contexts", contexts.len());
+
+        let mut handler = OscoreHandler {
+            contexts: Mutex::new(contexts),
+            state: Mutex::new(Some(state)),
+            ..OscoreHandler::new(edhoc, coap)
+        };
+        // Peers may have been removed while we weren't running
+        handler.retain_trusted();
+
+        Ok(handler)
+    }
+
+    /// Sets the policy deciding when security contexts expire, which is
+    /// the default one otherwise.
+    pub fn set_rekey_policy(&mut self, policy: RekeyPolicy) {
+        self.policy = policy;
+    }
+
+    /// Sets the statistics to count in, which are shared with the
+    /// `EdhocHandler` and `CoapHandler`.
+    pub fn set_stats(&mut self, stats: Arc<Stats>) {
+        self.edhoc_mut().set_stats(stats.clone());
+        self.coap.set_stats(stats.clone());
+        self.stats = stats;
+    }
+
+    /// Returns the `EdhocHandler`, locked, for instance to expire the
+    /// handshakes while requests are being handled.
+    pub fn edhoc(&self) -> MutexGuard<'_, EdhocHandler> {
+        lock(&self.edhoc)
+    }
+
+    /// Returns the `EdhocHandler`, for instance to manage its trust store.
+    pub fn edhoc_mut(&mut self) -> &mut EdhocHandler {
+        self.edhoc.get_mut().expect("EDHOC handler poisoned")
+    }
+
+    /// Returns the `CoapHandler`, for instance to check for observations.
+    pub fn coap(&self) -> &CoapHandler {
+        &self.coap
+    }
+
+    /// Returns the `CoapHandler`, for instance to change the access
+    /// policies of its resources.
+    pub fn coap_mut(&mut self) -> &mut CoapHandler {
+        &mut self.coap
+    }
+
+    /// Returns the table of established security contexts, locked.
+    pub fn contexts(&self) -> MutexGuard<'_, ContextTable> {
+        lock(&self.contexts)
+    }
+
+    /// Returns the table of established security contexts mutably, for
+    /// instance to evict one.
+    pub fn contexts_mut(&mut self) -> &mut ContextTable {
+        self.contexts.get_mut().expect("Security contexts poisoned")
+    }
+
     /// Unprotects an OSCORE message if it is one, passes the CoAP to the
     /// `CoapHandler` and protects the response if necessary.
-    pub fn handle(&mut self, req_bytes: &[u8]) -> Option<Vec<u8>> {
-        let mut req =
-            Packet::from_bytes(req_bytes).expect("Unable to parse CoAP");
-        let mut is_oscore = false;
-
-        // Check if the request is OSCORE and we are ready to deal with it
-        if req.get_option(CoapOption::Oscore).is_some()
-            && self.oscore.is_some()
+    ///
+    /// On failure, the response to send (if any) is part of the `Failure`.
+    pub fn handle(
+        &self,
+        peer: SocketAddr,
+        req_bytes: &[u8],
+    ) -> Result<Option<Vec<u8>>, Failure> {
+        // Everything logged for this exchange carries what identifies it
+        let span = info_span!(
+            "exchange",
+            %peer,
+            token = field::Empty,
+            kid = field::Empty,
+            piv = field::Empty
+        );
+        let _exchange = span.enter();
+        lock(&self.contexts).expire_retired();
+
+        // Without a CoAP message there is nobody to answer to
+        let req = match Packet::from_bytes(req_bytes) {
+            Ok(req) => req,
+            Err(_) => {
+                self.stats.decode_errors.increment();
+                return Err(Failure {
+                    error: Error::Parse,
+                    response: None,
+                });
+            }
+        };
+        span.record("token", &field::display(hex::encode(req.get_token())));
+
+        // A proxy may have split up the protected request with outer Block1
+        // options, in which case we need all of it before unprotecting, or
+        // ask for a later block of a protected response with outer Block2
+        let outer = match req.get_option(CoapOption::Oscore) {
+            Some(_) => {
+                let mut blocks = lock(&self.outer_blocks);
+                match blocks.handle_request(peer, req.clone()) {
+                    Ok(req) => Some(req),
+                    Err(res) => return Ok(res.to_bytes().ok()),
+                }
+            }
+            None => None,
+        };
+        let (req, assembled) = match &outer {
+            Some(outer) if outer.get_option(CoapOption::Block1).is_some() => {
+                let mut req = outer.clone();
+                req.clear_option(CoapOption::Block1);
+                let bytes = req.to_bytes().map_err(|_| Failure {
+                    error: Error::Parse,
+                    response: None,
+                })?;
+                (req, Some(bytes))
+            }
+            _ => (req, None),
+        };
+        let req_bytes = assembled.as_deref().unwrap_or(req_bytes);
+
+        let mut result = self.process(peer, req_bytes, req.clone());
+        if let (Ok(Some(res)), Some(outer)) = (&result, &outer) {
+            // Confirm the last block and split up a large response outside
+            // of the protected one
+            result = self.outer_blocks(peer, outer, res).map(Some);
+        }
+        // Make sure the state file is current before anything goes out
+        self.save_state(&lock(&self.contexts));
+
+        result.map_err(|error| {
+            match error {
+                Error::Parse | Error::InvalidOption | Error::InnerParse => {
+                    self.stats.decode_errors.increment()
+                }
+                Error::Unprotect(oscore::oscore::Error::ReplayDetected) => {
+                    self.stats.replays_rejected.increment()
+                }
+                _ => (),
+            }
+            // Reply to the outer message, since we don't trust the inner one
+            let response = error.response_code().and_then(|code| {
+                let mut res = generate_error(&req, code, &error.to_string());
+                // Error responses must not be cached
+                res.add_option(CoapOption::MaxAge, vec![]);
+                res.to_bytes().ok()
+            });
+            Failure { error, response }
+        })
+    }
+
+    /// Returns the notifications for the observers of all resources that
+    /// changed, protected with a fresh Partial IV if the observation was
+    /// registered with OSCORE.
+    pub fn notifications(&self) -> Vec<(SocketAddr, Vec<u8>)> {
+        let notifications = self.coap.notifications();
+        // Forget the observations that ended in the meantime
+        lock(&self.observations)
+            .retain(|o| self.coap.is_observing(o.peer, &o.token));
+
+        let mut protected = Vec::new();
+        for notification in notifications {
+            let peer = notification.peer;
+            let token = notification.token;
+            let bytes = match notification.packet.to_bytes() {
+                Ok(bytes) => bytes,
+                Err(_) => {
+                    error!("Error building notification bytes");
+                    continue;
+                }
+            };
+            match self.protect_notification(peer, &token, &bytes) {
+                Ok(bytes) => protected.push((peer, bytes)),
+                Err(e) => {
+                    // Without a way to protect them, the observation is over
+                    warn!(%peer, "Failed protecting notification: {}", e);
+                    self.coap.cancel(peer, &token);
+                    lock(&self.observations)
+                        .retain(|o| o.peer != peer || o.token != token);
+                }
+            }
+        }
+
+        protected
+    }
+
+    /// Ends all observations of the given peer, like when its connection
+    /// closed.
+    pub fn cancel_all(&self, peer: SocketAddr) {
+        self.coap.cancel_all(peer);
+        lock(&self.observations).retain(|o| o.peer != peer);
+    }
+
+    /// Saves the security contexts as they are, since they won't be used
+    /// anymore.
+    pub fn close_state(&self) {
+        let contexts = lock(&self.contexts);
+        if let Some(state) = lock(&self.state).as_mut() {
+            if let Err(e) = state.close(&contexts) {
+                error!("Failed saving security contexts: {}", e);
+            }
+        }
+    }
+
+    /// Drops the security contexts whose recipient ID the predicate
+    /// rejects, like those of peers that are no longer trusted, and saves
+    /// the state without them. Their observations end too.
+    pub fn retain_contexts<F>(&mut self, mut keep: F)
+    where
+        F: FnMut(&[u8]) -> bool,
+    {
+        let contexts =
+            self.contexts.get_mut().expect("Security contexts poisoned");
+        contexts.retain(&mut keep);
+        let coap = &self.coap;
+        lock(&self.observations).retain(|o| {
+            let kept = keep(&o.recipient_id);
+            if !kept {
+                coap.cancel(o.peer, &o.token);
+            }
+            kept
+        });
+        if let Some(state) = lock(&self.state).as_mut() {
+            if let Err(e) = state.save(contexts) {
+                error!("Failed saving security contexts: {}", e);
+            }
+        }
+    }
+
+    /// Drops the security contexts of the peers that aren't in the trust
+    /// store of the `EdhocHandler`, like after it was replaced.
+    pub fn retain_trusted(&mut self) {
+        let trust_store = self.edhoc_mut().trust_store_mut();
+        let trusted: HashSet<Vec<u8>> = trust_store
+            .kids()
+            .filter_map(|kid| trust_store.get(kid))
+            .map(|peer| peer.recipient_id.clone())
+            .collect();
+        let before = self.contexts_mut().len();
+        self.retain_contexts(|recipient_id| trusted.contains(recipient_id));
+        let dropped = before - self.contexts_mut().len();
+        if dropped > 0 {
+            info!("Dropped {} security contexts of untrusted peers", dropped);
+        }
+    }
+
+    /// Protects a notification if its observation was registered with
+    /// OSCORE, or returns it as it is otherwise.
+    fn protect_notification(
+        &self,
+        peer: SocketAddr,
+        token: &[u8],
+        notification: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        let (recipient_id, request) = match lock(&self.observations)
+            .iter()
+            .find(|o| o.peer == peer && o.token[..] == token[..])
         {
-            println!("Unprotecting OSCORE request");
-            is_oscore = true;
-            // Temporarily take the oscore context
-            let mut oscore = self.oscore.take().unwrap();
-            // Unprotect the request and replace the original with it
-            req = Packet::from_bytes(
-                &oscore
-                    .unprotect_request(req_bytes)
-                    .expect("Failed unprotecting request"),
-            )
-            .expect("Unable to parse unprotected request");
-            // Put the context back in
-            self.oscore = Some(oscore);
-        }
-
-        // Use CoAP handler to deal with it
-        let mut res = self
-            .coap
-            .handle(&mut self.edhoc, req)?
-            .to_bytes()
-            .expect("Error building CoAP bytes");
-
-        // Check if EDHOC has advanced
-        if let Some((master_secret, master_salt)) = self.edhoc.take_params() {
-            // Since EDHOC is done, we can initialize OSCORE
-            self.oscore = Some(
-                SecurityContext::new(
-                    master_secret,
-                    master_salt,
-                    self.sender_id.clone(),
-                    self.recipient_id.clone(),
-                )
-                .expect("Failed intializing OSCORE"),
-            );
+            Some(o) => (o.recipient_id.clone(), o.request.clone()),
+            None => return Ok(notification.to_vec()),
+        };
+        let mut contexts = lock(&self.contexts);
+        // We're about to use a fresh sequence number, which has to be
+        // reserved in the state file first
+        self.save_state(&contexts);
+        let context =
+            contexts.get_mut(&recipient_id).ok_or(Error::NoContext)?;
+        if context.is_expired(&self.policy) {
+            return Err(Error::Expired);
+        }
+        let protected = context
+            .protect_response(notification, &request, false)
+            .map_err(Error::Protect)?;
+
+        copy_outer_observe(notification, &protected)
+    }
+
+    /// Adds the outer Block1 option confirming the last block of a request
+    /// to the protected response, and sends only the first block of it
+    /// with an outer Block2 option if it's too large.
+    fn outer_blocks(
+        &self,
+        peer: SocketAddr,
+        req: &Packet,
+        res: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        let res = Packet::from_bytes(res).map_err(|_| Error::Build)?;
+        let res = lock(&self.outer_blocks).handle_response(peer, req, res);
+
+        res.to_bytes().map_err(|_| Error::Build)
+    }
+
+    /// Saves the security contexts if the state file is no longer current.
+    fn save_state(&self, contexts: &ContextTable) {
+        if let Some(state) = lock(&self.state).as_mut() {
+            if state.is_stale(contexts) {
+                if let Err(e) = state.save(contexts) {
+                    error!("Failed saving security contexts: {}", e);
+                }
+            }
         }
+    }
+
+    /// Does the actual work of `handle` for a parsed request.
+    fn process(
+        &self,
+        peer: SocketAddr,
+        req_bytes: &[u8],
+        mut req: Packet,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        // The recipient ID and master secret of the context used, if the
+        // request is OSCORE
+        let mut used = None;
+
+        // Check if the request is OSCORE
+        if let Some(option) = req.get_option(CoapOption::Oscore) {
+            let value = option.front().ok_or(Error::InvalidOption)?;
+            let kid = extract_kid(value).ok_or(Error::InvalidOption)?.to_vec();
+            let piv = extract_piv(value).ok_or(Error::InvalidOption)?;
+            Span::current()
+                .record("kid", &field::display(hex::encode(&kid)))
+                .record("piv", &piv);
+            let mut contexts = lock(&self.contexts);
+            let context = contexts.get_mut(&kid).ok_or(Error::NoContext)?;
+            if context.is_expired(&self.policy) {
+                // The peer has to do EDHOC again for a fresh one
+                contexts.remove(&kid);
+                return Err(Error::Expired);
+            }
+            debug!("Unprotecting OSCORE request");
+            // Unprotect the request and replace the original with it. If
+            // the peer just re-keyed, it may still be protected with the
+            // context we replaced.
+            let (unprotected, secret) =
+                match context.unprotect_request(req_bytes, piv) {
+                    Ok(unprotected) => {
+                        (unprotected, context.master_secret().to_vec())
+                    }
+                    Err(e) => match contexts.get_retired_mut(&kid) {
+                        Some(old) => (
+                            old.unprotect_request(req_bytes, piv)
+                                .map_err(|_| Error::Unprotect(e))?,
+                            old.master_secret().to_vec(),
+                        ),
+                        None => return Err(Error::Unprotect(e)),
+                    },
+                };
+            drop(contexts);
+            req = Packet::from_bytes(&unprotected)
+                .map_err(|_| Error::InnerParse)?;
+            self.stats.requests_protected.increment();
+            used = Some((kid, secret));
+        } else {
+            self.stats.requests_unprotected.increment();
+        }
+
+        // Use CoAP handler to deal with it, which passes EDHOC messages back
+        // to us. A failure there is ours, not the peer's, so it's not an
+        // EDHOC error message but an error response.
+        let failure = Cell::new(None);
+        let edhoc = |peer, msg| match self.handle_edhoc(peer, msg) {
+            Ok(reply) => reply,
+            Err(e) => {
+                failure.set(Some(e));
+                None
+            }
+        };
+        let res = self.coap.handle(&edhoc, peer, req, used.is_some());
+        if let Some(e) = failure.into_inner() {
+            return Err(e);
+        }
+        let res = match res {
+            Some(res) => res,
+            None => return Ok(None),
+        };
+        if res.get_option(CoapOption::Observe).is_some() {
+            // Remember the registration, since the notifications have to be
+            // protected with the same context and request
+            let token = res.get_token().clone();
+            let mut observations = lock(&self.observations);
+            observations.retain(|o| o.peer != peer || o.token != token);
+            if let Some((recipient_id, _)) = used.as_ref() {
+                observations.push(Observation {
+                    peer,
+                    token,
+                    recipient_id: recipient_id.clone(),
+                    request: req_bytes.to_vec(),
+                });
+            }
+        }
+        let mut res = res.to_bytes().map_err(|_| Error::Build)?;
 
         // If the exchange is protected with OSCORE, protect the response
-        if is_oscore {
-            println!("Protecting OSCORE response");
-            // Temporarily take the oscore context
-            let mut oscore = self.oscore.take().unwrap();
+        // with the same context
+        if let Some((kid, secret)) = used {
+            debug!("Protecting OSCORE response");
+            let mut contexts = lock(&self.contexts);
+            // The context may have been replaced in the meantime
+            let current = match contexts.get(&kid) {
+                Some(context) => context.master_secret() == &secret[..],
+                None => false,
+            };
+            let context = if current {
+                contexts.get_mut(&kid)
+            } else {
+                contexts.get_retired_mut(&kid)
+            }
+            .ok_or(Error::NoContext)?;
             // Protect the response and replace the original with it
-            res = oscore
+            res = context
                 .protect_response(&res, req_bytes, true)
-                .expect("Failed protecting response");
-            // Put the context back in
-            self.oscore = Some(oscore);
+                .map_err(Error::Protect)?;
         }
 
         // Return the bytes of the CoAP response packet
-        Some(res)
+        Ok(Some(res))
+    }
+
+    /// Handles an EDHOC message for the `CoapHandler` and returns the reply.
+    ///
+    /// The `EdhocHandler` stays locked until the security context of a
+    /// completed handshake is in place, so the peer can use it as soon as
+    /// it gets the reply.
+    fn handle_edhoc(
+        &self,
+        peer: SocketAddr,
+        msg: Vec<u8>,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let mut edhoc = lock(&self.edhoc);
+        let reply = edhoc.handle(peer, msg);
+        if let Some(params) = edhoc.take_params() {
+            self.install(params)?;
+        }
+
+        Ok(reply)
+    }
+
+    /// Puts the security context of a completed handshake in place.
+    fn install(&self, params: Params) -> Result<(), Error> {
+        // Since EDHOC is done, we can initialize OSCORE for this peer. If
+        // the peer already had a context, it's replaced.
+        let context = PeerContext::new(
+            params.master_secret,
+            params.master_salt,
+            params.sender_id,
+            params.recipient_id,
+        )
+        .map_err(Error::Context)?;
+        // Observations with the old context end with it
+        let mut ended = Vec::new();
+        lock(&self.observations).retain(|o| {
+            if o.recipient_id == context.recipient_id() {
+                ended.push((o.peer, o.token.clone()));
+                false
+            } else {
+                true
+            }
+        });
+        for (peer, token) in ended {
+            self.coap.cancel(peer, &token);
+        }
+        // The replaced one is still good for requests already on their way
+        let mut contexts = lock(&self.contexts);
+        if let Some(old) = contexts.insert(context) {
+            info!("Replaced a security context, keeping the old one");
+            contexts.retire(old, Instant::now() + self.policy.grace);
+        }
+        info!("Now holding {} security contexts", contexts.len());
+
+        Ok(())
+    }
+}
+
+/// The ways in which handling a message can fail.
+#[derive(Debug)]
+pub enum Error {
+    /// The datagram is not a CoAP message.
+    Parse,
+    /// The OSCORE option lacks the kid or Partial IV.
+    InvalidOption,
+    /// There is no security context for the kid.
+    NoContext,
+    /// The security context ran out of sequence numbers or is too old.
+    Expired,
+    /// The request couldn't be unprotected.
+    Unprotect(oscore::oscore::Error),
+    /// The unprotected request is not a CoAP message.
+    InnerParse,
+    /// The response couldn't be serialized.
+    Build,
+    /// The security context couldn't be derived after EDHOC.
+    Context(oscore::oscore::Error),
+    /// The response couldn't be protected.
+    Protect(oscore::oscore::Error),
+}
+
+impl Error {
+    /// Returns the code of the error response as defined in RFC 8613, or
+    /// `None` if the message should be dropped silently.
+    pub fn response_code(&self) -> Option<ResponseType> {
+        match self {
+            Error::Parse => None,
+            Error::InvalidOption | Error::InnerParse => {
+                Some(ResponseType::BadRequest)
+            }
+            Error::NoContext
+            | Error::Expired
+            | Error::Unprotect(oscore::oscore::Error::ReplayDetected) => {
+                Some(ResponseType::Unauthorized)
+            }
+            Error::Unprotect(_) => Some(ResponseType::BadRequest),
+            Error::Build | Error::Context(_) | Error::Protect(_) => {
+                Some(ResponseType::InternalServerError)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Parse => write!(f, "Unable to parse CoAP"),
+            Error::InvalidOption => {
+                write!(f, "OSCORE option lacks kid or Partial IV")
+            }
+            Error::NoContext => write!(f, "Security context not found"),
+            Error::Expired => write!(f, "Security context expired"),
+            Error::Unprotect(oscore::oscore::Error::ReplayDetected) => {
+                write!(f, "Replay detected")
+            }
+            Error::Unprotect(e) => {
+                write!(f, "Failed unprotecting request: {:?}", e)
+            }
+            Error::InnerParse => {
+                write!(f, "Unable to parse unprotected request")
+            }
+            Error::Build => write!(f, "Error building CoAP bytes"),
+            Error::Context(e) => {
+                write!(f, "Failed initializing OSCORE: {:?}", e)
+            }
+            Error::Protect(e) => {
+                write!(f, "Failed protecting response: {:?}", e)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// A message that couldn't be handled.
+#[derive(Debug)]
+pub struct Failure {
+    /// What went wrong.
+    pub error: Error,
+    /// The error response to send, if there is one.
+    pub response: Option<Vec<u8>>,
+}
+
+/// Gives the protected notification an outer Observe option with the value
+/// of the inner one, for the benefit of proxies (RFC 8613 Section 4.1.3.5).
+fn copy_outer_observe(
+    notification: &[u8],
+    protected: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let inner = Packet::from_bytes(notification).map_err(|_| Error::Build)?;
+    let mut outer = Packet::from_bytes(protected).map_err(|_| Error::Build)?;
+    if outer.get_option(CoapOption::Observe).is_some() {
+        return Ok(protected.to_vec());
+    }
+    if let Some(value) = inner
+        .get_option(CoapOption::Observe)
+        .and_then(|values| values.front())
+    {
+        outer.add_option(CoapOption::Observe, value.clone());
+    }
+
+    outer.to_bytes().map_err(|_| Error::Build)
+}
+
+/// Locks one of the parts of `OscoreHandler`.
+fn lock<T>(part: &Mutex<T>) -> MutexGuard<'_, T> {
+    part.lock().expect("OSCORE handler poisoned")
+}
+
+/// Returns the kid from the value of an OSCORE option, if it has one.
+fn extract_kid(option: &[u8]) -> Option<&[u8]> {
+    // The first byte holds the flags, an empty option has none of them set
+    let flags = *option.first()?;
+    // The lowest three bits are the length of the Partial IV
+    let mut pos = 1 + (flags & 0x07) as usize;
+    // Skip the kid context, which is prefixed with its length
+    if flags & 0x10 != 0 {
+        pos += 1 + *option.get(pos)? as usize;
+    }
+    // Whatever remains is the kid, if its flag is set
+    if flags & 0x08 != 0 {
+        option.get(pos..)
+    } else {
+        None
+    }
+}
+
+/// Returns the Partial IV from the value of an OSCORE option, if it has one.
+fn extract_piv(option: &[u8]) -> Option<u64> {
+    let flags = *option.first()?;
+    // The lowest three bits are the length of the Partial IV, which can't be
+    // more than 5 bytes
+    let len = (flags & 0x07) as usize;
+    if len == 0 || len > 5 {
+        return None;
+    }
+
+    Some(
+        option
+            .get(1..=len)?
+            .iter()
+            .fold(0, |piv, &b| piv << 8 | u64::from(b)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        keys,
+        trust::{Peer, TrustStore},
+    };
+    use coap_lite::{MessageClass, MessageType, RequestType};
+    use oscore::oscore::SecurityContext;
+    use std::{fs, time::Duration};
+
+    const SERVER_PRIV: [u8; 32] = [1; 32];
+
+    /// Returns an `EdhocHandler` trusting only the peer with kid 0xA2, whose
+    /// recipient ID is the same.
+    fn edhoc() -> EdhocHandler {
+        let mut trust_store = TrustStore::new();
+        let peer = Peer {
+            public: keys::derive_public(&[2; 32]).unwrap(),
+            sender_id: vec![0xA3],
+            recipient_id: vec![0xA2],
+        };
+        assert!(trust_store.insert(vec![0xA2], peer).is_ok());
+
+        EdhocHandler::new(
+            SERVER_PRIV,
+            keys::derive_public(&SERVER_PRIV).unwrap(),
+            vec![0xA3],
+            trust_store,
+        )
+    }
+
+    fn context(recipient_id: u8) -> PeerContext {
+        PeerContext::new(vec![0; 16], vec![], vec![0xA3], vec![recipient_id])
+            .unwrap()
+    }
+
+    fn peer() -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], 5683))
+    }
+
+    /// Returns the client's side of `context`, with the master secret.
+    fn client(secret: u8) -> SecurityContext {
+        SecurityContext::new(vec![secret; 16], vec![], vec![0xA2], vec![0xA3])
+            .unwrap()
+    }
+
+    /// Returns a GET /hello protected with the client's context.
+    fn hello(client: &mut SecurityContext) -> Vec<u8> {
+        let mut req = Packet::new();
+        req.header.set_type(MessageType::Confirmable);
+        req.header.code = MessageClass::Request(RequestType::Get);
+        req.header.message_id = 1;
+        req.set_token(vec![1]);
+        req.add_option(CoapOption::UriPath, b"hello".to_vec());
+        client.protect_request(&req.to_bytes().unwrap()).unwrap()
+    }
+
+    /// Returns a handler with a fresh context for the client, and the one it
+    /// replaced retired until `until`.
+    fn rekeyed(until: Instant) -> OscoreHandler {
+        let mut handler = OscoreHandler::new(edhoc(), CoapHandler::new());
+        let contexts = handler.contexts_mut();
+        let fresh =
+            PeerContext::new(vec![2; 16], vec![], vec![0xA3], vec![0xA2]);
+        contexts.insert(fresh.unwrap());
+        let old =
+            PeerContext::new(vec![1; 16], vec![], vec![0xA3], vec![0xA2]);
+        contexts.retire(old.unwrap(), until);
+        handler
+    }
+
+    #[test]
+    fn answers_under_retired_context() {
+        let handler = rekeyed(Instant::now() + Duration::from_secs(60));
+        let mut old = client(1);
+        let res = match handler.handle(peer(), &hello(&mut old)) {
+            Ok(Some(res)) => res,
+            _ => panic!("No response"),
+        };
+        // Protected with the context the request was
+        let res = Packet::from_bytes(&old.unprotect_response(&res).unwrap());
+        assert_eq!(
+            res.unwrap().header.code,
+            MessageClass::Response(ResponseType::Content)
+        );
+
+        // The fresh one works too, of course
+        let mut fresh = client(2);
+        match handler.handle(peer(), &hello(&mut fresh)) {
+            Ok(Some(res)) => assert!(fresh.unprotect_response(&res).is_ok()),
+            _ => panic!("No response"),
+        }
+    }
+
+    #[test]
+    fn refuses_retired_context_after_grace() {
+        let handler = rekeyed(Instant::now());
+        match handler.handle(peer(), &hello(&mut client(1))) {
+            Err(Failure {
+                error: Error::Unprotect(_),
+                response: Some(_),
+            }) => {}
+            _ => panic!("Request under expired context accepted"),
+        }
+    }
+
+    #[test]
+    fn response_codes() {
+        use oscore::oscore::Error::ReplayDetected;
+        let unauthorized = Some(ResponseType::Unauthorized);
+        let bad_request = Some(ResponseType::BadRequest);
+        let internal = Some(ResponseType::InternalServerError);
+        let table = [
+            (Error::Parse, None),
+            (Error::InvalidOption, bad_request),
+            (Error::NoContext, unauthorized),
+            (Error::Expired, unauthorized),
+            (Error::Unprotect(ReplayDetected), unauthorized),
+            (Error::InnerParse, bad_request),
+            (Error::Build, internal),
+            (Error::Context(ReplayDetected), internal),
+            (Error::Protect(ReplayDetected), internal),
+        ];
+        for (error, code) in table.iter() {
+            assert_eq!(error.response_code(), *code, "{}", error);
+        }
+    }
+
+    /// Returns the error and code of the response for a request the handler
+    /// fails on, checking that the response must not be cached.
+    fn failure(
+        handler: &OscoreHandler,
+        req: &[u8],
+    ) -> (Error, Option<MessageClass>) {
+        let failure = match handler.handle(peer(), req) {
+            Ok(_) => panic!("Request didn't fail"),
+            Err(failure) => failure,
+        };
+        let code = failure.response.map(|res| {
+            let res = Packet::from_bytes(&res).unwrap();
+            let max_age = res.get_option(CoapOption::MaxAge).unwrap();
+            // An empty value is 0
+            assert_eq!(max_age.front(), Some(&vec![]));
+            res.header.code
+        });
+
+        (failure.error, code)
+    }
+
+    #[test]
+    fn error_responses() {
+        let handler = rekeyed(Instant::now());
+        let code = |code| Some(MessageClass::Response(code));
+
+        // Garbage doesn't get an answer
+        assert!(matches!(failure(&handler, &[0xFF]), (Error::Parse, None)));
+        // Neither kid nor Partial IV
+        let mut req = Packet::new();
+        req.set_token(vec![1]);
+        req.add_option(CoapOption::Oscore, vec![]);
+        let (error, res) = failure(&handler, &req.to_bytes().unwrap());
+        assert!(matches!(error, Error::InvalidOption));
+        assert_eq!(res, code(ResponseType::BadRequest));
+        // A context we don't have
+        let mut unknown =
+            SecurityContext::new(vec![2; 16], vec![], vec![0xEE], vec![0xA3])
+                .unwrap();
+        let (error, res) = failure(&handler, &hello(&mut unknown));
+        assert!(matches!(error, Error::NoContext));
+        assert_eq!(res, code(ResponseType::Unauthorized));
+        // The retired context, after its grace period
+        let (error, res) = failure(&handler, &hello(&mut client(1)));
+        assert!(matches!(error, Error::Unprotect(_)));
+        assert_eq!(res, code(ResponseType::BadRequest));
+        // A replay
+        let req = hello(&mut client(2));
+        assert!(handler.handle(peer(), &req).is_ok());
+        let (error, res) = failure(&handler, &req);
+        assert!(matches!(
+            error,
+            Error::Unprotect(oscore::oscore::Error::ReplayDetected)
+        ));
+        assert_eq!(res, code(ResponseType::Unauthorized));
+    }
+
+    #[test]
+    fn expired_context() {
+        let mut handler = rekeyed(Instant::now());
+        handler.set_rekey_policy(RekeyPolicy {
+            max_seq: 0,
+            ..Default::default()
+        });
+        let (error, res) = failure(&handler, &hello(&mut client(2)));
+        assert!(matches!(error, Error::Expired));
+        assert_eq!(
+            res,
+            Some(MessageClass::Response(ResponseType::Unauthorized))
+        );
+        // It's gone, the peer has to do EDHOC again
+        assert!(handler.contexts().get(&[0xA2]).is_none());
+    }
+
+    #[test]
+    fn drops_untrusted_on_start() {
+        let path = std::env::temp_dir()
+            .join(format!("oscore-test-{}.cbor", std::process::id()));
+        let mut table = ContextTable::new();
+        table.insert(context(0xA2));
+        table.insert(context(0xB));
+        StateFile::new(&path).save(&table).unwrap();
+
+        let handler = OscoreHandler::with_state(
+            edhoc(),
+            CoapHandler::new(),
+            StateFile::new(&path),
+        )
+        .unwrap();
+        assert!(handler.contexts().get(&[0xA2]).is_some());
+        assert!(handler.contexts().get(&[0xB]).is_none());
+        // And it's gone from the file too
+        assert_eq!(StateFile::new(&path).load().unwrap().len(), 1);
+        fs::remove_file(&path).unwrap();
     }
 }
@@ -0,0 +1,571 @@
+//! The resources served by the desktop server.
+
+use coap_lite::{CoapOption, ContentFormat, Packet, ResponseType};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    time::Instant,
+};
+
+use crate::{
+    coap::{generate_error, generate_response, generate_status},
+    router::{Attributes, Changed, Request, Resource},
+};
+
+/// Responds with a friendly greeting.
+pub struct Hello;
+
+impl Resource for Hello {
+    fn attributes(&self) -> Attributes {
+        Attributes {
+            rt: Some("test"),
+            ct: Some(0),
+            ..Default::default()
+        }
+    }
+
+    fn get(&mut self, req: &Request) -> Packet {
+        generate_response(
+            req.packet,
+            b"Hello, world!".to_vec(),
+            ContentFormat::TextPlain,
+        )
+    }
+}
+
+/// Responds with the payload of the request.
+pub struct Echo;
+
+impl Echo {
+    /// Returns the payload of the request.
+    fn echo(req: &Request) -> Packet {
+        generate_response(
+            req.packet,
+            req.packet.payload.clone(),
+            ContentFormat::ApplicationOctetStream,
+        )
+    }
+}
+
+impl Resource for Echo {
+    fn attributes(&self) -> Attributes {
+        Attributes {
+            rt: Some("echo"),
+            ct: Some(42),
+            ..Default::default()
+        }
+    }
+
+    fn get(&mut self, req: &Request) -> Packet {
+        Echo::echo(req)
+    }
+
+    fn post(&mut self, req: &Request) -> Packet {
+        Echo::echo(req)
+    }
+
+    fn put(&mut self, req: &Request) -> Packet {
+        Echo::echo(req)
+    }
+}
+
+/// Responds with the number of seconds since the server started, and can be
+/// observed to get it every second.
+pub struct Uptime {
+    started: Instant,
+    /// The uptime the observers were last notified of.
+    notified: u64,
+}
+
+impl Default for Uptime {
+    fn default() -> Uptime {
+        Uptime {
+            started: Instant::now(),
+            notified: 0,
+        }
+    }
+}
+
+impl Uptime {
+    /// Creates a new `Uptime`, counting from now.
+    pub fn new() -> Uptime {
+        Default::default()
+    }
+
+    /// Returns the number of whole seconds since we started.
+    fn seconds(&self) -> u64 {
+        self.started.elapsed().as_secs()
+    }
+}
+
+impl Resource for Uptime {
+    fn attributes(&self) -> Attributes {
+        Attributes {
+            rt: Some("uptime"),
+            ct: Some(0),
+            obs: true,
+            ..Default::default()
+        }
+    }
+
+    fn get(&mut self, req: &Request) -> Packet {
+        generate_response(
+            req.packet,
+            self.seconds().to_string().into_bytes(),
+            ContentFormat::TextPlain,
+        )
+    }
+
+    fn poll_changed(&mut self) -> Changed {
+        let seconds = self.seconds();
+        if seconds == self.notified {
+            return Changed::Nothing;
+        }
+        self.notified = seconds;
+
+        Changed::Everything
+    }
+}
+
+/// The most keys `Store` holds.
+const MAX_ENTRIES: usize = 64;
+/// The largest value `Store` accepts.
+const MAX_VALUE_SIZE: usize = 1024;
+
+/// A value in the `Store`.
+struct Entry {
+    value: Vec<u8>,
+    /// The raw Content-Format option it was stored with, if any.
+    content_format: Option<Vec<u8>>,
+    /// Identifies this version of the value, changes with every write.
+    etag: Vec<u8>,
+}
+
+/// A key-value store, with a resource for every key at `/store/{key}`.
+///
+/// PUT creates or replaces a value, POST appends to it (creating it if
+/// necessary) and DELETE removes it. Writes can be made conditional with
+/// If-Match and If-None-Match, and GET answers with 2.03 (Valid) if the
+/// request has the ETag of the current value. Observers of a key are
+/// notified when it changes.
+#[derive(Default)]
+pub struct Store {
+    entries: BTreeMap<Vec<u8>, Entry>,
+    /// The number of writes so far, which the ETags are made from.
+    version: u64,
+    /// The keys written since the observers were last notified.
+    changed: BTreeSet<Vec<u8>>,
+}
+
+impl Store {
+    /// Creates a new, empty `Store`.
+    pub fn new() -> Store {
+        Default::default()
+    }
+
+    /// Returns the key of the request, which is the segment matched by the
+    /// wildcard of its route.
+    fn key(req: &Request) -> Vec<u8> {
+        req.wildcards
+            .first()
+            .map(|k| k.to_vec())
+            .unwrap_or_default()
+    }
+
+    /// Checks the If-Match and If-None-Match options of the request against
+    /// the current value, returning 4.12 (Precondition Failed) if they
+    /// don't hold.
+    fn check_preconditions(
+        &self,
+        req: &Request,
+        key: &[u8],
+    ) -> Result<(), Packet> {
+        let entry = self.entries.get(key);
+        // An empty If-Match only requires the value to exist
+        let if_match = req.packet.get_option(CoapOption::IfMatch);
+        let matched = match (if_match, entry) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some(etags), Some(entry)) => etags
+                .iter()
+                .any(|etag| etag.is_empty() || *etag == entry.etag),
+        };
+        let if_none_match =
+            req.packet.get_option(CoapOption::IfNoneMatch).is_some();
+        if !matched || (if_none_match && entry.is_some()) {
+            return Err(generate_error(
+                req.packet,
+                ResponseType::PreconditionFailed,
+                "Precondition failed",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Stores the value under the key with a fresh ETag and returns 2.01
+    /// (Created) or 2.04 (Changed) with it.
+    fn write(
+        &mut self,
+        req: &Request,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Packet {
+        if value.len() > MAX_VALUE_SIZE {
+            return generate_error(
+                req.packet,
+                ResponseType::RequestEntityTooLarge,
+                "Value too large",
+            );
+        }
+        let exists = self.entries.contains_key(&key);
+        if !exists && self.entries.len() >= MAX_ENTRIES {
+            return generate_error(
+                req.packet,
+                ResponseType::ServiceUnavailable,
+                "Store full",
+            );
+        }
+
+        self.version += 1;
+        let etag = uint_bytes(self.version);
+        let content_format = req
+            .packet
+            .get_option(CoapOption::ContentFormat)
+            .and_then(|values| values.front())
+            .cloned();
+        self.changed.insert(key.clone());
+        self.entries.insert(
+            key,
+            Entry {
+                value,
+                content_format,
+                etag: etag.clone(),
+            },
+        );
+
+        let code = if exists {
+            ResponseType::Changed
+        } else {
+            ResponseType::Created
+        };
+        let mut res = generate_status(req.packet, code);
+        res.add_option(CoapOption::ETag, etag);
+
+        res
+    }
+}
+
+impl Resource for Store {
+    fn attributes(&self) -> Attributes {
+        Attributes {
+            rt: Some("store"),
+            obs: true,
+            ..Default::default()
+        }
+    }
+
+    fn get(&mut self, req: &Request) -> Packet {
+        let entry = match self.entries.get(&Store::key(req)) {
+            Some(entry) => entry,
+            None => {
+                return generate_error(
+                    req.packet,
+                    ResponseType::NotFound,
+                    "Not found",
+                )
+            }
+        };
+
+        // The peer already has the current value if it knows the ETag
+        let valid = match req.packet.get_option(CoapOption::ETag) {
+            Some(etags) => etags.iter().any(|e| *e == entry.etag),
+            None => false,
+        };
+        let mut res = if valid {
+            generate_status(req.packet, ResponseType::Valid)
+        } else {
+            let mut res = generate_status(req.packet, ResponseType::Content);
+            if let Some(content_format) = &entry.content_format {
+                res.add_option(
+                    CoapOption::ContentFormat,
+                    content_format.clone(),
+                );
+            }
+            res.payload = entry.value.clone();
+            res
+        };
+        res.add_option(CoapOption::ETag, entry.etag.clone());
+
+        res
+    }
+
+    fn put(&mut self, req: &Request) -> Packet {
+        let key = Store::key(req);
+        if let Err(res) = self.check_preconditions(req, &key) {
+            return res;
+        }
+
+        self.write(req, key, req.packet.payload.clone())
+    }
+
+    fn post(&mut self, req: &Request) -> Packet {
+        let key = Store::key(req);
+        if let Err(res) = self.check_preconditions(req, &key) {
+            return res;
+        }
+
+        let mut value = self
+            .entries
+            .get(&key)
+            .map(|entry| entry.value.clone())
+            .unwrap_or_default();
+        value.extend(&req.packet.payload);
+        self.write(req, key, value)
+    }
+
+    fn delete(&mut self, req: &Request) -> Packet {
+        let key = Store::key(req);
+        if let Err(res) = self.check_preconditions(req, &key) {
+            return res;
+        }
+
+        match self.entries.remove(&key) {
+            Some(_) => {
+                self.changed.insert(key);
+                generate_status(req.packet, ResponseType::Deleted)
+            }
+            None => {
+                generate_error(req.packet, ResponseType::NotFound, "Not found")
+            }
+        }
+    }
+
+    fn poll_changed(&mut self) -> Changed {
+        if self.changed.is_empty() {
+            return Changed::Nothing;
+        }
+        let keys = std::mem::take(&mut self.changed);
+
+        Changed::Wildcards(keys.into_iter().map(|key| vec![key]).collect())
+    }
+}
+
+/// Encodes an unsigned integer in as few bytes as possible, but at least
+/// one.
+fn uint_bytes(n: u64) -> Vec<u8> {
+    let bytes = n.to_be_bytes();
+    let skip = bytes.iter().take_while(|&&b| b == 0).count().min(7);
+
+    bytes[skip..].to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use coap_lite::{MessageClass, RequestType};
+    use std::net::SocketAddr;
+
+    /// Sends a request with the method and payload for the key to the store.
+    fn send(
+        store: &mut Store,
+        method: RequestType,
+        key: &[u8],
+        payload: &[u8],
+    ) -> Packet {
+        send_with(store, method, key, payload, &[])
+    }
+
+    /// Sends a request with options too.
+    fn send_with(
+        store: &mut Store,
+        method: RequestType,
+        key: &[u8],
+        payload: &[u8],
+        options: &[(CoapOption, &[u8])],
+    ) -> Packet {
+        let mut packet = Packet::new();
+        packet.header.code = MessageClass::Request(method);
+        packet.payload = payload.to_vec();
+        for (option, value) in options {
+            packet.add_option(*option, value.to_vec());
+        }
+        let req = Request {
+            packet: &packet,
+            peer: SocketAddr::from(([127, 0, 0, 1], 5683)),
+            wildcards: vec![key],
+        };
+        match method {
+            RequestType::Put => store.put(&req),
+            RequestType::Post => store.post(&req),
+            RequestType::Delete => store.delete(&req),
+            _ => store.get(&req),
+        }
+    }
+
+    /// Returns the keys that changed, sorted.
+    fn changed_keys(store: &mut Store) -> Vec<Vec<u8>> {
+        match store.poll_changed() {
+            Changed::Nothing => vec![],
+            Changed::Everything => panic!("Store changed as a whole"),
+            Changed::Wildcards(changed) => {
+                changed.into_iter().flatten().collect()
+            }
+        }
+    }
+
+    fn code(res: &Packet) -> MessageClass {
+        res.header.code
+    }
+
+    fn etag(res: &Packet) -> Vec<u8> {
+        let etags = res.get_option(CoapOption::ETag).unwrap();
+        etags.front().unwrap().clone()
+    }
+
+    #[test]
+    fn response_codes() {
+        let mut store = Store::new();
+        let res = send(&mut store, RequestType::Get, b"a", b"");
+        assert_eq!(code(&res), MessageClass::Response(ResponseType::NotFound));
+        let res = send(&mut store, RequestType::Put, b"a", b"1");
+        assert_eq!(code(&res), MessageClass::Response(ResponseType::Created));
+        let res = send(&mut store, RequestType::Post, b"a", b"2");
+        assert_eq!(code(&res), MessageClass::Response(ResponseType::Changed));
+        let res = send(&mut store, RequestType::Get, b"a", b"");
+        assert_eq!(code(&res), MessageClass::Response(ResponseType::Content));
+        assert_eq!(res.payload, b"12");
+        let res = send(&mut store, RequestType::Delete, b"a", b"");
+        assert_eq!(code(&res), MessageClass::Response(ResponseType::Deleted));
+        let res = send(&mut store, RequestType::Delete, b"a", b"");
+        assert_eq!(code(&res), MessageClass::Response(ResponseType::NotFound));
+    }
+
+    #[test]
+    fn validates_etag() {
+        let mut store = Store::new();
+        let first = etag(&send(&mut store, RequestType::Put, b"a", b"1"));
+        let res = send_with(
+            &mut store,
+            RequestType::Get,
+            b"a",
+            b"",
+            &[(CoapOption::ETag, &first)],
+        );
+        assert_eq!(code(&res), MessageClass::Response(ResponseType::Valid));
+        assert!(res.payload.is_empty());
+        assert_eq!(etag(&res), first);
+
+        // Every write makes a new version
+        let second = etag(&send(&mut store, RequestType::Put, b"a", b"1"));
+        assert_ne!(first, second);
+        let res = send_with(
+            &mut store,
+            RequestType::Get,
+            b"a",
+            b"",
+            &[(CoapOption::ETag, &first)],
+        );
+        assert_eq!(code(&res), MessageClass::Response(ResponseType::Content));
+        assert_eq!(etag(&res), second);
+    }
+
+    #[test]
+    fn if_match() {
+        let mut store = Store::new();
+        let failed = MessageClass::Response(ResponseType::PreconditionFailed);
+        // Nothing to match yet
+        let res = send_with(
+            &mut store,
+            RequestType::Put,
+            b"a",
+            b"1",
+            &[(CoapOption::IfMatch, b"")],
+        );
+        assert_eq!(code(&res), failed);
+        let current = etag(&send(&mut store, RequestType::Put, b"a", b"1"));
+
+        let res = send_with(
+            &mut store,
+            RequestType::Put,
+            b"a",
+            b"2",
+            &[(CoapOption::IfMatch, b"stale")],
+        );
+        assert_eq!(code(&res), failed);
+        let res = send_with(
+            &mut store,
+            RequestType::Delete,
+            b"a",
+            b"",
+            &[(CoapOption::IfMatch, b"stale")],
+        );
+        assert_eq!(code(&res), failed);
+        let res = send(&mut store, RequestType::Get, b"a", b"");
+        assert_eq!(res.payload, b"1");
+
+        let res = send_with(
+            &mut store,
+            RequestType::Put,
+            b"a",
+            b"2",
+            &[(CoapOption::IfMatch, &current)],
+        );
+        assert_eq!(code(&res), MessageClass::Response(ResponseType::Changed));
+        // An empty one matches any value
+        let res = send_with(
+            &mut store,
+            RequestType::Post,
+            b"a",
+            b"3",
+            &[(CoapOption::IfMatch, b"")],
+        );
+        assert_eq!(code(&res), MessageClass::Response(ResponseType::Changed));
+    }
+
+    #[test]
+    fn if_none_match() {
+        let mut store = Store::new();
+        let create = |store: &mut Store| {
+            let res = send_with(
+                store,
+                RequestType::Put,
+                b"a",
+                b"1",
+                &[(CoapOption::IfNoneMatch, b"")],
+            );
+            code(&res)
+        };
+        assert_eq!(
+            create(&mut store),
+            MessageClass::Response(ResponseType::Created)
+        );
+        assert_eq!(
+            create(&mut store),
+            MessageClass::Response(ResponseType::PreconditionFailed)
+        );
+    }
+
+    #[test]
+    fn changes_only_written_keys() {
+        let mut store = Store::new();
+        send(&mut store, RequestType::Put, b"a", b"1");
+        send(&mut store, RequestType::Post, b"b", b"2");
+        send(&mut store, RequestType::Get, b"c", b"");
+        assert_eq!(
+            changed_keys(&mut store),
+            vec![b"a".to_vec(), b"b".to_vec()]
+        );
+        assert!(changed_keys(&mut store).is_empty());
+
+        send(&mut store, RequestType::Delete, b"a", b"");
+        assert_eq!(changed_keys(&mut store), vec![b"a".to_vec()]);
+    }
+
+    #[test]
+    fn failed_writes_change_nothing() {
+        let mut store = Store::new();
+        send(&mut store, RequestType::Delete, b"a", b"");
+        send(&mut store, RequestType::Put, b"a", &[0; MAX_VALUE_SIZE + 1]);
+        assert!(changed_keys(&mut store).is_empty());
+    }
+}
@@ -0,0 +1,648 @@
+//! Dispatching of CoAP requests to resources.
+
+use coap_lite::{CoapOption, MessageClass, Packet, RequestType, ResponseType};
+use std::{collections::BTreeMap, net::SocketAddr, sync::Arc};
+use tracing::{debug, info};
+
+use crate::{coap::generate_error, stats::Stats};
+
+/// The attributes of a resource advertised in /.well-known/core.
+#[derive(Default, Clone)]
+pub struct Attributes {
+    /// Resource type (rt).
+    pub rt: Option<&'static str>,
+    /// Interface description (if).
+    pub interface: Option<&'static str>,
+    /// Content-Format (ct).
+    pub ct: Option<u16>,
+    /// Whether the resource is observable (obs).
+    pub obs: bool,
+}
+
+/// Who may access a resource.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    /// Only requests protected with OSCORE, others get 4.01
+    /// (Unauthorized).
+    Oscore,
+    /// Any request, protected or not.
+    Public,
+    /// None at all, as if the resource wasn't registered.
+    Disabled,
+}
+
+/// A request as it's handed to a resource.
+pub struct Request<'a> {
+    /// The CoAP request, already unprotected if it was OSCORE.
+    pub packet: &'a Packet,
+    /// The address the request came from.
+    pub peer: SocketAddr,
+    /// The Uri-Path segments that matched the wildcards of the route, in
+    /// order.
+    pub wildcards: Vec<&'a [u8]>,
+}
+
+/// A CoAP resource, with one handler for each method.
+///
+/// The handlers not implemented respond with 4.05 (Method Not Allowed).
+pub trait Resource {
+    /// Returns the attributes to advertise in /.well-known/core.
+    fn attributes(&self) -> Attributes {
+        Default::default()
+    }
+
+    /// Handles a GET request.
+    fn get(&mut self, req: &Request) -> Packet {
+        method_not_allowed(req)
+    }
+
+    /// Handles a POST request.
+    fn post(&mut self, req: &Request) -> Packet {
+        method_not_allowed(req)
+    }
+
+    /// Handles a PUT request.
+    fn put(&mut self, req: &Request) -> Packet {
+        method_not_allowed(req)
+    }
+
+    /// Handles a DELETE request.
+    fn delete(&mut self, req: &Request) -> Packet {
+        method_not_allowed(req)
+    }
+
+    /// Returns which representations changed since the last call, so
+    /// their observers need to be notified. Only called for resources that
+    /// are advertised as observable.
+    fn poll_changed(&mut self) -> Changed {
+        Changed::Nothing
+    }
+}
+
+/// The representations of a resource that changed.
+pub enum Changed {
+    /// None of them.
+    Nothing,
+    /// All of them, like for a route without wildcards.
+    Everything,
+    /// Only those at these wildcard segments, each in the order of
+    /// `Request::wildcards`.
+    Wildcards(Vec<Vec<Vec<u8>>>),
+}
+
+impl Changed {
+    /// Returns `true` if the representation at the wildcard segments is
+    /// among those that changed.
+    fn includes(&self, wildcards: &[&[u8]]) -> bool {
+        match self {
+            Changed::Nothing => false,
+            Changed::Everything => true,
+            Changed::Wildcards(changed) => changed.iter().any(|segments| {
+                segments
+                    .iter()
+                    .map(Vec::as_slice)
+                    .eq(wildcards.iter().copied())
+            }),
+        }
+    }
+}
+
+/// The maximum number of observers a single resource can have.
+const MAX_OBSERVERS: usize = 16;
+
+/// A peer observing a resource.
+struct Observer {
+    peer: SocketAddr,
+    token: Vec<u8>,
+    /// The registration, which is handled again for every notification.
+    request: Packet,
+}
+
+/// A notification to send to an observer.
+pub struct Notification {
+    /// The observer to send it to.
+    pub peer: SocketAddr,
+    /// The token of the registration, which the notification also has.
+    pub token: Vec<u8>,
+    /// The response for the observer, without message type and ID yet.
+    pub packet: Packet,
+}
+
+/// A single segment of a route.
+#[derive(PartialEq)]
+enum Segment {
+    /// Matches exactly this segment.
+    Literal(Vec<u8>),
+    /// Matches any one segment.
+    Wildcard,
+}
+
+/// A resource together with the path it's reachable at.
+struct Route {
+    path: String,
+    segments: Vec<Segment>,
+    resource: Box<dyn Resource + Send>,
+    access: Access,
+    /// The access the resource was registered with.
+    default_access: Access,
+    observers: Vec<Observer>,
+    /// The Observe sequence number of the current representation.
+    observe_seq: u32,
+}
+
+/// Matches the Uri-Path of requests to resources.
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+    /// Where the requests for each resource are counted.
+    stats: Arc<Stats>,
+}
+
+impl Router {
+    /// Creates a new `Router` without any resources.
+    pub fn new() -> Router {
+        Default::default()
+    }
+
+    /// Sets the statistics to count the requests for each resource in.
+    pub fn set_stats(&mut self, stats: Arc<Stats>) {
+        self.stats = stats;
+    }
+
+    /// Registers a resource at the given path, which only requests
+    /// protected with OSCORE may access.
+    ///
+    /// Segments of the path are separated by `/`, and a `*` segment matches
+    /// any one segment. Routes are tried in the order they were registered
+    /// in.
+    pub fn add<R>(&mut self, path: &str, resource: R)
+    where
+        R: Resource + Send + 'static,
+    {
+        self.add_with_access(path, resource, Access::Oscore);
+    }
+
+    /// Registers a resource at the given path like `add`, with the given
+    /// access policy.
+    pub fn add_with_access<R>(
+        &mut self,
+        path: &str,
+        resource: R,
+        access: Access,
+    ) where
+        R: Resource + Send + 'static,
+    {
+        let segments = path
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .map(|s| {
+                if s == "*" {
+                    Segment::Wildcard
+                } else {
+                    Segment::Literal(s.as_bytes().to_vec())
+                }
+            })
+            .collect();
+
+        self.routes.push(Route {
+            path: format!("/{}", path.trim_start_matches('/')),
+            segments,
+            resource: Box::new(resource),
+            access,
+            default_access: access,
+            observers: Vec::new(),
+            observe_seq: 0,
+        });
+    }
+
+    /// Changes the access policies of the resources, given by their path.
+    /// The ones not listed go back to the access they were registered with.
+    ///
+    /// Nothing is changed if one of the paths isn't registered, which is
+    /// returned as the error. Disabling a resource ends its observations.
+    pub fn set_access_policies(
+        &mut self,
+        policies: &BTreeMap<String, Access>,
+    ) -> Result<(), String> {
+        let normalized: BTreeMap<String, Access> = policies
+            .iter()
+            .map(|(path, &access)| {
+                (format!("/{}", path.trim_start_matches('/')), access)
+            })
+            .collect();
+        if let Some(path) = normalized
+            .keys()
+            .find(|path| !self.routes.iter().any(|r| &r.path == *path))
+        {
+            return Err(path.clone());
+        }
+
+        for route in &mut self.routes {
+            route.access = normalized
+                .get(&route.path)
+                .cloned()
+                .unwrap_or(route.default_access);
+            if route.access == Access::Disabled {
+                route.observers.clear();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Passes the request to the resource handling its path and returns the
+    /// response, or `None` if there is no such resource.
+    ///
+    /// `protected` tells whether the request came protected with OSCORE,
+    /// which the access policy of the resource may demand.
+    pub fn dispatch(
+        &mut self,
+        packet: &Packet,
+        peer: SocketAddr,
+        protected: bool,
+    ) -> Option<Packet> {
+        let path = uri_path(packet);
+
+        let (route, wildcards) = self
+            .routes
+            .iter_mut()
+            .filter(|route| route.access != Access::Disabled)
+            .find_map(|route| {
+                route_match(&route.segments, &path).map(|w| (route, w))
+            })?;
+        debug!("Request for the {} resource", route.path);
+        if route.access == Access::Oscore && !protected {
+            info!("Refusing unprotected request");
+            return Some(generate_error(
+                packet,
+                ResponseType::Unauthorized,
+                "OSCORE required",
+            ));
+        }
+        self.stats.hit(&route.path);
+
+        let req = Request {
+            packet,
+            peer,
+            wildcards,
+        };
+        let res = match packet.header.code {
+            MessageClass::Request(RequestType::Get) => {
+                let res = route.resource.get(&req);
+                route.observe(packet, peer, res)
+            }
+            MessageClass::Request(RequestType::Post) => {
+                route.resource.post(&req)
+            }
+            MessageClass::Request(RequestType::Put) => {
+                route.resource.put(&req)
+            }
+            MessageClass::Request(RequestType::Delete) => {
+                route.resource.delete(&req)
+            }
+            _ => method_not_allowed(&req),
+        };
+
+        Some(res)
+    }
+
+    /// Returns the notifications for the observers of all resources that
+    /// changed.
+    ///
+    /// Observers that get an error response are removed, since that ends
+    /// the observation.
+    pub fn notifications(&mut self) -> Vec<Notification> {
+        let mut notifications = Vec::new();
+        for route in &mut self.routes {
+            // Polling even without observers, so nothing piles up
+            let changed = route.resource.poll_changed();
+            if route.observers.is_empty()
+                || matches!(changed, Changed::Nothing)
+            {
+                continue;
+            }
+            route.observe_seq = (route.observe_seq + 1) & 0xFF_FFFF;
+
+            let resource = &mut route.resource;
+            let segments = &route.segments;
+            let observe_seq = route.observe_seq;
+            route.observers.retain(|observer| {
+                let path = uri_path(&observer.request);
+                let wildcards =
+                    route_match(segments, &path).unwrap_or_default();
+                if !changed.includes(&wildcards) {
+                    return true;
+                }
+                let req = Request {
+                    packet: &observer.request,
+                    peer: observer.peer,
+                    wildcards,
+                };
+                let mut packet = resource.get(&req);
+                let success = is_success(&packet);
+                if success {
+                    packet.add_option(
+                        CoapOption::Observe,
+                        uint_value(observe_seq),
+                    );
+                }
+                notifications.push(Notification {
+                    peer: observer.peer,
+                    token: observer.token.clone(),
+                    packet,
+                });
+
+                success
+            });
+        }
+
+        notifications
+    }
+
+    /// Ends the observation with the given token, if there is one.
+    pub fn cancel(&mut self, peer: SocketAddr, token: &[u8]) {
+        for route in &mut self.routes {
+            route
+                .observers
+                .retain(|o| o.peer != peer || o.token[..] != token[..]);
+        }
+    }
+
+    /// Ends all observations of the given peer.
+    pub fn cancel_all(&mut self, peer: SocketAddr) {
+        for route in &mut self.routes {
+            route.observers.retain(|o| o.peer != peer);
+        }
+    }
+
+    /// Returns `true` if there is an observation with the given token.
+    pub fn is_observing(&self, peer: SocketAddr, token: &[u8]) -> bool {
+        self.routes.iter().any(|route| {
+            route
+                .observers
+                .iter()
+                .any(|o| o.peer == peer && o.token[..] == token[..])
+        })
+    }
+
+    /// Returns the link-format description of all resources without
+    /// wildcards, followed by the additional entries.
+    pub fn link_format(&self, additional: &[(&str, Attributes)]) -> Vec<u8> {
+        self.routes
+            .iter()
+            .filter(|r| {
+                r.access != Access::Disabled
+                    && !r.segments.contains(&Segment::Wildcard)
+            })
+            .map(|r| (&r.path[..], r.resource.attributes()))
+            .chain(additional.iter().cloned())
+            .map(|(path, attributes)| link(path, &attributes))
+            .collect::<Vec<_>>()
+            .join(",")
+            .into_bytes()
+    }
+}
+
+impl Route {
+    /// Registers or deregisters an observer as requested by the Observe
+    /// option of a GET request, returning the response to it.
+    fn observe(
+        &mut self,
+        packet: &Packet,
+        peer: SocketAddr,
+        mut res: Packet,
+    ) -> Packet {
+        let observe = packet
+            .get_option(CoapOption::Observe)
+            .and_then(|values| values.front())
+            .map(|value| value.iter().fold(0, |n, &b| n << 8 | u32::from(b)));
+        let token = packet.get_token();
+        match observe {
+            // Register, unless the resource can't be observed or we can't
+            // take any more observers, in which case the response without
+            // Observe option tells the peer
+            Some(0) if self.resource.attributes().obs && is_success(&res) => {
+                self.observers
+                    .retain(|o| o.peer != peer || o.token != *token);
+                if self.observers.len() < MAX_OBSERVERS {
+                    self.observers.push(Observer {
+                        peer,
+                        token: token.clone(),
+                        request: packet.clone(),
+                    });
+                    res.add_option(
+                        CoapOption::Observe,
+                        uint_value(self.observe_seq),
+                    );
+                }
+            }
+            Some(1) => {
+                self.observers
+                    .retain(|o| o.peer != peer || o.token != *token);
+            }
+            _ => {}
+        }
+
+        res
+    }
+}
+
+/// Returns the Uri-Path segments of a request.
+fn uri_path(packet: &Packet) -> Vec<&[u8]> {
+    packet
+        .get_option(CoapOption::UriPath)
+        .map(|p| p.iter().map(|s| &s[..]).collect())
+        .unwrap_or_default()
+}
+
+/// Returns `true` if the response has a 2.xx code.
+fn is_success(res: &Packet) -> bool {
+    matches!(
+        res.header.code,
+        MessageClass::Response(ResponseType::Created)
+            | MessageClass::Response(ResponseType::Deleted)
+            | MessageClass::Response(ResponseType::Valid)
+            | MessageClass::Response(ResponseType::Changed)
+            | MessageClass::Response(ResponseType::Content)
+    )
+}
+
+/// Encodes an unsigned integer option value in as few bytes as possible.
+fn uint_value(n: u32) -> Vec<u8> {
+    let bytes = n.to_be_bytes();
+    let skip = bytes.iter().take_while(|&&b| b == 0).count();
+
+    bytes[skip..].to_vec()
+}
+
+/// Returns the segments matched by wildcards if the path fits the route.
+fn route_match<'a>(
+    segments: &[Segment],
+    path: &[&'a [u8]],
+) -> Option<Vec<&'a [u8]>> {
+    if segments.len() != path.len() {
+        return None;
+    }
+
+    let mut wildcards = Vec::new();
+    for (segment, part) in segments.iter().zip(path) {
+        match segment {
+            Segment::Literal(literal) if literal[..] == **part => {}
+            Segment::Literal(_) => return None,
+            Segment::Wildcard => wildcards.push(*part),
+        }
+    }
+
+    Some(wildcards)
+}
+
+/// Returns the link-format entry for a single resource.
+fn link(path: &str, attributes: &Attributes) -> String {
+    let mut link = format!("<{}>", path);
+    if let Some(rt) = attributes.rt {
+        link.push_str(&format!(";rt=\"{}\"", rt));
+    }
+    if let Some(interface) = attributes.interface {
+        link.push_str(&format!(";if=\"{}\"", interface));
+    }
+    if let Some(ct) = attributes.ct {
+        link.push_str(&format!(";ct={}", ct));
+    }
+    if attributes.obs {
+        link.push_str(";obs");
+    }
+
+    link
+}
+
+/// Returns a 4.05 (Method Not Allowed) response.
+fn method_not_allowed(req: &Request) -> Packet {
+    generate_error(
+        req.packet,
+        ResponseType::MethodNotAllowed,
+        "Method not allowed",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Empty;
+
+    impl Resource for Empty {}
+
+    /// Responds with the wildcard segments, separated by commas.
+    struct Wildcards;
+
+    impl Resource for Wildcards {
+        fn get(&mut self, req: &Request) -> Packet {
+            let mut res = Packet::new();
+            res.header.code = MessageClass::Response(ResponseType::Content);
+            res.payload = req.wildcards.join(&b',');
+
+            res
+        }
+    }
+
+    /// Dispatches a GET for the path and returns the response payload, or
+    /// `None` if no resource handles it.
+    fn get(router: &mut Router, path: &[&str]) -> Option<Vec<u8>> {
+        let mut req = Packet::new();
+        req.header.code = MessageClass::Request(RequestType::Get);
+        for segment in path {
+            req.add_option(CoapOption::UriPath, segment.as_bytes().to_vec());
+        }
+        let peer = "127.0.0.1:5683".parse().unwrap();
+
+        router.dispatch(&req, peer, true).map(|res| res.payload)
+    }
+
+    #[test]
+    fn counts_only_granted_hits() {
+        let stats = Arc::new(Stats::default());
+        let mut router = Router::new();
+        router.set_stats(stats.clone());
+        router.add("secret", Empty);
+        let mut req = Packet::new();
+        req.header.code = MessageClass::Request(RequestType::Get);
+        req.add_option(CoapOption::UriPath, b"secret".to_vec());
+        let peer = "127.0.0.1:5683".parse().unwrap();
+
+        router.dispatch(&req, peer, false);
+        assert!(stats.resource_hits().is_empty());
+        router.dispatch(&req, peer, true);
+        assert_eq!(stats.resource_hits().get("/secret"), Some(&1));
+    }
+
+    #[test]
+    fn changed_includes() {
+        assert!(!Changed::Nothing.includes(&[]));
+        assert!(Changed::Everything.includes(&[b"a"]));
+        let changed = Changed::Wildcards(vec![vec![b"a".to_vec()]]);
+        assert!(changed.includes(&[b"a"]));
+        assert!(!changed.includes(&[b"b"]));
+        assert!(!changed.includes(&[b"a", b"b"]));
+    }
+
+    #[test]
+    fn matches_wildcards() {
+        let mut router = Router::new();
+        router.add("/kv/*", Wildcards);
+        router.add("a/*/c/*", Wildcards);
+        // Shadowed by the wildcard registered before
+        router.add("kv/fixed", Empty);
+
+        assert_eq!(get(&mut router, &["kv", "x"]), Some(b"x".to_vec()));
+        assert_eq!(
+            get(&mut router, &["kv", "fixed"]),
+            Some(b"fixed".to_vec())
+        );
+        assert_eq!(get(&mut router, &["kv", ""]), Some(b"".to_vec()));
+        assert_eq!(
+            get(&mut router, &["a", "1", "c", "2"]),
+            Some(b"1,2".to_vec())
+        );
+        // A wildcard matches exactly one segment
+        assert_eq!(get(&mut router, &["kv"]), None);
+        assert_eq!(get(&mut router, &["kv", "x", "y"]), None);
+        assert_eq!(get(&mut router, &["a", "1", "d", "2"]), None);
+        assert_eq!(get(&mut router, &[]), None);
+    }
+
+    #[test]
+    fn link_format() {
+        struct Sensor;
+
+        impl Resource for Sensor {
+            fn attributes(&self) -> Attributes {
+                Attributes {
+                    rt: Some("temperature"),
+                    interface: Some("core.s"),
+                    ct: Some(0),
+                    obs: true,
+                }
+            }
+        }
+
+        let mut router = Router::new();
+        router.add("sensor", Sensor);
+        router.add("kv/*", Empty);
+        router.add_with_access("/plain", Empty, Access::Public);
+        router.add("off", Empty);
+        let mut policies = BTreeMap::new();
+        policies.insert("off".to_string(), Access::Disabled);
+        router.set_access_policies(&policies).unwrap();
+
+        let extra = Attributes {
+            ct: Some(40),
+            ..Default::default()
+        };
+        assert_eq!(
+            String::from_utf8(router.link_format(&[("/extra", extra)]))
+                .unwrap(),
+            "</sensor>;rt=\"temperature\";if=\"core.s\";ct=0;obs,\
+             </plain>,</extra>;ct=40"
+        );
+    }
+}
@@ -0,0 +1,640 @@
+//! The event loops of the server, either blocking on a single socket or
+//! asynchronous on several, including TCP listeners.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{SocketAddr, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, RwLock, RwLockReadGuard,
+    },
+    thread,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        udp::{RecvHalf, SendHalf},
+        TcpListener, TcpStream,
+    },
+    runtime::{self, Runtime},
+    signal::{
+        self,
+        unix::{signal as unix_signal, SignalKind},
+    },
+    sync::{broadcast, mpsc, Mutex as AsyncMutex},
+    task, time,
+};
+use tracing::{debug, error, info, warn};
+
+use crate::{dedup::DedupCache, oscore::OscoreHandler, tcp};
+
+/// How often we check whether observed resources changed.
+pub const NOTIFY_INTERVAL: Duration = Duration::from_millis(250);
+
+/// A function applying a changed configuration to the `OscoreHandler`.
+pub type Reload = Box<dyn FnMut(&mut OscoreHandler) + Send>;
+
+/// Handles the datagrams of all peers, answering duplicates from the cache.
+///
+/// Requests are handled at the same time, sharing the `OscoreHandler`. It's
+/// only locked exclusively to reload.
+pub struct Server {
+    oscore: RwLock<OscoreHandler>,
+    dedup: Mutex<DedupCache>,
+    reload: Mutex<Option<Reload>>,
+    /// Set when SIGHUP arrived, until the reload is done.
+    hangup: Arc<AtomicBool>,
+    /// Set when SIGINT or SIGTERM arrived in the blocking event loop.
+    stop: Arc<AtomicBool>,
+}
+
+impl Server {
+    /// Creates a new `Server` around the `OscoreHandler`.
+    pub fn new(oscore: OscoreHandler) -> Server {
+        Server {
+            oscore: RwLock::new(oscore),
+            // Remembers our responses in case the client retransmits
+            dedup: Mutex::new(DedupCache::new()),
+            reload: Mutex::new(None),
+            hangup: Arc::new(AtomicBool::new(false)),
+            stop: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Sets the function to call on SIGHUP, which can change the
+    /// configuration while keeping the security contexts.
+    pub fn set_reload<F>(&mut self, reload: F)
+    where
+        F: FnMut(&mut OscoreHandler) + Send + 'static,
+    {
+        self.reload = Mutex::new(Some(Box::new(reload)));
+    }
+
+    /// Calls the reload function, if there is one.
+    pub fn reload(&self) {
+        let mut reload = self.reload.lock().expect("Server state poisoned");
+        if let Some(reload) = reload.as_mut() {
+            info!("Reloading the configuration");
+            reload(&mut self.oscore.write().expect("Server state poisoned"));
+        }
+    }
+
+    /// Handles a datagram and returns the response to send, if any.
+    pub fn handle(&self, peer: SocketAddr, msg: &[u8]) -> Option<Vec<u8>> {
+        {
+            let mut dedup = self.dedup.lock().expect("Server state poisoned");
+            // Answer retransmissions with the response we already built
+            if let Some(res) = dedup.lookup(peer, msg) {
+                info!(%peer, "Received a duplicate, repeating the response");
+                return res;
+            }
+            // Until then, a retransmission arriving while we're still at it
+            // is dropped
+            dedup.insert(peer, msg, None);
+        }
+
+        // Handle the request
+        let res = match self.oscore().handle(peer, msg) {
+            Ok(res) => res,
+            Err(failure) => {
+                warn!(%peer, "Failed handling message: {}", failure.error);
+                failure.response
+            }
+        };
+        self.dedup.lock().expect("Server state poisoned").insert(
+            peer,
+            msg,
+            res.as_deref(),
+        );
+
+        res
+    }
+
+    /// Returns the notifications for the observers of resources that
+    /// changed.
+    ///
+    /// Since this is called regularly, it's also where handshakes that
+    /// waited too long for message_3 are given up on, and where a SIGHUP
+    /// leads to a reload.
+    pub fn notifications(&self) -> Vec<(SocketAddr, Vec<u8>)> {
+        if self.hangup.swap(false, Ordering::Relaxed) {
+            self.reload();
+        }
+        let oscore = self.oscore();
+        oscore.edhoc().expire();
+        oscore.notifications()
+    }
+
+    /// Returns `true` if the peer observes a resource with the token.
+    pub fn is_observing(&self, peer: SocketAddr, token: &[u8]) -> bool {
+        self.oscore().coap().is_observing(peer, token)
+    }
+
+    /// Ends all observations of the given peer, like when its connection
+    /// closed.
+    pub fn cancel_all(&self, peer: SocketAddr) {
+        self.oscore().cancel_all(peer);
+    }
+
+    /// Saves the state before shutting down.
+    pub fn close(&self) {
+        self.oscore().close_state();
+    }
+
+    /// Serves requests on a single socket, one at a time, until SIGINT or
+    /// SIGTERM.
+    pub fn run_blocking(&self, socket: &UdpSocket) -> io::Result<()> {
+        self.watch_hangup()?;
+        watch_signals(
+            &[SignalKind::interrupt(), SignalKind::terminate()],
+            self.stop.clone(),
+        )?;
+        // Wake up regularly to notify observers, even if nobody talks to us
+        socket.set_read_timeout(Some(NOTIFY_INTERVAL))?;
+
+        while !self.stop.load(Ordering::Relaxed) {
+            for (peer, notification) in self.notifications() {
+                debug!(%peer, "Notifying observer");
+                socket.send_to(&notification, peer)?;
+            }
+
+            let mut buf = [0; 2048];
+            let (amt, src) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut =>
+                {
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            debug!(%src, "Received {} bytes", amt);
+
+            if let Some(res) = self.handle(src, &buf[..amt]) {
+                debug!(%src, "Responding with {} bytes", res.len());
+                socket.send_to(&res, src)?;
+            }
+        }
+        info!("Shutting down");
+        self.close();
+
+        Ok(())
+    }
+
+    /// Serves requests on all of the UDP and TCP addresses until SIGINT or
+    /// SIGTERM.
+    ///
+    /// Every datagram is handled in its own task, so sockets keep receiving
+    /// while a request is being handled. Every TCP connection gets a task
+    /// too, which handles its messages in order. The handling itself and
+    /// everything else that takes locks runs on the blocking thread pool.
+    /// Notifications go out through the socket their observation was
+    /// registered on. On shutdown, the requests being handled are answered
+    /// and the state is saved before returning.
+    pub fn run_async(
+        self,
+        addrs: &[SocketAddr],
+        tcp_addrs: &[SocketAddr],
+    ) -> io::Result<()> {
+        self.watch_hangup()?;
+        let mut runtime = Runtime::new()?;
+        runtime.block_on(serve(self, addrs, tcp_addrs))
+    }
+
+    /// Returns the `OscoreHandler`, shared with the other requests.
+    fn oscore(&self) -> RwLockReadGuard<'_, OscoreHandler> {
+        self.oscore.read().expect("Server state poisoned")
+    }
+
+    /// Makes SIGHUP trigger a reload, if there is a reload function.
+    ///
+    /// The reload itself happens with the next notifications, so it never
+    /// runs in the middle of handling a request.
+    fn watch_hangup(&self) -> io::Result<()> {
+        if self.reload.lock().expect("Server state poisoned").is_none() {
+            return Ok(());
+        }
+
+        watch_signals(&[SignalKind::hangup()], self.hangup.clone())
+    }
+}
+
+/// Sets the flag whenever one of the signals arrives.
+///
+/// The signals are waited for on a thread of their own, which works the
+/// same for both event loops.
+fn watch_signals(
+    kinds: &[SignalKind],
+    flag: Arc<AtomicBool>,
+) -> io::Result<()> {
+    let mut runtime = runtime::Builder::new()
+        .basic_scheduler()
+        .enable_all()
+        .build()?;
+    // Registering here means the signals no longer terminate us from now on
+    let signals = kinds
+        .iter()
+        .map(|&kind| runtime.enter(|| unix_signal(kind)))
+        .collect::<io::Result<Vec<_>>>()?;
+    thread::spawn(move || {
+        runtime.block_on(async move {
+            let waiting: Vec<_> = signals
+                .into_iter()
+                .map(|mut signal| {
+                    let flag = flag.clone();
+                    task::spawn(async move {
+                        while signal.recv().await.is_some() {
+                            flag.store(true, Ordering::Relaxed);
+                        }
+                    })
+                })
+                .collect();
+            for wait in waiting {
+                wait.await.ok();
+            }
+        })
+    });
+
+    Ok(())
+}
+
+/// The sending half of a socket, shared by the tasks using it.
+type Sender = Arc<AsyncMutex<SendHalf>>;
+
+/// The open TCP connections, with the channel to send frames on, by peer.
+type Connections =
+    Arc<Mutex<HashMap<SocketAddr, mpsc::UnboundedSender<Vec<u8>>>>>;
+
+/// The socket each observation was registered on, by peer and token.
+type Registrations = Arc<Mutex<HashMap<(SocketAddr, Vec<u8>), Sender>>>;
+
+/// Binds the sockets and runs until a shutdown signal arrives.
+async fn serve(
+    server: Server,
+    addrs: &[SocketAddr],
+    tcp_addrs: &[SocketAddr],
+) -> io::Result<()> {
+    let server = Arc::new(server);
+    let (shutdown, _) = broadcast::channel(1);
+    // Every task holds a sender, so when all of them are dropped we know
+    // that everything is done
+    let (done, mut all_done) = mpsc::channel::<()>(1);
+
+    let registrations = Registrations::default();
+    for addr in addrs {
+        let socket = tokio::net::UdpSocket::bind(addr).await?;
+        info!("Listening on {}", socket.local_addr()?);
+        let (recv, send) = socket.split();
+        tokio::spawn(receive(
+            server.clone(),
+            recv,
+            Arc::new(AsyncMutex::new(send)),
+            registrations.clone(),
+            shutdown.subscribe(),
+            done.clone(),
+        ));
+    }
+    let connections = Connections::default();
+    for addr in tcp_addrs {
+        let listener = TcpListener::bind(addr).await?;
+        info!("Listening on coap+tcp://{}", listener.local_addr()?);
+        tokio::spawn(accept(
+            server.clone(),
+            listener,
+            connections.clone(),
+            shutdown.clone(),
+            done.clone(),
+        ));
+    }
+    tokio::spawn(notify(
+        server.clone(),
+        registrations,
+        connections,
+        shutdown.subscribe(),
+        done.clone(),
+    ));
+
+    wait_for_signal().await?;
+    info!("Shutting down");
+    // Nobody may be listening anymore if all tasks failed, which is fine
+    shutdown.send(()).ok();
+    drop(done);
+    all_done.recv().await;
+    task::spawn_blocking(move || server.close())
+        .await
+        .expect("Saving the state panicked");
+
+    Ok(())
+}
+
+/// Receives datagrams on a socket and spawns a task for each of them.
+async fn receive(
+    server: Arc<Server>,
+    mut recv: RecvHalf,
+    send: Sender,
+    registrations: Registrations,
+    mut shutdown: broadcast::Receiver<()>,
+    done: mpsc::Sender<()>,
+) {
+    loop {
+        let mut buf = vec![0; 2048];
+        let (amt, src) = tokio::select! {
+            received = recv.recv_from(&mut buf) => match received {
+                Ok(received) => received,
+                Err(e) => {
+                    error!("Failed while receiving: {}", e);
+                    continue;
+                }
+            },
+            _ = shutdown.recv() => break,
+        };
+        debug!(%src, "Received {} bytes", amt);
+        buf.truncate(amt);
+
+        tokio::spawn(respond(
+            server.clone(),
+            send.clone(),
+            registrations.clone(),
+            src,
+            buf,
+            done.clone(),
+        ));
+    }
+}
+
+/// Handles a single datagram and sends the response.
+async fn respond(
+    server: Arc<Server>,
+    send: Sender,
+    registrations: Registrations,
+    src: SocketAddr,
+    msg: Vec<u8>,
+    _done: mpsc::Sender<()>,
+) {
+    // Handling is CPU-bound and takes blocking locks, so keep it away from
+    // the tasks doing I/O
+    let (res, registered) = task::spawn_blocking(move || {
+        let res = server.handle(src, &msg);
+        let registered = token(&msg)
+            .filter(|token| server.is_observing(src, token))
+            .map(|token| token.to_vec());
+        (res, registered)
+    })
+    .await
+    .expect("Handler panicked");
+    if let Some(token) = registered {
+        registrations
+            .lock()
+            .expect("Registrations poisoned")
+            .insert((src, token), send.clone());
+    }
+
+    if let Some(res) = res {
+        debug!(%src, "Responding with {} bytes", res.len());
+        if let Err(e) = send.lock().await.send_to(&res, &src).await {
+            error!(%src, "Failed sending: {}", e);
+        }
+    }
+}
+
+/// Accepts TCP connections and spawns a task for each of them.
+async fn accept(
+    server: Arc<Server>,
+    mut listener: TcpListener,
+    connections: Connections,
+    shutdown: broadcast::Sender<()>,
+    done: mpsc::Sender<()>,
+) {
+    let mut stop = shutdown.subscribe();
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    error!("Failed accepting connection: {}", e);
+                    continue;
+                }
+            },
+            _ = stop.recv() => break,
+        };
+        info!(%peer, "Accepted connection");
+
+        tokio::spawn(connection(
+            server.clone(),
+            stream,
+            peer,
+            connections.clone(),
+            shutdown.subscribe(),
+            done.clone(),
+        ));
+    }
+}
+
+/// Serves a TCP connection until the peer closes it or we shut down.
+async fn connection(
+    server: Arc<Server>,
+    stream: TcpStream,
+    peer: SocketAddr,
+    connections: Connections,
+    mut shutdown: broadcast::Receiver<()>,
+    done: mpsc::Sender<()>,
+) {
+    let (mut read, write) = stream.into_split();
+    // Everything is sent through a channel, so notifications can find the
+    // connection too
+    let (send, frames) = mpsc::unbounded_channel();
+    tokio::spawn(write_frames(write, frames, done));
+    send.send(tcp::csm()).ok();
+    connections
+        .lock()
+        .expect("Connections poisoned")
+        .insert(peer, send.clone());
+
+    loop {
+        let frame = tokio::select! {
+            frame = read_frame(&mut read) => frame,
+            _ = shutdown.recv() => {
+                send.send(tcp::signal(tcp::RELEASE, &[])).ok();
+                break;
+            }
+        };
+        let frame = match frame {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            Err(e) => {
+                warn!(%peer, "Failed while receiving: {}", e);
+                if e.kind() == io::ErrorKind::InvalidData {
+                    send.send(tcp::signal(tcp::ABORT, &[])).ok();
+                }
+                break;
+            }
+        };
+
+        match tcp::code(&frame) {
+            // An empty message, which only keeps the connection alive
+            0 => {}
+            // The peer's capabilities don't matter, since our messages are
+            // small enough for any of them
+            tcp::CSM | tcp::PONG => {}
+            tcp::PING => {
+                send.send(tcp::signal(tcp::PONG, tcp::token(&frame))).ok();
+            }
+            tcp::RELEASE | tcp::ABORT => break,
+            // Other signaling messages aren't for us
+            code if code >> 5 == 7 => {}
+            _ => {
+                debug!(%peer, "Received {} bytes over TCP", frame.len());
+                let msg = tcp::to_datagram(&frame);
+                let server = server.clone();
+                let res =
+                    task::spawn_blocking(move || server.handle(peer, &msg))
+                        .await
+                        .expect("Handler panicked");
+
+                if let Some(res) = res.as_deref().and_then(tcp::from_datagram)
+                {
+                    debug!(%peer, "Responding with {} bytes", res.len());
+                    send.send(res).ok();
+                }
+            }
+        }
+    }
+
+    info!(%peer, "Connection closed");
+    connections
+        .lock()
+        .expect("Connections poisoned")
+        .remove(&peer);
+    // Observations end with the connection
+    task::spawn_blocking(move || server.cancel_all(peer))
+        .await
+        .expect("Handler panicked");
+}
+
+/// Reads a frame, or returns `None` if the peer closed the connection.
+async fn read_frame(read: &mut OwnedReadHalf) -> io::Result<Option<Vec<u8>>> {
+    let mut frame = vec![0];
+    if read.read(&mut frame).await? == 0 {
+        return Ok(None);
+    }
+    frame.resize(tcp::header_size(frame[0]), 0);
+    read.read_exact(&mut frame[1..]).await?;
+
+    let size = frame.len() + tcp::remaining_size(&frame);
+    if size > tcp::MAX_MESSAGE_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "message too large",
+        ));
+    }
+    let start = frame.len();
+    frame.resize(size, 0);
+    read.read_exact(&mut frame[start..]).await?;
+
+    Ok(Some(frame))
+}
+
+/// Writes the frames of a connection until all of its senders are gone.
+async fn write_frames(
+    mut write: OwnedWriteHalf,
+    mut frames: mpsc::UnboundedReceiver<Vec<u8>>,
+    _done: mpsc::Sender<()>,
+) {
+    while let Some(frame) = frames.recv().await {
+        if let Err(e) = write.write_all(&frame).await {
+            warn!("Failed sending on connection: {}", e);
+            break;
+        }
+    }
+}
+
+/// Sends the notifications for observed resources that changed.
+async fn notify(
+    server: Arc<Server>,
+    registrations: Registrations,
+    connections: Connections,
+    mut shutdown: broadcast::Receiver<()>,
+    _done: mpsc::Sender<()>,
+) {
+    let mut interval = time::interval(NOTIFY_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.recv() => break,
+        }
+
+        // Building them may save the state, which is blocking I/O
+        let notifications = {
+            let server = server.clone();
+            let registrations = registrations.clone();
+            task::spawn_blocking(move || {
+                // Forget the registrations of observations that ended. Ones
+                // ending with these notifications still get them.
+                registrations
+                    .lock()
+                    .expect("Registrations poisoned")
+                    .retain(|(peer, token), _| {
+                        server.is_observing(*peer, token)
+                    });
+                server.notifications()
+            })
+            .await
+            .expect("Notifying panicked")
+        };
+        for (peer, notification) in notifications {
+            // Observers on TCP get them on their connection
+            let connection = connections
+                .lock()
+                .expect("Connections poisoned")
+                .get(&peer)
+                .cloned();
+            if let Some(connection) = connection {
+                if let Some(frame) = tcp::from_datagram(&notification) {
+                    debug!(%peer, "Notifying observer over TCP");
+                    connection.send(frame).ok();
+                }
+                continue;
+            }
+            // Others on the socket they registered on
+            let send = match token(&notification).and_then(|token| {
+                registrations
+                    .lock()
+                    .expect("Registrations poisoned")
+                    .get(&(peer, token.to_vec()))
+                    .cloned()
+            }) {
+                Some(send) => send,
+                None => {
+                    debug!(%peer, "No socket to notify observer on");
+                    continue;
+                }
+            };
+            debug!(%peer, "Notifying observer");
+            let mut send = send.lock().await;
+            if let Err(e) = send.send_to(&notification, &peer).await {
+                error!(%peer, "Failed sending: {}", e);
+            }
+        }
+    }
+}
+
+/// Returns the token of a message in the UDP format, or `None` if it's too
+/// short.
+fn token(msg: &[u8]) -> Option<&[u8]> {
+    let tkl = usize::from(*msg.first()? & 0x0F);
+
+    msg.get(4..4 + tkl)
+}
+
+/// Waits for SIGINT or SIGTERM.
+async fn wait_for_signal() -> io::Result<()> {
+    let mut terminate = unix_signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = signal::ctrl_c() => result,
+        _ = terminate.recv() => Ok(()),
+    }
+}
@@ -0,0 +1,408 @@
+//! Persistence of the security contexts across restarts.
+//!
+//! The state file is a CBOR array with a map for each context. Following
+//! RFC 8613 Appendix B.1.1, the sender sequence number isn't written on
+//! every use, but as a bound `K` ahead of the one in use. After a crash, we
+//! continue from that bound, so a sequence number is never used twice.
+//!
+//! The replay window is saved the same way: as long as no request beyond
+//! the highest Partial IV on disk arrived, there's no need to save it again.
+//! After a crash, everything up to that bound is rejected. Only on a clean
+//! shutdown are the exact sequence numbers and replay windows saved.
+
+use serde_cbor::Value;
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::TryFrom,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::warn;
+
+use crate::context::{ContextTable, PeerContext, ReplayWindow};
+use desktop_common::persist::{
+    bytes, get_bytes, get_u64, key, to_u64, write_atomically, K,
+    MAX_RESTORE_SEQ,
+};
+
+/// The ways in which loading or saving the state can fail.
+#[derive(Debug)]
+pub enum Error {
+    /// The file couldn't be read or written.
+    Io(io::Error),
+    /// The file is not a valid state file.
+    Format(&'static str),
+    /// A security context couldn't be recreated.
+    Oscore(oscore::oscore::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "unable to access state file: {}", e),
+            Error::Format(reason) => {
+                write!(f, "invalid state file: {}", reason)
+            }
+            Error::Oscore(e) => {
+                write!(f, "unable to restore security context: {:?}", e)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+/// The file the security contexts are saved to.
+pub struct StateFile {
+    path: PathBuf,
+    /// The sequence number bound on disk for each recipient ID.
+    reserved: HashMap<Vec<u8>, u64>,
+    /// The highest Partial IV on disk for each recipient ID, if any.
+    replay_bounds: HashMap<Vec<u8>, Option<u64>>,
+}
+
+impl StateFile {
+    /// Creates a new `StateFile` at the given path, which doesn't need to
+    /// exist yet.
+    pub fn new(path: &Path) -> StateFile {
+        StateFile {
+            path: path.to_path_buf(),
+            reserved: HashMap::new(),
+            replay_bounds: HashMap::new(),
+        }
+    }
+
+    /// Loads the security contexts, or returns an empty table if the file
+    /// doesn't exist.
+    ///
+    /// The restored contexts continue from the bounds on disk, which are
+    /// then advanced by saving right away.
+    pub fn load(&mut self) -> Result<ContextTable, Error> {
+        let mut table = ContextTable::new();
+        let bytes = match fs::read(&self.path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(table),
+            Err(e) => return Err(e.into()),
+        };
+
+        let records = match serde_cbor::from_slice(&bytes) {
+            Ok(Value::Array(records)) => records,
+            _ => return Err(Error::Format("not a CBOR array")),
+        };
+        for record in records {
+            let record = match record {
+                Value::Map(record) => record,
+                _ => return Err(Error::Format("context is not a map")),
+            };
+            let highest = match record.get(&key("replay_highest")) {
+                Some(Value::Integer(n)) => {
+                    Some(to_u64(*n).map_err(Error::Format)?)
+                }
+                Some(Value::Null) | None => None,
+                Some(_) => return Err(Error::Format("invalid replay window")),
+            };
+            let bitmap =
+                get_u64(&record, "replay_bitmap").map_err(Error::Format)?;
+            let bitmap = u32::try_from(bitmap)
+                .map_err(|_| Error::Format("invalid replay window"))?;
+            // Files from before contexts had a lifetime start it over
+            let created = match record.get(&key("created")) {
+                Some(Value::Integer(n)) => {
+                    let created = to_u64(*n).map_err(Error::Format)?;
+                    UNIX_EPOCH + Duration::from_secs(created)
+                }
+                None => SystemTime::now(),
+                Some(_) => return Err(Error::Format("created")),
+            };
+            let recipient_id =
+                get_bytes(&record, "recipient_id").map_err(Error::Format)?;
+            let sender_seq =
+                get_u64(&record, "sender_seq").map_err(Error::Format)?;
+            if sender_seq > MAX_RESTORE_SEQ {
+                // The peer gets an error and does EDHOC again
+                warn!(
+                    kid = %hex::encode(&recipient_id),
+                    "Restoring a context too far along as expired"
+                );
+            }
+            let context = PeerContext::restore(
+                get_bytes(&record, "master_secret").map_err(Error::Format)?,
+                get_bytes(&record, "master_salt").map_err(Error::Format)?,
+                get_bytes(&record, "sender_id").map_err(Error::Format)?,
+                recipient_id,
+                sender_seq,
+                ReplayWindow::from_parts(highest, bitmap),
+                created,
+            )
+            .map_err(Error::Oscore)?;
+            table.insert(context);
+        }
+        self.save(&table)?;
+
+        Ok(table)
+    }
+
+    /// Returns `true` if the file is missing anything it needs to hold for
+    /// the contexts to be restored safely.
+    ///
+    /// That's the case for new and removed contexts, and when a sequence
+    /// number or Partial IV reached the bound on disk.
+    pub fn is_stale(&self, table: &ContextTable) -> bool {
+        table.len() != self.reserved.len()
+            || table.iter().any(|context| {
+                let id = context.recipient_id();
+                match (self.reserved.get(id), self.replay_bounds.get(id)) {
+                    (Some(reserved), Some(bound)) => {
+                        context.sender_seq() >= *reserved
+                            || reached(
+                                context.replay_window().highest(),
+                                *bound,
+                            )
+                    }
+                    _ => true,
+                }
+            })
+    }
+
+    /// Saves the security contexts, reserving the next `K` sequence numbers
+    /// and Partial IVs of each.
+    pub fn save(&mut self, table: &ContextTable) -> Result<(), Error> {
+        self.write(table, K)
+    }
+
+    /// Saves the security contexts as they are, for when they won't be used
+    /// anymore.
+    ///
+    /// Nothing is reserved, so after a clean shutdown we continue exactly
+    /// where we left off.
+    pub fn close(&mut self, table: &ContextTable) -> Result<(), Error> {
+        self.write(table, 0)
+    }
+
+    /// Writes the security contexts with bounds `ahead` of the sequence
+    /// numbers and Partial IVs in use.
+    fn write(
+        &mut self,
+        table: &ContextTable,
+        ahead: u64,
+    ) -> Result<(), Error> {
+        let mut reserved = HashMap::new();
+        let mut replay_bounds = HashMap::new();
+        let records = table
+            .iter()
+            .map(|context| {
+                let bound = context.sender_seq() + ahead;
+                let mut window = *context.replay_window();
+                if ahead > 0 {
+                    // Reject everything up to the bound after a crash, since
+                    // we can't know what arrived in the meantime
+                    if let Some(highest) = window.highest() {
+                        window = ReplayWindow::from_parts(
+                            Some(highest + ahead),
+                            !0,
+                        );
+                    }
+                }
+                reserved.insert(context.recipient_id().to_vec(), bound);
+                replay_bounds
+                    .insert(context.recipient_id().to_vec(), window.highest());
+
+                let mut record = BTreeMap::new();
+                record.insert(
+                    key("master_secret"),
+                    bytes(context.master_secret()),
+                );
+                record
+                    .insert(key("master_salt"), bytes(context.master_salt()));
+                record.insert(key("sender_id"), bytes(context.sender_id()));
+                record.insert(
+                    key("recipient_id"),
+                    bytes(context.recipient_id()),
+                );
+                record.insert(key("sender_seq"), Value::Integer(bound.into()));
+                record.insert(
+                    key("replay_highest"),
+                    window
+                        .highest()
+                        .map_or(Value::Null, |n| Value::Integer(n.into())),
+                );
+                record.insert(
+                    key("replay_bitmap"),
+                    Value::Integer(window.bitmap().into()),
+                );
+                let created = context
+                    .created()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |since| since.as_secs());
+                record.insert(key("created"), Value::Integer(created.into()));
+                Value::Map(record)
+            })
+            .collect();
+        let bytes = serde_cbor::to_vec(&Value::Array(records))
+            .map_err(|_| Error::Format("unable to encode state"))?;
+
+        write_atomically(&self.path, &bytes)?;
+        self.reserved = reserved;
+        self.replay_bounds = replay_bounds;
+
+        Ok(())
+    }
+}
+
+/// Returns `true` if the highest Partial IV received reached the bound on
+/// disk.
+fn reached(highest: Option<u64>, bound: Option<u64>) -> bool {
+    match (highest, bound) {
+        (Some(highest), Some(bound)) => highest >= bound,
+        // Nothing was received yet when the file was written
+        (Some(_), None) => true,
+        (None, _) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::RekeyPolicy;
+
+    /// Returns a path for a state file of the test, removing an old one.
+    fn state_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "state-test-{}-{}.cbor",
+            name,
+            std::process::id()
+        ));
+        fs::remove_file(&path).ok();
+        path
+    }
+
+    /// Returns a table with a context at the sequence number and window.
+    fn table(sender_seq: u64, highest: Option<u64>) -> ContextTable {
+        let mut table = ContextTable::new();
+        let context = PeerContext::restore(
+            vec![0; 16],
+            vec![1; 8],
+            vec![0xA],
+            vec![0xB],
+            sender_seq,
+            ReplayWindow::from_parts(highest, 0),
+            UNIX_EPOCH + Duration::from_secs(1_600_000_000),
+        )
+        .unwrap();
+        table.insert(context);
+        table
+    }
+
+    #[test]
+    fn missing_file() {
+        let mut state = StateFile::new(&state_path("missing"));
+        assert!(state.load().unwrap().is_empty());
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = state_path("round-trip");
+        StateFile::new(&path).save(&table(5, Some(10))).unwrap();
+
+        let loaded = StateFile::new(&path).load().unwrap();
+        let context = loaded.get(&[0xB]).unwrap();
+        assert_eq!(context.master_secret(), &[0; 16][..]);
+        assert_eq!(context.master_salt(), &[1; 8][..]);
+        assert_eq!(context.sender_id(), &[0xA][..]);
+        assert_eq!(context.sender_seq(), 5 + K);
+        assert_eq!(
+            context.created(),
+            UNIX_EPOCH + Duration::from_secs(1_600_000_000)
+        );
+        // After what might have been a crash, nothing up to the bound is
+        // accepted
+        let window = context.replay_window();
+        assert!(!window.is_fresh(10));
+        assert!(!window.is_fresh(10 + K));
+        assert!(window.is_fresh(10 + K + 1));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn close_keeps_exact_state() {
+        let path = state_path("close");
+        StateFile::new(&path).close(&table(5, Some(10))).unwrap();
+
+        let loaded = StateFile::new(&path).load().unwrap();
+        let context = loaded.get(&[0xB]).unwrap();
+        assert_eq!(context.sender_seq(), 5);
+        assert!(!context.replay_window().is_fresh(10));
+        assert!(context.replay_window().is_fresh(9));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn stale_at_sequence_number_bound() {
+        let path = state_path("seq-bound");
+        let mut state = StateFile::new(&path);
+        state.save(&table(5, None)).unwrap();
+
+        assert!(!state.is_stale(&table(5, None)));
+        assert!(!state.is_stale(&table(5 + K - 1, None)));
+        assert!(state.is_stale(&table(5 + K, None)));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn stale_at_replay_bound() {
+        let path = state_path("replay-bound");
+        let mut state = StateFile::new(&path);
+        state.save(&table(0, Some(10))).unwrap();
+
+        assert!(!state.is_stale(&table(0, Some(11))));
+        assert!(!state.is_stale(&table(0, Some(10 + K - 1))));
+        assert!(state.is_stale(&table(0, Some(10 + K))));
+
+        // The first request after saving an empty window needs a save
+        state.save(&table(0, None)).unwrap();
+        assert!(state.is_stale(&table(0, Some(0))));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn stale_with_other_contexts() {
+        let path = state_path("other-contexts");
+        let mut state = StateFile::new(&path);
+        assert!(state.is_stale(&table(0, None)));
+        state.save(&table(0, None)).unwrap();
+        assert!(state.is_stale(&ContextTable::new()));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn expires_context_too_far_along() {
+        let path = state_path("too-far");
+        StateFile::new(&path)
+            .save(&table(MAX_RESTORE_SEQ, None))
+            .unwrap();
+
+        // Kept, but only to tell the peer to re-key
+        let loaded = StateFile::new(&path).load().unwrap();
+        let context = loaded.get(&[0xB]).unwrap();
+        assert!(context.is_expired(&RekeyPolicy::default()));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn invalid_file() {
+        let path = state_path("invalid");
+        fs::write(&path, b"not cbor").unwrap();
+        assert!(matches!(
+            StateFile::new(&path).load(),
+            Err(Error::Format(_))
+        ));
+        fs::remove_file(&path).unwrap();
+    }
+}
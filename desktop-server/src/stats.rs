@@ -0,0 +1,179 @@
+//! Counters of what the server has been doing.
+//!
+//! They're shared between the handlers through an `Arc`, so they can be read
+//! by the `/stats` resource or written to a file from another thread.
+
+use coap_lite::{CoapOption, ContentFormat, Packet};
+use serde_cbor::Value;
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    fs, io,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use crate::{
+    coap::generate_response,
+    router::{Attributes, Request, Resource},
+};
+
+/// The Content-Format of CBOR, which coap-lite doesn't know about.
+const CONTENT_FORMAT_CBOR: u8 = 60;
+
+/// A single counter that only ever goes up.
+#[derive(Default, Debug)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    /// Adds one to the counter.
+    pub fn increment(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the current value of the counter.
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// The counters of the server.
+#[derive(Default, Debug)]
+pub struct Stats {
+    /// EDHOC handshakes started with a message_1.
+    pub handshakes_started: Counter,
+    /// EDHOC handshakes that ended with a security context.
+    pub handshakes_completed: Counter,
+    /// EDHOC handshakes that failed or timed out.
+    pub handshakes_failed: Counter,
+    /// Requests that were successfully unprotected with OSCORE.
+    pub requests_protected: Counter,
+    /// Requests that came without OSCORE.
+    pub requests_unprotected: Counter,
+    /// OSCORE requests rejected because their Partial IV was already used.
+    pub replays_rejected: Counter,
+    /// Messages that couldn't be parsed, either outside or inside OSCORE.
+    pub decode_errors: Counter,
+    /// The number of requests for each resource, keyed by its path.
+    resource_hits: Mutex<BTreeMap<String, u64>>,
+}
+
+impl Stats {
+    /// Creates a new `Stats` with all counters at zero.
+    pub fn new() -> Stats {
+        Default::default()
+    }
+
+    /// Counts a request for the resource at the given path.
+    pub fn hit(&self, path: &str) {
+        let mut hits = self.resource_hits.lock().unwrap();
+        *hits.entry(path.to_string()).or_insert(0) += 1;
+    }
+
+    /// Returns the number of requests for each resource.
+    pub fn resource_hits(&self) -> BTreeMap<String, u64> {
+        self.resource_hits.lock().unwrap().clone()
+    }
+
+    /// Returns the counters with their names, in the order they're listed.
+    fn counters(&self) -> [(&'static str, u64); 7] {
+        [
+            ("handshakes_started", self.handshakes_started.get()),
+            ("handshakes_completed", self.handshakes_completed.get()),
+            ("handshakes_failed", self.handshakes_failed.get()),
+            ("requests_protected", self.requests_protected.get()),
+            ("requests_unprotected", self.requests_unprotected.get()),
+            ("replays_rejected", self.replays_rejected.get()),
+            ("decode_errors", self.decode_errors.get()),
+        ]
+    }
+
+    /// Returns the counters as a CBOR map, with the resource hits in a map
+    /// of their own under `resource_hits`.
+    pub fn to_cbor(&self) -> Vec<u8> {
+        let mut map = BTreeMap::new();
+        for (name, value) in self.counters().iter() {
+            map.insert(
+                Value::Text(name.to_string()),
+                Value::Integer(i128::from(*value)),
+            );
+        }
+        let hits = self
+            .resource_hits()
+            .into_iter()
+            .map(|(path, n)| {
+                (Value::Text(path), Value::Integer(i128::from(n)))
+            })
+            .collect();
+        map.insert(Value::Text("resource_hits".into()), Value::Map(hits));
+
+        // Serializing a map of integers can't fail
+        serde_cbor::to_vec(&Value::Map(map)).unwrap()
+    }
+
+    /// Returns the counters in the Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let mut text = String::new();
+        for (name, value) in self.counters().iter() {
+            let _ = writeln!(text, "# TYPE oscore_{}_total counter", name);
+            let _ = writeln!(text, "oscore_{}_total {}", name, value);
+        }
+        let _ = writeln!(text, "# TYPE oscore_resource_hits_total counter");
+        for (path, n) in self.resource_hits() {
+            let _ = writeln!(
+                text,
+                "oscore_resource_hits_total{{path=\"{}\"}} {}",
+                path.replace('\\', "\\\\").replace('"', "\\\""),
+                n
+            );
+        }
+
+        text
+    }
+
+    /// Writes the counters in the Prometheus text format to the file,
+    /// replacing it atomically so a scraper never sees half of it.
+    pub fn write_prometheus(&self, path: &Path) -> io::Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        fs::write(&tmp, self.to_prometheus())?;
+        fs::rename(&tmp, path)
+    }
+}
+
+/// Responds with the counters of the server as a CBOR map.
+pub struct StatsResource {
+    stats: Arc<Stats>,
+}
+
+impl StatsResource {
+    /// Creates a new `StatsResource` reporting the given counters.
+    pub fn new(stats: Arc<Stats>) -> StatsResource {
+        StatsResource { stats }
+    }
+}
+
+impl Resource for StatsResource {
+    fn attributes(&self) -> Attributes {
+        Attributes {
+            rt: Some("stats"),
+            ct: Some(u16::from(CONTENT_FORMAT_CBOR)),
+            ..Default::default()
+        }
+    }
+
+    fn get(&mut self, req: &Request) -> Packet {
+        let mut res = generate_response(
+            req.packet,
+            self.stats.to_cbor(),
+            ContentFormat::ApplicationOctetStream,
+        );
+        res.clear_option(CoapOption::ContentFormat);
+        res.add_option(CoapOption::ContentFormat, vec![CONTENT_FORMAT_CBOR]);
+
+        res
+    }
+}
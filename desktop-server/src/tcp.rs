@@ -0,0 +1,175 @@
+//! Framing and signaling of CoAP over TCP (RFC 8323).
+//!
+//! Over TCP, messages have no type and Message ID, and are prefixed with
+//! their length instead. The rest of the server only knows the UDP format,
+//! so incoming frames are turned into Non-confirmable messages and the
+//! responses back into frames. Signaling messages never make it that far,
+//! they're answered here.
+
+/// The largest message we accept, and advertise in our CSM.
+pub const MAX_MESSAGE_SIZE: usize = 2048;
+
+/// The code of a Capabilities and Settings Message (7.01).
+pub const CSM: u8 = 0xE1;
+/// The code of a Ping (7.02).
+pub const PING: u8 = 0xE2;
+/// The code of a Pong (7.03).
+pub const PONG: u8 = 0xE3;
+/// The code of a Release message (7.04).
+pub const RELEASE: u8 = 0xE4;
+/// The code of an Abort message (7.05).
+pub const ABORT: u8 = 0xE5;
+
+/// The Max-Message-Size option of a CSM.
+const MAX_MESSAGE_SIZE_OPTION: u8 = 2;
+
+/// Returns the size of the length fields, given the first byte of a frame.
+pub fn header_size(first: u8) -> usize {
+    match first >> 4 {
+        13 => 2,
+        14 => 3,
+        15 => 5,
+        _ => 1,
+    }
+}
+
+/// Returns the number of bytes following the length fields, which are the
+/// code, token, options and payload.
+pub fn remaining_size(header: &[u8]) -> usize {
+    let tkl = usize::from(header[0] & 0x0F);
+    let ext = header[1..].iter().fold(0, |n, &b| n << 8 | usize::from(b));
+    let len = match header[0] >> 4 {
+        13 => ext + 13,
+        14 => ext + 269,
+        15 => ext + 65805,
+        len => usize::from(len),
+    };
+
+    1 + tkl + len
+}
+
+/// Returns the code of a complete frame.
+pub fn code(frame: &[u8]) -> u8 {
+    frame[header_size(frame[0])]
+}
+
+/// Returns the token of a complete frame.
+pub fn token(frame: &[u8]) -> &[u8] {
+    let start = header_size(frame[0]) + 1;
+    &frame[start..start + usize::from(frame[0] & 0x0F)]
+}
+
+/// Turns a complete frame into a Non-confirmable message in the UDP format.
+pub fn to_datagram(frame: &[u8]) -> Vec<u8> {
+    let start = header_size(frame[0]);
+    let tkl = frame[0] & 0x0F;
+    // Version 1, Non-confirmable and the token length
+    let mut msg = vec![0x50 | tkl, frame[start], 0, 0];
+    msg.extend(&frame[start + 1..]);
+
+    msg
+}
+
+/// Turns a message in the UDP format into a frame, or returns `None` if it's
+/// malformed.
+pub fn from_datagram(msg: &[u8]) -> Option<Vec<u8>> {
+    let tkl = usize::from(*msg.first()? & 0x0F);
+    let code = *msg.get(1)?;
+    let token = msg.get(4..4 + tkl)?;
+    let rest = &msg[4 + tkl..];
+
+    Some(frame(code, token, rest))
+}
+
+/// Returns our CSM, which has to be the first message on a connection.
+pub fn csm() -> Vec<u8> {
+    let size = (MAX_MESSAGE_SIZE as u32).to_be_bytes();
+    let skip = size.iter().take_while(|&&b| b == 0).count();
+    let size = &size[skip..];
+    let mut options = vec![MAX_MESSAGE_SIZE_OPTION << 4 | size.len() as u8];
+    options.extend(size);
+
+    frame(CSM, &[], &options)
+}
+
+/// Returns a signaling message without options.
+pub fn signal(code: u8, token: &[u8]) -> Vec<u8> {
+    frame(code, token, &[])
+}
+
+/// Builds a frame from its code, token and the options and payload.
+fn frame(code: u8, token: &[u8], rest: &[u8]) -> Vec<u8> {
+    let len = rest.len();
+    let (nibble, ext) = if len < 13 {
+        (len as u8, Vec::new())
+    } else if len < 269 {
+        (13, vec![(len - 13) as u8])
+    } else if len < 65805 {
+        (14, ((len - 269) as u16).to_be_bytes().to_vec())
+    } else {
+        (15, ((len - 65805) as u32).to_be_bytes().to_vec())
+    };
+
+    let mut frame = vec![nibble << 4 | token.len() as u8];
+    frame.extend(ext);
+    frame.push(code);
+    frame.extend(token);
+    frame.extend(rest);
+
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks the length fields of a frame against its actual length.
+    fn check_lengths(frame: &[u8]) {
+        let header = header_size(frame[0]);
+        assert_eq!(header + remaining_size(&frame[..header]), frame.len());
+    }
+
+    #[test]
+    fn lengths() {
+        // Around where the extended length fields start and grow
+        for &len in &[0, 12, 13, 268, 269, 65804, 65805] {
+            let frame = frame(0x45, &[1, 2], &vec![0xFF; len]);
+            check_lengths(&frame);
+        }
+        assert_eq!(header_size(frame(0x45, &[], &[0; 12])[0]), 1);
+        assert_eq!(header_size(frame(0x45, &[], &[0; 13])[0]), 2);
+        assert_eq!(header_size(frame(0x45, &[], &[0; 269])[0]), 3);
+        assert_eq!(header_size(frame(0x45, &[], &[0; 65805])[0]), 5);
+    }
+
+    #[test]
+    fn datagram_round_trip() {
+        // GET, token 0xAB, Uri-Path "x"
+        let msg = [0x51, 0x01, 0, 0, 0xAB, 0xB1, b'x'];
+        let frame = from_datagram(&msg).unwrap();
+        assert_eq!(frame, [0x21, 0x01, 0xAB, 0xB1, b'x']);
+        assert_eq!(code(&frame), 0x01);
+        assert_eq!(token(&frame), [0xAB]);
+        assert_eq!(to_datagram(&frame), msg);
+    }
+
+    #[test]
+    fn malformed_datagram() {
+        assert_eq!(from_datagram(&[]), None);
+        assert_eq!(from_datagram(&[0x52, 0x01, 0, 0, 0xAB]), None);
+    }
+
+    #[test]
+    fn csm_advertises_size() {
+        // Max-Message-Size of 2048 in two bytes
+        assert_eq!(csm(), [0x30, CSM, 0x22, 0x08, 0x00]);
+        check_lengths(&csm());
+    }
+
+    #[test]
+    fn pong_keeps_token() {
+        let ping = signal(PING, &[7, 8]);
+        assert_eq!(ping, [0x02, PING, 7, 8]);
+        assert_eq!(signal(PONG, token(&ping)), [0x02, PONG, 7, 8]);
+    }
+}
@@ -0,0 +1,119 @@
+//! The peers we're willing to authenticate with EDHOC.
+
+use std::collections::HashMap;
+
+/// An enrolled peer with its public key and the OSCORE IDs to use with it.
+#[derive(Clone)]
+pub struct Peer {
+    /// The peer's public authentication key.
+    pub public: [u8; 32],
+    /// Our sender ID in the security context with this peer.
+    pub sender_id: Vec<u8>,
+    /// Our recipient ID in the security context with this peer, which it
+    /// uses as kid in its requests.
+    pub recipient_id: Vec<u8>,
+}
+
+/// Maps the kid a peer authenticates with to what we know about it.
+#[derive(Default, Clone)]
+pub struct TrustStore {
+    peers: HashMap<Vec<u8>, Peer>,
+}
+
+impl TrustStore {
+    /// Creates a new, empty `TrustStore`.
+    pub fn new() -> TrustStore {
+        Default::default()
+    }
+
+    /// Enrolls a peer under its kid, replacing any peer that had it.
+    ///
+    /// Since security contexts are looked up by recipient ID, this fails if
+    /// another peer already uses the same one.
+    pub fn insert(&mut self, kid: Vec<u8>, peer: Peer) -> Result<(), Peer> {
+        let taken = self.peers.iter().any(|(other_kid, other)| {
+            *other_kid != kid && other.recipient_id == peer.recipient_id
+        });
+        if taken {
+            return Err(peer);
+        }
+        self.peers.insert(kid, peer);
+
+        Ok(())
+    }
+
+    /// Returns the peer with the given kid.
+    pub fn get(&self, kid: &[u8]) -> Option<&Peer> {
+        self.peers.get(kid)
+    }
+
+    /// Removes the peer with the given kid and returns it.
+    pub fn remove(&mut self, kid: &[u8]) -> Option<Peer> {
+        self.peers.remove(kid)
+    }
+
+    /// Returns an iterator over the kids of all enrolled peers.
+    pub fn kids(&self) -> impl Iterator<Item = &[u8]> {
+        self.peers.keys().map(|kid| &kid[..])
+    }
+
+    /// Returns the number of enrolled peers.
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    /// Returns `true` if no peers are enrolled.
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(recipient_id: &[u8]) -> Peer {
+        Peer {
+            public: [recipient_id[0]; 32],
+            sender_id: vec![0xA3],
+            recipient_id: recipient_id.to_vec(),
+        }
+    }
+
+    #[test]
+    fn unknown_kid() {
+        let mut trust_store = TrustStore::new();
+        assert!(trust_store.insert(vec![0xA2], peer(&[0xA2])).is_ok());
+        assert!(trust_store.get(&[0xA2]).is_some());
+        assert!(trust_store.get(&[0xA4]).is_none());
+        assert!(trust_store.get(&[]).is_none());
+        assert!(trust_store.remove(&[0xA4]).is_none());
+        assert_eq!(trust_store.len(), 1);
+    }
+
+    #[test]
+    fn duplicate_recipient_id() {
+        let mut trust_store = TrustStore::new();
+        assert!(trust_store.insert(vec![0xA2], peer(&[0x01])).is_ok());
+        let rejected = trust_store.insert(vec![0xA4], peer(&[0x01]));
+        assert_eq!(rejected.err().unwrap().recipient_id, [0x01]);
+        assert!(trust_store.get(&[0xA4]).is_none());
+        assert_eq!(trust_store.len(), 1);
+
+        // Free once the peer using it is gone
+        trust_store.remove(&[0xA2]).unwrap();
+        assert!(trust_store.insert(vec![0xA4], peer(&[0x01])).is_ok());
+        assert_eq!(trust_store.kids().collect::<Vec<_>>(), [[0xA4]]);
+    }
+
+    #[test]
+    fn reenrolling_replaces() {
+        let mut trust_store = TrustStore::new();
+        assert!(trust_store.insert(vec![0xA2], peer(&[0x01])).is_ok());
+        // The peer keeps or changes its own recipient ID
+        assert!(trust_store.insert(vec![0xA2], peer(&[0x01])).is_ok());
+        assert!(trust_store.insert(vec![0xA2], peer(&[0x02])).is_ok());
+        assert_eq!(trust_store.len(), 1);
+        assert_eq!(trust_store.get(&[0xA2]).unwrap().recipient_id, [0x02]);
+    }
+}
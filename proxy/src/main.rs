@@ -1,12 +1,46 @@
+use clap::{App, Arg};
 use coap_lite::{CoapOption, Packet};
-use std::{net::UdpSocket, time::Duration};
+use std::{net::UdpSocket, process, time::Duration};
+use tracing::{debug, info, info_span, warn};
 
+use desktop_common::logging::{self, Format};
 use proxy::ProxyUri;
 
 const COAP_PORT: u32 = 5683;
 const READ_TIMEOUT: Duration = Duration::from_secs(5);
 
 fn main() {
+    let matches = App::new(clap::crate_name!())
+        .version(clap::crate_version!())
+        .about("Forwarding CoAP proxy.")
+        .author(clap::crate_authors!())
+        .arg(
+            Arg::with_name("log")
+                .long("log")
+                .value_name("FILTER")
+                .takes_value(true)
+                .help("Log filter like info,proxy=debug"),
+        )
+        .arg(
+            Arg::with_name("log-format")
+                .long("log-format")
+                .value_name("FORMAT")
+                .takes_value(true)
+                .possible_values(&["text", "json"])
+                .default_value("text")
+                .help("Whether to log text or JSON lines"),
+        )
+        .get_matches();
+    let format = matches
+        .value_of("log-format")
+        .unwrap()
+        .parse::<Format>()
+        .unwrap();
+    logging::init(format, matches.value_of("log")).unwrap_or_else(|e| {
+        eprintln!("Error setting up logging: {}", e);
+        process::exit(1);
+    });
+
     let socket = UdpSocket::bind(format!("0.0.0.0:{}", COAP_PORT))
         .expect("Unable to bind to port");
     let mut buf = [0; 2048];
@@ -22,7 +56,15 @@ fn main() {
             socket.recv_from(&mut buf).expect("Failed while receiving");
         let mut packet =
             Packet::from_bytes(&buf[..amt]).expect("Failed parsing CoAP");
-        println!("Received packet from {}:\n{:?}", sender, packet);
+        // Everything logged for this exchange carries what identifies it
+        let token: String = packet
+            .get_token()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        let span = info_span!("exchange", %sender, %token);
+        let _exchange = span.enter();
+        debug!(?packet, "Received packet");
 
         // Check if it contains a Proxy-Uri option
         if let Some(option_val) = packet
@@ -50,6 +92,7 @@ fn main() {
                 proxy_uri.uri_host,
                 proxy_uri.uri_port.unwrap_or_else(|| COAP_PORT.to_string())
             );
+            info!(%destination, "Forwarding request");
             socket
                 .send_to(&bytes, destination)
                 .expect("Unable to send packet");
@@ -63,13 +106,13 @@ fn main() {
             let (amt, responder) = match socket.recv_from(&mut buf) {
                 Ok(val) => val,
                 Err(_) => {
-                    println!("Timed out waiting for response");
+                    warn!("Timed out waiting for response");
                     continue;
                 }
             };
             let packet =
                 Packet::from_bytes(&buf[..amt]).expect("Failed parsing CoAP");
-            println!("Received response from {}:\n{:?}\n", responder, packet);
+            debug!(%responder, ?packet, "Received response");
             socket
                 .send_to(&buf[..amt], sender)
                 .expect("Unable to send packet");
@@ -3,37 +3,165 @@
 use alloc::vec::Vec;
 use alt_stm32f30x_hal::{device::USART1, serial::Tx};
 use coap_lite::{
-    CoapOption, ContentFormat, MessageClass, MessageType, Packet, ResponseType,
+    CoapOption, ContentFormat, MessageClass, MessageType, Packet, RequestType,
+    ResponseType,
 };
 use core::fmt::Write;
 use util::{uprint, uprintln};
+use w5500::IpAddress;
 
-use crate::edhoc::EdhocHandler;
+use crate::{edhoc::EdhocHandler, stats::Stats};
+
+/// The maximum number of observers of the /counter resource.
+const MAX_OBSERVERS: usize = 4;
+
+/// The number of notifications we remember, so a Reset to them can cancel
+/// the observation.
+const MAX_SENT: usize = 8;
+
+/// The resources only requests protected with OSCORE may access. Others get
+/// 4.01 (Unauthorized).
+const PROTECTED: [&[u8]; 4] = [b"hello", b"counter", b"echo", b"stats"];
+
+/// The Content-Format of CBOR, which coap-lite doesn't know about.
+const CONTENT_FORMAT_CBOR: u8 = 60;
+
+/// A peer observing the /counter resource.
+struct Observer {
+    peer: (IpAddress, u16),
+    token: Vec<u8>,
+}
+
+/// A notification to send to an observer.
+pub struct Notification {
+    /// The observer to send it to.
+    pub peer: (IpAddress, u16),
+    /// The token of the registration, which the notification also has.
+    pub token: Vec<u8>,
+    /// The notification itself.
+    pub packet: Packet,
+}
 
 /// Handles CoAP messages.
-pub struct CoapHandler;
+pub struct CoapHandler {
+    /// The number of requests handled, served by /counter.
+    counter: u32,
+    /// The counter value the observers were last notified of.
+    notified: u32,
+    observers: Vec<Observer>,
+    /// The Observe sequence number of the current representation.
+    observe_seq: u32,
+    /// The Message ID of the next notification.
+    next_mid: u16,
+    /// The peer, Message ID and token of recent notifications, oldest first.
+    sent: Vec<((IpAddress, u16), u16, Vec<u8>)>,
+}
 
 impl Default for CoapHandler {
     fn default() -> CoapHandler {
-        CoapHandler
+        CoapHandler {
+            counter: 0,
+            notified: 0,
+            observers: Vec::new(),
+            observe_seq: 0,
+            next_mid: 0,
+            sent: Vec::with_capacity(MAX_SENT),
+        }
     }
 }
 
 impl CoapHandler {
     /// Creates a new `CoapHandler`.
     pub fn new() -> CoapHandler {
-        // Since this is an empty struct, the constructor is not necessary, but
-        // it's still here in case that changes.
         Default::default()
     }
 
-    /// Handles a CoAP message and returns a response.
+    /// Returns the notifications for the observers of /counter if it
+    /// changed, ready to be sent.
+    ///
+    /// They're Non-confirmable, so a peer that went away without
+    /// deregistering keeps getting them until it answers with a Reset.
+    pub fn notifications(&mut self) -> Vec<Notification> {
+        if self.counter == self.notified || self.observers.is_empty() {
+            return Vec::new();
+        }
+        self.notified = self.counter;
+        self.observe_seq = (self.observe_seq + 1) & 0xFF_FFFF;
+
+        let mut notifications = Vec::with_capacity(self.observers.len());
+        for observer in &self.observers {
+            let mid = self.next_mid;
+            self.next_mid = self.next_mid.wrapping_add(1);
+
+            let mut packet = Packet::new();
+            packet.header.set_type(MessageType::NonConfirmable);
+            packet.header.code = MessageClass::Response(ResponseType::Content);
+            packet.header.message_id = mid;
+            packet.set_token(observer.token.clone());
+            packet
+                .add_option(CoapOption::Observe, uint_value(self.observe_seq));
+            packet.set_content_format(ContentFormat::TextPlain);
+            packet.payload = counter_payload(self.counter);
+
+            if self.sent.len() >= MAX_SENT {
+                self.sent.remove(0);
+            }
+            self.sent.push((observer.peer, mid, observer.token.clone()));
+            notifications.push(Notification {
+                peer: observer.peer,
+                token: observer.token.clone(),
+                packet,
+            });
+        }
+
+        notifications
+    }
+
+    /// Ends the observation with the given token, if there is one.
+    pub fn cancel(&mut self, peer: (IpAddress, u16), token: &[u8]) {
+        self.observers
+            .retain(|o| o.peer != peer || o.token[..] != token[..]);
+    }
+
+    /// Ends all observations of the given peer, like when it can't be
+    /// reached.
+    pub fn cancel_all(&mut self, peer: (IpAddress, u16)) {
+        self.observers.retain(|o| o.peer != peer);
+    }
+
+    /// Returns `true` if there is an observation with the given token.
+    pub fn is_observing(&self, peer: (IpAddress, u16), token: &[u8]) -> bool {
+        self.observers
+            .iter()
+            .any(|o| o.peer == peer && o.token[..] == token[..])
+    }
+
+    /// Handles a CoAP message from the given peer and returns a response.
+    ///
+    /// `protected` tells whether the message was protected with OSCORE.
     pub fn handle(
         &mut self,
         tx: &mut Tx<USART1>,
         edhoc: &mut EdhocHandler,
+        stats: &mut Stats,
+        peer: (IpAddress, u16),
         req: Packet,
+        protected: bool,
     ) -> Option<Packet> {
+        // A Reset to a notification means the peer lost interest
+        if req.header.get_type() == MessageType::Reset {
+            let mid = req.header.message_id;
+            if let Some((_, _, token)) =
+                self.sent.iter().find(|(p, m, _)| *p == peer && *m == mid)
+            {
+                uprintln!(tx, "Observation cancelled with a Reset");
+                let token = token.clone();
+                self.cancel(peer, &token);
+            }
+            return None;
+        }
+        self.counter = self.counter.wrapping_add(1);
+
         if let Some(path) = req.get_option(CoapOption::UriPath) {
             // Copy the linked list of references so we can manipulate it for
             // easier traversal
@@ -47,6 +175,7 @@ impl CoapHandler {
                                 tx,
                                 "Request for the /.well-known/core resource"
                             );
+                            stats.hit("/.well-known/core");
                             // Response to /.well-known/core
                             return Some(generate_link_format(
                                 &req,
@@ -55,16 +184,20 @@ impl CoapHandler {
                                 // of the IANA registry yet, we don't know it
                                 b"</hello>;rt=\"test\";ct=0,\
                                   </echo>;rt=\"echo\";ct=42,\
+                                  </counter>;rt=\"counter\";ct=0;obs,\
+                                  </stats>;rt=\"stats\";ct=60,\
                                   </.well-known/edhoc>;rt=\"edhoc\";ct=42"
                                     .to_vec(),
                             ));
                         } else if second == b"edhoc" {
                             // Response to /.well-known/edhoc
+                            stats.hit("/.well-known/edhoc");
 
                             // Duplicate the token for later use
                             let token = req.get_token().clone();
                             // Get our response from the EDHOC handler
-                            let payload = edhoc.handle(tx, req.payload);
+                            let payload =
+                                edhoc.handle(tx, stats, peer, req.payload);
                             // Do an early return with None if we got that
                             let payload = payload?;
 
@@ -94,16 +227,38 @@ impl CoapHandler {
                         &req,
                         br#"</.well-known/core>;rt="core";ct=40"#.to_vec(),
                     ));
+                } else if !protected
+                    && PROTECTED.iter().any(|&p| p == &first[..])
+                {
+                    uprintln!(tx, "Refusing unprotected request");
+                    return Some(generate_error(
+                        &req,
+                        ResponseType::Unauthorized,
+                        "OSCORE required",
+                    ));
                 } else if first == b"hello" {
                     uprintln!(tx, "Request for the /hello resource");
+                    stats.hit("/hello");
                     // Response to /hello
                     return Some(generate_response(
                         &req,
                         b"Hello, world!".to_vec(),
                         ContentFormat::TextPlain,
                     ));
+                } else if first == b"counter" {
+                    uprintln!(tx, "Request for the /counter resource");
+                    stats.hit("/counter");
+                    // Response to /counter, which may register an observer
+                    let mut res = generate_response(
+                        &req,
+                        counter_payload(self.counter),
+                        ContentFormat::TextPlain,
+                    );
+                    self.observe(peer, &req, &mut res);
+                    return Some(res);
                 } else if first == b"echo" {
                     uprintln!(tx, "Request for the /echo resource");
+                    stats.hit("/echo");
                     // Response to /echo
                     let payload = req.payload.clone();
                     return Some(generate_response(
@@ -111,24 +266,81 @@ impl CoapHandler {
                         payload,
                         ContentFormat::ApplicationOctetStream,
                     ));
+                } else if first == b"stats" {
+                    uprintln!(tx, "Request for the /stats resource");
+                    stats.hit("/stats");
+                    // Response to /stats, with a Content-Format coap-lite
+                    // can't set for us
+                    let mut res = generate_response(
+                        &req,
+                        stats.to_cbor(),
+                        ContentFormat::ApplicationOctetStream,
+                    );
+                    res.clear_option(CoapOption::ContentFormat);
+                    res.add_option(
+                        CoapOption::ContentFormat,
+                        vec![CONTENT_FORMAT_CBOR],
+                    );
+                    return Some(res);
                 }
             }
         }
 
         // If we made it here, the requested resource was not found
         uprintln!(tx, "Requested resource was not found");
-        let mut res = Packet::new();
-        res.header.set_type(MessageType::Acknowledgement);
-        res.header.code = MessageClass::Response(ResponseType::NotFound);
-        res.header.message_id = req.header.message_id;
-        res.set_token(req.get_token().clone());
-        res.set_content_format(ContentFormat::TextPlain);
-        res.payload = b"Not found".to_vec();
-
-        Some(res)
+        Some(generate_error(&req, ResponseType::NotFound, "Not found"))
+    }
+
+    /// Registers or deregisters an observer of /counter as requested by
+    /// the Observe option of a GET request.
+    fn observe(
+        &mut self,
+        peer: (IpAddress, u16),
+        req: &Packet,
+        res: &mut Packet,
+    ) {
+        if req.header.code != MessageClass::Request(RequestType::Get) {
+            return;
+        }
+        let observe = req
+            .get_option(CoapOption::Observe)
+            .and_then(|values| values.front())
+            .map(|value| value.iter().fold(0, |n, &b| n << 8 | u32::from(b)));
+        let token = req.get_token();
+        match observe {
+            Some(0) => {
+                self.cancel(peer, token);
+                // Without Observe option, the peer knows it's not registered
+                if self.observers.len() < MAX_OBSERVERS {
+                    self.observers.push(Observer {
+                        peer,
+                        token: token.clone(),
+                    });
+                    res.add_option(
+                        CoapOption::Observe,
+                        uint_value(self.observe_seq),
+                    );
+                }
+            }
+            Some(1) => self.cancel(peer, token),
+            _ => {}
+        }
     }
 }
 
+/// Returns the representation of the counter.
+fn counter_payload(counter: u32) -> Vec<u8> {
+    format!("{}", counter).into_bytes()
+}
+
+/// Encodes an unsigned integer option value in as few bytes as possible.
+fn uint_value(n: u32) -> Vec<u8> {
+    let bytes = n.to_be_bytes();
+    let skip = bytes.iter().take_while(|&&b| b == 0).count();
+
+    bytes[skip..].to_vec()
+}
+
 /// Returns a link-format type response with the given payload.
 fn generate_link_format(req: &Packet, payload: Vec<u8>) -> Packet {
     let mut res = Packet::new();
@@ -158,3 +370,20 @@ fn generate_response(
 
     res
 }
+
+/// Returns an error response with a diagnostic payload.
+pub fn generate_error(
+    req: &Packet,
+    code: ResponseType,
+    diagnostic: &str,
+) -> Packet {
+    let mut res = Packet::new();
+    res.header.set_type(MessageType::Acknowledgement);
+    res.header.code = MessageClass::Response(code);
+    res.header.message_id = req.header.message_id;
+    res.set_token(req.get_token().clone());
+    res.set_content_format(ContentFormat::TextPlain);
+    res.payload = diagnostic.as_bytes().to_vec();
+
+    res
+}
@@ -0,0 +1,112 @@
+//! Detection of duplicate Confirmable messages.
+//!
+//! A client retransmits a Confirmable message until it gets a response, so
+//! if the response is lost, we see the same message again. Instead of
+//! handling it a second time (which OSCORE would reject as a replay), we
+//! send the response we already built, as RFC 7252 Section 4.5 asks.
+//!
+//! Without a clock, we can't expire exchanges after EXCHANGE_LIFETIME, so
+//! we only keep the last few. At the rate this device is talked to, they're
+//! replaced long before that anyway.
+//!
+//! The heap is only 10 KiB and handling a request (OSCORE and EDHOC
+//! especially) needs most of it, so the cached responses are limited to
+//! BUDGET bytes in total. A response larger than that isn't cached, and a
+//! retransmission of its request is handled again.
+
+use alloc::vec::Vec;
+use w5500::IpAddress;
+
+/// The most exchanges remembered.
+const CAPACITY: usize = 8;
+/// The most bytes of responses remembered.
+const BUDGET: usize = 1024;
+
+/// A message we responded to.
+struct Entry {
+    peer: (IpAddress, u16),
+    message_id: u16,
+    /// The response, or `None` if the message didn't get one.
+    response: Option<Vec<u8>>,
+}
+
+/// Remembers the responses to the most recent Confirmable messages.
+pub struct DedupCache {
+    /// The remembered exchanges, oldest first.
+    entries: Vec<Entry>,
+    /// The total length of the remembered responses.
+    size: usize,
+}
+
+impl Default for DedupCache {
+    fn default() -> DedupCache {
+        DedupCache {
+            entries: Vec::with_capacity(CAPACITY),
+            size: 0,
+        }
+    }
+}
+
+impl DedupCache {
+    /// Creates a new, empty `DedupCache`.
+    pub fn new() -> DedupCache {
+        Default::default()
+    }
+
+    /// Returns the response to send again if the message is a duplicate.
+    ///
+    /// The outer `Option` is `None` if the message is new, the inner one if
+    /// the original didn't get a response.
+    pub fn lookup(
+        &self,
+        peer: (IpAddress, u16),
+        msg: &[u8],
+    ) -> Option<Option<Vec<u8>>> {
+        let message_id = confirmable_id(msg)?;
+
+        self.entries
+            .iter()
+            .find(|e| e.peer == peer && e.message_id == message_id)
+            .map(|e| e.response.clone())
+    }
+
+    /// Remembers the response to a message, if it's Confirmable and the
+    /// response fits into the budget.
+    pub fn insert(
+        &mut self,
+        peer: (IpAddress, u16),
+        msg: &[u8],
+        response: Option<&[u8]>,
+    ) {
+        let message_id = match confirmable_id(msg) {
+            Some(message_id) => message_id,
+            None => return,
+        };
+        let len = response.map_or(0, <[u8]>::len);
+        if len > BUDGET {
+            return;
+        }
+        while self.entries.len() >= CAPACITY || self.size + len > BUDGET {
+            let oldest = self.entries.remove(0);
+            self.size -= oldest.response.map_or(0, |r| r.len());
+        }
+        self.size += len;
+        self.entries.push(Entry {
+            peer,
+            message_id,
+            response: response.map(|r| r.to_vec()),
+        });
+    }
+}
+
+/// Returns the Message ID of a Confirmable message, or `None` for any other
+/// message.
+fn confirmable_id(msg: &[u8]) -> Option<u16> {
+    let header = msg.get(..4)?;
+    // The type is in bits 4 and 5 of the first byte, 0 is Confirmable
+    if header[0] >> 4 & 0x03 != 0 {
+        return None;
+    }
+
+    Some(u16::from_be_bytes([header[2], header[3]]))
+}
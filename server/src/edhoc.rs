@@ -9,26 +9,32 @@ use oscore::edhoc::{
     PartyV,
 };
 use util::{uprint, uprintln};
+use w5500::IpAddress;
 
-/// The state in which the EDHOC exchange is.
-#[derive(PartialEq)]
-enum State {
-    WaitingForFirst,
-    WaitingForThird,
-    Complete,
+use crate::stats::Stats;
+
+/// The maximum number of handshakes that can be waiting for message_3 at the
+/// same time. When it's reached, the oldest one is dropped. This is small,
+/// since each one takes up precious heap.
+const MAX_PENDING: usize = 4;
+
+/// A handshake that has sent message_2 and is waiting for message_3.
+struct Session {
+    c_v: Vec<u8>,
+    peer: (IpAddress, u16),
+    msg3_receiver: PartyV<api::Msg3Receiver>,
 }
 
-/// Handles the EDHOC exchange.
+/// Handles the EDHOC exchanges with any number of peers.
 pub struct EdhocHandler {
     auth_priv: [u8; 32],
     auth_pub: [u8; 32],
     kid: Vec<u8>,
     auth_peer: [u8; 32],
-    state: State,
-    msg1_receiver: Option<PartyV<api::Msg1Receiver>>,
-    msg3_receiver: Option<PartyV<api::Msg3Receiver>>,
-    master_secret: Option<Vec<u8>>,
-    master_salt: Option<Vec<u8>>,
+    /// The pending handshakes, oldest first.
+    sessions: Vec<Session>,
+    next_c_v: u8,
+    completed: Option<(Vec<u8>, Vec<u8>)>,
 }
 
 impl EdhocHandler {
@@ -44,139 +50,64 @@ impl EdhocHandler {
             auth_pub,
             kid,
             auth_peer,
-            state: State::WaitingForFirst,
-            msg1_receiver: None,
-            msg3_receiver: None,
-            master_secret: None,
-            master_salt: None,
+            sessions: Vec::new(),
+            next_c_v: 0,
+            completed: None,
         }
     }
 
-    /// Handles an EDHOC message and returns the reply to send.
+    /// Handles an EDHOC message from the given peer and returns the reply to
+    /// send.
     pub fn handle(
         &mut self,
         tx: &mut Tx<USART1>,
+        stats: &mut Stats,
+        peer: (IpAddress, u16),
         msg: Vec<u8>,
     ) -> Option<Vec<u8>> {
-        match self.state {
-            State::WaitingForFirst => {
-                uprintln!(
-                    tx,
-                    "Received an EDHOC message while waiting for message_1"
-                );
-                // Setup
-                self.initialize();
-
-                // Take out the receiver (which we know exists at this point)
-                let msg1_receiver = self.msg1_receiver.take().unwrap();
-                // Try to deal with message_1
-                let msg2_sender = match msg1_receiver.handle_message_1(msg) {
-                    Err(OwnError(b)) => {
-                        uprintln!(
-                            tx,
-                            "Ran into an issue dealing with the message"
-                        );
-                        // Since there's a problem, send an error message
-                        return Some(b);
-                    }
-                    Ok(val) => val,
-                };
-                // If that went well, produce message_2
-                let (msg2_bytes, msg3_receiver) = match msg2_sender
-                    .generate_message_2()
-                {
-                    Err(OwnError(b)) => {
-                        uprintln!(tx, "Ran into an issue producing message_2");
-                        return Some(b);
-                    }
-                    Ok(val) => val,
-                };
-                // Store the state and advance our progress
-                self.msg3_receiver = Some(msg3_receiver);
-                self.state = State::WaitingForThird;
-                uprintln!(tx, "Successfully built message_2");
-
-                // Return message_2 to be sent
-                Some(msg2_bytes)
-            }
-            State::WaitingForThird => {
-                uprintln!(
-                    tx,
-                    "Received an EDHOC message while waiting for message_3"
-                );
-                // Retrieve our state (which we know exists at this point)
-                let msg3_receiver = self.msg3_receiver.take().unwrap();
-                let (_u_kid, msg3_verifier) =
-                    match msg3_receiver.extract_peer_kid(msg) {
-                        Err(OwnOrPeerError::PeerError(s)) => {
-                            uprintln!(tx, "Received an EDHOC error: {}", s);
-                            self.state = State::WaitingForFirst;
-                            return None;
-                        }
-                        Err(OwnOrPeerError::OwnError(b)) => {
-                            uprintln!(
-                                tx,
-                                "Ran into an issue dealing with the message"
-                            );
-                            self.state = State::WaitingForFirst;
-                            return Some(b);
-                        }
-                        Ok(val) => val,
-                    };
-                let (master_secret, master_salt) = match msg3_verifier
-                    .verify_message_3(&self.auth_peer)
-                {
-                    Err(OwnError(b)) => {
-                        uprintln!(tx, "Ran into an issue verifying message_3");
-                        self.state = State::WaitingForFirst;
-                        return Some(b);
-                    }
-                    Ok(val) => val,
-                };
-
-                self.state = State::Complete;
-                uprintln!(
-                    tx,
-                    "Successfully derived the master secret and salt\r\n\
-                     {:?}\r\n\
-                     {:?}",
-                    master_secret,
-                    master_salt
-                );
-                self.master_secret = Some(master_secret);
-                self.master_salt = Some(master_salt);
-
-                // Return an empty message, which results in the final ACK to
-                // the client
-                Some(vec![])
+        // message_1 starts with the TYPE integer, while message_3 (and an
+        // error message in its place) starts with our C_V byte string
+        match peek_bstr(&msg) {
+            Some(c_v) => {
+                let c_v = c_v.to_vec();
+                self.handle_message_3(tx, stats, peer, c_v, msg)
             }
-            State::Complete => {
-                uprintln!(
-                    tx,
-                    "Received an EDHOC message, but we're already complete"
-                );
+            None if is_error(&msg) => {
+                // Without C_V we can't tell which handshake this ends, since
+                // several peers may be behind the same address (a proxy)
+                uprintln!(tx, "Received an EDHOC error");
+                stats.handshakes_failed += 1;
                 None
             }
+            None => self.handle_message_1(tx, stats, peer, msg),
         }
     }
 
-    /// Returns the negotiated master secret & salt, resetting the EDHOC state.
+    /// Returns the negotiated master secret & salt of the last completed
+    /// handshake.
     pub fn take_params(&mut self) -> Option<(Vec<u8>, Vec<u8>)> {
-        if self.state == State::Complete {
-            // Reset the state
-            self.state = State::WaitingForFirst;
-            // Take and return the derived context
-            Some((
-                self.master_secret.take().unwrap(),
-                self.master_salt.take().unwrap(),
-            ))
-        } else {
-            None
-        }
+        self.completed.take()
     }
 
-    /// Initializes the handler to its original state.
-    fn initialize(&mut self) {
+    /// Starts a new handshake with message_1 and returns message_2.
+    fn handle_message_1(
+        &mut self,
+        tx: &mut Tx<USART1>,
+        stats: &mut Stats,
+        peer: (IpAddress, u16),
+        msg: Vec<u8>,
+    ) -> Option<Vec<u8>> {
+        uprintln!(tx, "Received message_1");
+        stats.handshakes_started += 1;
+        // Make room if we're at capacity
+        if self.sessions.len() >= MAX_PENDING {
+            self.sessions.remove(0);
+            uprintln!(tx, "Too many handshakes, dropped the oldest one");
+            stats.handshakes_failed += 1;
+        }
+
+        // Setup
+        let c_v = self.choose_c_v();
         // "Generate" an ECDH key pair (this is hardcoded, but MUST be
         // ephemeral and generated randomly)
         let eph = [
@@ -184,18 +115,165 @@ impl EdhocHandler {
             0xE5, 0xB9, 0x6F, 0x82, 0xA3, 0x62, 0x39, 0xB4, 0x4B, 0xDE, 0x39,
             0x7A, 0x38, 0x62, 0xD5, 0x29, 0xBA, 0x8B, 0x3D, 0x7C, 0x62,
         ];
-        // Choose a connection identifier (also hardcoded, could be
-        // chosen by user or generated randomly)
-        let c_v = [0xC4].to_vec();
-
-        // Initialize what we need to handle messages
         let msg1_receiver = PartyV::new(
-            c_v,
+            c_v.clone(),
             eph,
             &self.auth_priv,
             &self.auth_pub,
             self.kid.clone(),
         );
-        self.msg1_receiver = Some(msg1_receiver);
+
+        // Try to deal with message_1
+        let msg2_sender = match msg1_receiver.handle_message_1(msg) {
+            Err(OwnError(b)) => {
+                uprintln!(tx, "Ran into an issue dealing with the message");
+                stats.handshakes_failed += 1;
+                // Since there's a problem, send an error message
+                return Some(b);
+            }
+            Ok(val) => val,
+        };
+        // If that went well, produce message_2
+        let (msg2_bytes, msg3_receiver) =
+            match msg2_sender.generate_message_2() {
+                Err(OwnError(b)) => {
+                    uprintln!(tx, "Ran into an issue producing message_2");
+                    stats.handshakes_failed += 1;
+                    return Some(b);
+                }
+                Ok(val) => val,
+            };
+        // Store the state of this handshake until message_3 arrives
+        self.sessions.push(Session {
+            c_v,
+            peer,
+            msg3_receiver,
+        });
+        uprintln!(
+            tx,
+            "Successfully built message_2 ({} handshakes pending)",
+            self.sessions.len()
+        );
+
+        // Return message_2 to be sent
+        Some(msg2_bytes)
     }
+
+    /// Completes the handshake identified by C_V with message_3.
+    fn handle_message_3(
+        &mut self,
+        tx: &mut Tx<USART1>,
+        stats: &mut Stats,
+        peer: (IpAddress, u16),
+        c_v: Vec<u8>,
+        msg: Vec<u8>,
+    ) -> Option<Vec<u8>> {
+        uprintln!(tx, "Received message_3");
+        // Retrieve the state of this handshake
+        let index = match self
+            .sessions
+            .iter()
+            .position(|s| s.c_v == c_v && s.peer == peer)
+        {
+            Some(index) => index,
+            None => {
+                uprintln!(tx, "There is no handshake with C_V {:?}", c_v);
+                return Some(error_message("Unknown connection identifier"));
+            }
+        };
+        let msg3_receiver = self.sessions.remove(index).msg3_receiver;
+
+        let (_u_kid, msg3_verifier) = match msg3_receiver.extract_peer_kid(msg)
+        {
+            Err(OwnOrPeerError::PeerError(s)) => {
+                uprintln!(tx, "Received an EDHOC error: {}", s);
+                stats.handshakes_failed += 1;
+                return None;
+            }
+            Err(OwnOrPeerError::OwnError(b)) => {
+                uprintln!(tx, "Ran into an issue dealing with the message");
+                stats.handshakes_failed += 1;
+                return Some(b);
+            }
+            Ok(val) => val,
+        };
+        let (master_secret, master_salt) =
+            match msg3_verifier.verify_message_3(&self.auth_peer) {
+                Err(OwnError(b)) => {
+                    uprintln!(tx, "Ran into an issue verifying message_3");
+                    stats.handshakes_failed += 1;
+                    return Some(b);
+                }
+                Ok(val) => val,
+            };
+
+        uprintln!(
+            tx,
+            "Successfully derived the master secret and salt\r\n\
+             {:?}\r\n\
+             {:?}",
+            master_secret,
+            master_salt
+        );
+        self.completed = Some((master_secret, master_salt));
+        stats.handshakes_completed += 1;
+
+        // Return an empty message, which results in the final ACK to
+        // the client
+        Some(vec![])
+    }
+
+    /// Returns a connection identifier not used by any pending handshake.
+    fn choose_c_v(&mut self) -> Vec<u8> {
+        // There are fewer pending handshakes than possible values, so this
+        // always terminates
+        loop {
+            let c_v = vec![self.next_c_v];
+            self.next_c_v = self.next_c_v.wrapping_add(1);
+            if !self.sessions.iter().any(|s| s.c_v == c_v) {
+                return c_v;
+            }
+        }
+    }
+}
+
+/// Returns the CBOR byte string at the start of the message, if there is one.
+fn peek_bstr(msg: &[u8]) -> Option<&[u8]> {
+    let first = *msg.first()?;
+    // Major type 2 is a byte string
+    if first >> 5 != 2 {
+        return None;
+    }
+    // The additional information is either the length itself or tells us
+    // how many of the following bytes encode it
+    let (len, start) = match first & 0x1F {
+        n @ 0..=23 => (n as usize, 1),
+        24 => (*msg.get(1)? as usize, 2),
+        25 => (u16::from_be_bytes([*msg.get(1)?, *msg.get(2)?]) as usize, 3),
+        _ => return None,
+    };
+
+    msg.get(start..start + len)
+}
+
+/// Returns whether the message starts with a CBOR text string, which is the
+/// case for an EDHOC error message without connection identifier.
+fn is_error(msg: &[u8]) -> bool {
+    // Major type 3 is a text string
+    matches!(msg.first(), Some(b) if b >> 5 == 3)
+}
+
+/// Returns an EDHOC error message with the given diagnostic text.
+fn error_message(err_msg: &str) -> Vec<u8> {
+    let len = err_msg.len();
+    // Encode the text string header, our messages are always shorter than
+    // 256 bytes
+    let mut msg = if len < 24 {
+        vec![0x60 | len as u8]
+    } else {
+        vec![0x78, len as u8]
+    };
+    msg.extend(err_msg.as_bytes());
+
+    msg
 }
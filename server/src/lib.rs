@@ -4,6 +4,8 @@
 extern crate alloc;
 
 pub mod coap;
+pub mod dedup;
 pub mod edhoc;
 pub mod led;
 pub mod oscore;
+pub mod stats;
@@ -16,7 +16,8 @@ use w5500::{
 };
 
 use server::{
-    coap::CoapHandler, edhoc::EdhocHandler, led::Leds, oscore::OscoreHandler,
+    coap::CoapHandler, dedup::DedupCache, edhoc::EdhocHandler, led::Leds,
+    oscore::OscoreHandler,
 };
 
 #[global_allocator]
@@ -133,6 +134,9 @@ fn main() -> ! {
     let mut oscore =
         OscoreHandler::new(edhoc, coap, KID.to_vec(), KID_PEER.to_vec());
 
+    // Remembers our responses in case the client retransmits
+    let mut dedup = DedupCache::new();
+
     loop {
         let (ip, port, len) = match udp.receive(&mut buffer) {
             Ok(Some(triple)) => triple,
@@ -149,17 +153,47 @@ fn main() -> ! {
         uprintln!(tx, "\nRx({})", len);
         uprintln!(tx, "IP packet from {}", ip);
 
-        // Handle the request
-        let res = oscore.handle(&mut tx, &buffer[..len]);
-        if res.is_none() {
-            continue;
+        let msg = &buffer[..len];
+        // Answer retransmissions with the response we already built
+        let res = match dedup.lookup((ip, port), msg) {
+            Some(res) => {
+                uprintln!(tx, "Received a duplicate, repeating the response");
+                res
+            }
+            None => {
+                // Handle the request
+                let res = match oscore.handle(&mut tx, (ip, port), msg) {
+                    Ok(res) => res,
+                    Err(failure) => {
+                        uprintln!(
+                            tx,
+                            "Failed handling message: {}",
+                            failure.error.diagnostic()
+                        );
+                        failure.response
+                    }
+                };
+                dedup.insert((ip, port), msg, res.as_deref());
+                res
+            }
+        };
+        if let Some(res) = res {
+            leds.spin().expect("Failed advancing led");
+            uprintln!(tx, "Responding with CoAP packet");
+            uprintln!(tx, "Tx({})", res.len());
+            udp.blocking_send(&ip, port, &res).expect("Failed sending");
         }
-        let res = res.unwrap();
 
-        leds.spin().expect("Failed advancing led");
-        uprintln!(tx, "Responding with CoAP packet");
-        uprintln!(tx, "Tx({})", res.len());
-        udp.blocking_send(&ip, port, &res).expect("Failed sending");
+        // Every request changes the counter, so let its observers know
+        for ((ip, port), notification) in oscore.notifications(&mut tx) {
+            uprintln!(tx, "Notifying observer {}", ip);
+            uprintln!(tx, "Tx({})", notification.len());
+            if let Err(err) = udp.blocking_send(&ip, port, &notification) {
+                // Not worth retrying, the observer has to register again
+                uprintln!(tx, "Error notifying: {:?}", err);
+                oscore.cancel_all((ip, port));
+            }
+        }
     }
 }
 
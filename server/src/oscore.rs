@@ -2,12 +2,17 @@
 
 use alloc::vec::Vec;
 use alt_stm32f30x_hal::{device::USART1, serial::Tx};
-use coap_lite::{CoapOption, Packet};
+use coap_lite::{CoapOption, Packet, ResponseType};
 use core::fmt::Write;
 use oscore::oscore::SecurityContext;
 use util::{uprint, uprintln};
+use w5500::IpAddress;
 
-use crate::{coap::CoapHandler, edhoc::EdhocHandler};
+use crate::{
+    coap::{generate_error, CoapHandler},
+    edhoc::EdhocHandler,
+    stats::Stats,
+};
 
 /// Unprotects and protects OSCORE message and invokes `CoapHandler`.
 pub struct OscoreHandler {
@@ -16,6 +21,18 @@ pub struct OscoreHandler {
     oscore: Option<SecurityContext>,
     sender_id: Vec<u8>,
     recipient_id: Vec<u8>,
+    /// The observations registered with OSCORE.
+    observations: Vec<Observation>,
+    /// The counters served by /stats.
+    stats: Stats,
+}
+
+/// An observation registered with an OSCORE request.
+struct Observation {
+    peer: (IpAddress, u16),
+    token: Vec<u8>,
+    /// The protected registration, which the notifications are bound to.
+    request: Vec<u8>,
 }
 
 impl OscoreHandler {
@@ -32,48 +49,184 @@ impl OscoreHandler {
             oscore: None,
             sender_id,
             recipient_id,
+            observations: Vec::new(),
+            stats: Stats::new(),
         }
     }
 
+    /// Returns the notifications for the observers of resources that
+    /// changed, protected with a fresh Partial IV if the observation was
+    /// registered with OSCORE.
+    pub fn notifications(
+        &mut self,
+        tx: &mut Tx<USART1>,
+    ) -> Vec<((IpAddress, u16), Vec<u8>)> {
+        let notifications = self.coap.notifications();
+        // Forget the observations that ended in the meantime
+        let coap = &self.coap;
+        self.observations
+            .retain(|o| coap.is_observing(o.peer, &o.token));
+
+        let mut protected = Vec::with_capacity(notifications.len());
+        for notification in notifications {
+            let peer = notification.peer;
+            let token = notification.token;
+            let result = notification
+                .packet
+                .to_bytes()
+                .map_err(|_| Error::Build)
+                .and_then(|bytes| {
+                    self.protect_notification(peer, &token, &bytes)
+                });
+            match result {
+                Ok(bytes) => protected.push((peer, bytes)),
+                Err(e) => {
+                    // Without a way to protect them, the observation is over
+                    uprintln!(tx, "Failed notifying: {}", e.diagnostic());
+                    self.coap.cancel(peer, &token);
+                    self.observations
+                        .retain(|o| o.peer != peer || o.token != token);
+                }
+            }
+        }
+
+        protected
+    }
+
+    /// Ends all observations of the given peer, like when sending it a
+    /// notification failed.
+    pub fn cancel_all(&mut self, peer: (IpAddress, u16)) {
+        self.coap.cancel_all(peer);
+        self.observations.retain(|o| o.peer != peer);
+    }
+
+    /// Protects a notification if its observation was registered with
+    /// OSCORE, or returns it as it is otherwise.
+    fn protect_notification(
+        &mut self,
+        peer: (IpAddress, u16),
+        token: &[u8],
+        notification: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        let observation = match self
+            .observations
+            .iter()
+            .find(|o| o.peer == peer && o.token[..] == token[..])
+        {
+            Some(observation) => observation,
+            None => return Ok(notification.to_vec()),
+        };
+        let oscore = self.oscore.as_mut().ok_or(Error::NoContext)?;
+
+        oscore
+            .protect_response(notification, &observation.request, false)
+            .map_err(Error::Protect)
+    }
+
     /// Unprotects an OSCORE message if it is one, passes the CoAP to the
     /// `CoapHandler` and protects the response if necessary.
+    ///
+    /// On failure, the response to send (if any) is part of the `Failure`.
     pub fn handle(
         &mut self,
         tx: &mut Tx<USART1>,
+        peer: (IpAddress, u16),
         req_bytes: &[u8],
-    ) -> Option<Vec<u8>> {
-        let mut req =
-            Packet::from_bytes(req_bytes).expect("Unable to parse CoAP");
+    ) -> Result<Option<Vec<u8>>, Failure> {
+        // Without a CoAP message there is nobody to answer to
+        let req = match Packet::from_bytes(req_bytes) {
+            Ok(req) => req,
+            Err(_) => {
+                self.stats.decode_errors += 1;
+                return Err(Failure {
+                    error: Error::Parse,
+                    response: None,
+                });
+            }
+        };
+
+        self.process(tx, peer, req_bytes, req.clone())
+            .map_err(|error| {
+                match error {
+                    Error::InnerParse => self.stats.decode_errors += 1,
+                    Error::Unprotect(
+                        oscore::oscore::Error::ReplayDetected,
+                    ) => self.stats.replays_rejected += 1,
+                    _ => (),
+                }
+                // Reply to the outer message, since we don't trust the inner
+                // one
+                let response = error.response_code().and_then(|code| {
+                    let mut res =
+                        generate_error(&req, code, error.diagnostic());
+                    // Error responses must not be cached
+                    res.add_option(CoapOption::MaxAge, Vec::new());
+                    res.to_bytes().ok()
+                });
+                Failure { error, response }
+            })
+    }
+
+    /// Does the actual work of `handle` for a parsed request.
+    fn process(
+        &mut self,
+        tx: &mut Tx<USART1>,
+        peer: (IpAddress, u16),
+        req_bytes: &[u8],
+        mut req: Packet,
+    ) -> Result<Option<Vec<u8>>, Error> {
         let mut is_oscore = false;
 
         // Check if the request is OSCORE and we are ready to deal with it
-        if req.get_option(CoapOption::Oscore).is_some()
-            && self.oscore.is_some()
-        {
+        if req.get_option(CoapOption::Oscore).is_some() {
+            let oscore = self.oscore.as_mut().ok_or(Error::NoContext)?;
             uprintln!(tx, "Unprotecting OSCORE request");
             is_oscore = true;
-            // Temporarily take the oscore context
-            let mut oscore = self.oscore.take().unwrap();
             // Unprotect the request and replace the original with it
-            req = Packet::from_bytes(
-                &oscore
-                    .unprotect_request(req_bytes)
-                    .expect("Failed unprotecting request"),
-            )
-            .expect("Unable to parse unprotected request");
-            // Put the context back in
-            self.oscore = Some(oscore);
+            let unprotected = oscore
+                .unprotect_request(req_bytes)
+                .map_err(Error::Unprotect)?;
+            req = Packet::from_bytes(&unprotected)
+                .map_err(|_| Error::InnerParse)?;
+            self.stats.requests_protected += 1;
+        } else {
+            self.stats.requests_unprotected += 1;
         }
 
         // Use CoAP handler to deal with it
-        let mut res = self
-            .coap
-            .handle(tx, &mut self.edhoc, req)?
-            .to_bytes()
-            .expect("Error building CoAP bytes");
+        let res = match self.coap.handle(
+            tx,
+            &mut self.edhoc,
+            &mut self.stats,
+            peer,
+            req,
+            is_oscore,
+        ) {
+            Some(res) => res,
+            None => return Ok(None),
+        };
+        if res.get_option(CoapOption::Observe).is_some() {
+            // Remember the registration, since the notifications have to be
+            // protected with the same request
+            let token = res.get_token().clone();
+            self.observations
+                .retain(|o| o.peer != peer || o.token != token);
+            if is_oscore {
+                self.observations.push(Observation {
+                    peer,
+                    token,
+                    request: req_bytes.to_vec(),
+                });
+            }
+        }
+        let mut res = res.to_bytes().map_err(|_| Error::Build)?;
 
         // Check if EDHOC has advanced
         if let Some((master_secret, master_salt)) = self.edhoc.take_params() {
+            // Observations with the old context end with it
+            for observation in self.observations.drain(..) {
+                self.coap.cancel(observation.peer, &observation.token);
+            }
             // Since EDHOC is done, we can initialize OSCORE
             self.oscore = Some(
                 SecurityContext::new(
@@ -82,24 +235,85 @@ impl OscoreHandler {
                     self.sender_id.clone(),
                     self.recipient_id.clone(),
                 )
-                .expect("Failed intializing OSCORE"),
+                .map_err(Error::Context)?,
             );
         }
 
         // If the exchange is protected with OSCORE, protect the response
         if is_oscore {
-            uprintln!(tx, "Protecting OSCORE response");
-            // Temporarily take the oscore context
-            let mut oscore = self.oscore.take().unwrap();
-            // Protect the response and replace the original with it
-            res = oscore
-                .protect_response(&res, req_bytes, true)
-                .expect("Failed protecting response");
-            // Put the context back in
-            self.oscore = Some(oscore);
+            if let Some(oscore) = self.oscore.as_mut() {
+                uprintln!(tx, "Protecting OSCORE response");
+                // Protect the response and replace the original with it
+                res = oscore
+                    .protect_response(&res, req_bytes, true)
+                    .map_err(Error::Protect)?;
+            }
         }
 
         // Return the bytes of the CoAP response packet
-        Some(res)
+        Ok(Some(res))
     }
 }
+
+/// The ways in which handling a message can fail.
+#[derive(Debug)]
+pub enum Error {
+    /// The datagram is not a CoAP message.
+    Parse,
+    /// There is no security context yet.
+    NoContext,
+    /// The request couldn't be unprotected.
+    Unprotect(oscore::oscore::Error),
+    /// The unprotected request is not a CoAP message.
+    InnerParse,
+    /// The response couldn't be serialized.
+    Build,
+    /// The security context couldn't be derived after EDHOC.
+    Context(oscore::oscore::Error),
+    /// The response couldn't be protected.
+    Protect(oscore::oscore::Error),
+}
+
+impl Error {
+    /// Returns the code of the error response as defined in RFC 8613, or
+    /// `None` if the message should be dropped silently.
+    pub fn response_code(&self) -> Option<ResponseType> {
+        match self {
+            Error::Parse => None,
+            Error::InnerParse => Some(ResponseType::BadRequest),
+            Error::NoContext
+            | Error::Unprotect(oscore::oscore::Error::ReplayDetected) => {
+                Some(ResponseType::Unauthorized)
+            }
+            Error::Unprotect(_) => Some(ResponseType::BadRequest),
+            Error::Build | Error::Context(_) | Error::Protect(_) => {
+                Some(ResponseType::InternalServerError)
+            }
+        }
+    }
+
+    /// Returns a short description to use as diagnostic payload.
+    pub fn diagnostic(&self) -> &'static str {
+        match self {
+            Error::Parse => "Unable to parse CoAP",
+            Error::NoContext => "Security context not found",
+            Error::Unprotect(oscore::oscore::Error::ReplayDetected) => {
+                "Replay detected"
+            }
+            Error::Unprotect(_) => "Failed unprotecting request",
+            Error::InnerParse => "Unable to parse unprotected request",
+            Error::Build => "Error building CoAP bytes",
+            Error::Context(_) => "Failed initializing OSCORE",
+            Error::Protect(_) => "Failed protecting response",
+        }
+    }
+}
+
+/// A message that couldn't be handled.
+#[derive(Debug)]
+pub struct Failure {
+    /// What went wrong.
+    pub error: Error,
+    /// The error response to send, if there is one.
+    pub response: Option<Vec<u8>>,
+}
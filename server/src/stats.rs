@@ -0,0 +1,109 @@
+//! Counters of what the server has been doing.
+
+use alloc::vec::Vec;
+
+/// The paths of the resources we count requests for.
+const RESOURCES: [&str; 6] = [
+    "/.well-known/core",
+    "/.well-known/edhoc",
+    "/hello",
+    "/counter",
+    "/echo",
+    "/stats",
+];
+
+/// The counters of the server.
+#[derive(Default)]
+pub struct Stats {
+    /// EDHOC handshakes started with a message_1.
+    pub handshakes_started: u32,
+    /// EDHOC handshakes that ended with a master secret and salt.
+    pub handshakes_completed: u32,
+    /// EDHOC handshakes that failed or were dropped.
+    pub handshakes_failed: u32,
+    /// Requests that were successfully unprotected with OSCORE.
+    pub requests_protected: u32,
+    /// Requests that came without OSCORE.
+    pub requests_unprotected: u32,
+    /// OSCORE requests rejected because they were already received.
+    pub replays_rejected: u32,
+    /// Messages that couldn't be parsed, either outside or inside OSCORE.
+    pub decode_errors: u32,
+    /// The number of requests for each of `RESOURCES`.
+    resource_hits: [u32; RESOURCES.len()],
+}
+
+impl Stats {
+    /// Creates a new `Stats` with all counters at zero.
+    pub fn new() -> Stats {
+        Default::default()
+    }
+
+    /// Counts a request for the resource at the given path, if it's one of
+    /// ours.
+    pub fn hit(&mut self, path: &str) {
+        if let Some(i) = RESOURCES.iter().position(|&r| r == path) {
+            self.resource_hits[i] += 1;
+        }
+    }
+
+    /// Returns the counters as a CBOR map, with the resource hits in a map
+    /// of their own under `resource_hits`.
+    pub fn to_cbor(&self) -> Vec<u8> {
+        let counters = [
+            ("handshakes_started", self.handshakes_started),
+            ("handshakes_completed", self.handshakes_completed),
+            ("handshakes_failed", self.handshakes_failed),
+            ("requests_protected", self.requests_protected),
+            ("requests_unprotected", self.requests_unprotected),
+            ("replays_rejected", self.replays_rejected),
+            ("decode_errors", self.decode_errors),
+        ];
+
+        let mut out = Vec::with_capacity(256);
+        push_head(&mut out, MAJOR_MAP, counters.len() as u32 + 1);
+        for &(name, value) in counters.iter() {
+            push_text(&mut out, name);
+            push_head(&mut out, MAJOR_UINT, value);
+        }
+        push_text(&mut out, "resource_hits");
+        push_head(&mut out, MAJOR_MAP, RESOURCES.len() as u32);
+        for (path, &hits) in RESOURCES.iter().zip(self.resource_hits.iter()) {
+            push_text(&mut out, path);
+            push_head(&mut out, MAJOR_UINT, hits);
+        }
+
+        out
+    }
+}
+
+/// The CBOR major type of unsigned integers.
+const MAJOR_UINT: u8 = 0;
+/// The CBOR major type of text strings.
+const MAJOR_TEXT: u8 = 3;
+/// The CBOR major type of maps.
+const MAJOR_MAP: u8 = 5;
+
+/// Appends the head of a CBOR item with the given major type and argument,
+/// in the shortest encoding.
+fn push_head(out: &mut Vec<u8>, major: u8, n: u32) {
+    let major = major << 5;
+    if n < 24 {
+        out.push(major | n as u8);
+    } else if n <= 0xFF {
+        out.push(major | 24);
+        out.push(n as u8);
+    } else if n <= 0xFFFF {
+        out.push(major | 25);
+        out.extend_from_slice(&(n as u16).to_be_bytes());
+    } else {
+        out.push(major | 26);
+        out.extend_from_slice(&n.to_be_bytes());
+    }
+}
+
+/// Appends a CBOR text string.
+fn push_text(out: &mut Vec<u8>, text: &str) {
+    push_head(out, MAJOR_TEXT, text.len() as u32);
+    out.extend_from_slice(text.as_bytes());
+}